@@ -1,6 +1,6 @@
 use std::error::Error as Err;
 use std::fmt::{Display, Formatter};
-use std::time::SystemTimeError;
+use std::time::{Duration, SystemTimeError};
 use async_sqlite::rusqlite;
 use log::error;
 use url::ParseError;
@@ -8,20 +8,80 @@ use url::ParseError;
 use libnss::host::Host;
 use libnss::interop::Response;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum Error {
+    /// The upstream could not answer the question, such as with SERVFAIL or REFUSED.
     DNSErrorReply,
+    /// The name does not exist (NXDOMAIN).
+    NonExistentDomain,
     EmptyDNSReply,
+    /// The name has a label longer than 63 octets or is longer than 255 octets.
+    InvalidName,
+    /// The family asked for is no address family, neither A (1) nor AAAA (28).
+    UnsupportedFamily(u32),
+    /// The CNAME records of the name lead back to one of the names before.
+    CnameLoop,
+    /// The name is an alias of an alias more times than allowed.
+    CnameChainTooLong,
+    /// The name requires DNSSEC but the answer was not validated.
+    InsecureDNSReply,
+    /// The answer failed DNSSEC validation.
+    BogusDNSReply,
+    /// The certificate of the upstream matches none of its pinned keys.
+    PinMismatch,
+    /// The connection to the upstream could not be opened or broke.
     UpstreamError,
+    /// The upstream did not answer in time.
+    UpstreamTimeout,
+    /// The TLS handshake with the upstream failed, such as on an untrusted certificate.
+    TlsError,
+    /// The upstream answered with an HTTP error status other than 429.
+    HttpError { status: u16, retry_after: Option<Duration> },
+    /// The upstream asked to slow down with HTTP 429, for how long when it said so.
+    RateLimited { retry_after: Option<Duration> },
+    /// The reply of the upstream could not be parsed.
+    MalformedReply,
     DatabaseError,
 }
 
+impl Error {
+    /// Whether asking the same upstream again may succeed.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::UpstreamError | Error::UpstreamTimeout | Error::RateLimited { .. } => true,
+            Error::HttpError { status, .. } => *status >= 500,
+            _ => false,
+        }
+    }
+
+    /// How long the upstream asked to wait before the next query.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Error::HttpError { retry_after, .. } | Error::RateLimited { retry_after } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             Error::UpstreamError => write!(f, "UpstreamError"),
+            Error::UpstreamTimeout => write!(f, "UpstreamTimeout"),
+            Error::TlsError => write!(f, "TlsError"),
+            Error::HttpError { status, .. } => write!(f, "HttpError({})", status),
+            Error::RateLimited { .. } => write!(f, "RateLimited"),
+            Error::MalformedReply => write!(f, "MalformedReply"),
             Error::DNSErrorReply => write!(f, "DNSErrorReply"),
+            Error::NonExistentDomain => write!(f, "NonExistentDomain"),
             Error::EmptyDNSReply => write!(f, "EmptyDNSReply"),
+            Error::InvalidName => write!(f, "InvalidName"),
+            Error::UnsupportedFamily(family) => write!(f, "UnsupportedFamily({})", family),
+            Error::CnameLoop => write!(f, "CnameLoop"),
+            Error::CnameChainTooLong => write!(f, "CnameChainTooLong"),
+            Error::InsecureDNSReply => write!(f, "InsecureDNSReply"),
+            Error::BogusDNSReply => write!(f, "BogusDNSReply"),
+            Error::PinMismatch => write!(f, "PinMismatch"),
             Error::DatabaseError => write!(f, "DatabaseError")
         }
     }
@@ -32,14 +92,18 @@ impl Err for Error {}
 impl From<std::io::Error> for Error {
     fn from(error: std::io::Error) -> Self {
         error!("reading response body error: {:?}", error);
-        Error::UpstreamError
+
+        match error.kind() {
+            std::io::ErrorKind::TimedOut => Error::UpstreamTimeout,
+            _ => Error::UpstreamError,
+        }
     }
 }
 
 impl From<serde_json::Error> for Error {
     fn from(error: serde_json::Error) -> Self {
         error!("error deserialization JSON: {} {:?}", error, error);
-        Error::UpstreamError
+        Error::MalformedReply
     }
 }
 
@@ -64,12 +128,54 @@ impl From<SystemTimeError> for Error {
     }
 }
 
-impl Into<Response<Host>> for Error {
-    fn into(self) -> Response<Host> {
-        match self {
-            Error::EmptyDNSReply => Response::NotFound,
-            Error::UpstreamError => Response::TryAgain,
-            _ => Response::Unavail
+impl From<Error> for Response<Host> {
+    fn from(error: Error) -> Self {
+        match error {
+            // The name has no address, asking again changes nothing
+            Error::EmptyDNSReply
+            | Error::NonExistentDomain
+            | Error::InvalidName
+            | Error::CnameLoop
+            | Error::CnameChainTooLong => Response::NotFound,
+            // The upstream may well answer the next query
+            Error::DNSErrorReply
+            | Error::UpstreamError
+            | Error::UpstreamTimeout
+            | Error::RateLimited { .. } => Response::TryAgain,
+            Error::HttpError { status, .. } if status >= 500 => Response::TryAgain,
+            // Refused by policy or by a misconfigured upstream, which lasts
+            Error::HttpError { .. }
+            | Error::InsecureDNSReply
+            | Error::BogusDNSReply
+            | Error::PinMismatch
+            | Error::UnsupportedFamily(_)
+            | Error::TlsError
+            | Error::MalformedReply
+            | Error::DatabaseError => Response::Unavail,
+        }
+    }
+}
+
+/// Error of the `ResolveName` D-Bus method, named after the NSS status the
+/// module is to return; the message is the [`Error`] behind it.
+#[derive(Debug, zbus::DBusError)]
+#[zbus(prefix = "com.glaciaos.NameResolver")]
+pub enum ResolveError {
+    #[zbus(error)]
+    ZBus(zbus::Error),
+    NotFound(String),
+    TryAgain(String),
+    Unavail(String),
+}
+
+impl From<Error> for ResolveError {
+    fn from(error: Error) -> Self {
+        let message = error.to_string();
+
+        match Response::<Host>::from(error) {
+            Response::NotFound => ResolveError::NotFound(message),
+            Response::TryAgain => ResolveError::TryAgain(message),
+            _ => ResolveError::Unavail(message),
         }
     }
 }
@@ -91,6 +197,13 @@ impl From<url::ParseError> for Error {
 impl From<reqwest::Error> for Error {
     fn from(error: reqwest::Error) -> Self {
         error!("error while making upstream request: {} {:?}", error, error);
-        Error::UpstreamError
+
+        if error.is_timeout() {
+            Error::UpstreamTimeout
+        } else if error.is_decode() {
+            Error::MalformedReply
+        } else {
+            Error::UpstreamError
+        }
     }
 }
\ No newline at end of file
@@ -1,6 +1,8 @@
 pub mod error;
 pub mod loggger;
 
+use std::net::SocketAddr;
+
 use serde::{Serialize};
 use zvariant::Type;
 
@@ -22,10 +24,184 @@ pub struct AuditDnsQuery {
     process_name: String,
     host: String,
     create: u64,
+    upstream: String,
+    // Resolved over classic DNS, in clear text
+    downgraded: bool,
+    // Suffix of the forwarding rule that sent the query to its upstream
+    forward_rule: String,
+    // Validated with DNSSEC by the upstream
+    authenticated: bool,
 }
 
 impl AuditDnsQuery {
-    pub fn new(process_name: String, host: String, create: u64) -> Self {
-        Self { process_name, host, create }
+    pub fn new(process_name: String,
+               host: String,
+               create: u64,
+               upstream: String,
+               downgraded: bool,
+               forward_rule: String,
+               authenticated: bool) -> Self {
+        Self { process_name, host, create, upstream, downgraded, forward_rule, authenticated }
+    }
+}
+
+#[derive(Serialize, Type)]
+pub struct ForwardRule {
+    suffix: String,
+    upstream: String,
+}
+
+impl ForwardRule {
+    pub fn new(suffix: String, upstream: String) -> Self {
+        Self { suffix, upstream }
+    }
+}
+
+#[derive(Serialize, Type)]
+pub struct UpstreamHealth {
+    name: String,
+    circuit: String,
+    consecutive_failures: u32,
+    latency_ms: f64,
+    successes: u64,
+    failures: u64,
+}
+
+impl UpstreamHealth {
+    pub fn new(name: String,
+               circuit: String,
+               consecutive_failures: u32,
+               latency_ms: f64,
+               successes: u64,
+               failures: u64) -> Self {
+        Self { name, circuit, consecutive_failures, latency_ms, successes, failures }
+    }
+}
+
+#[derive(Serialize, Type)]
+pub struct ResolverStats {
+    queries: u64,
+    // Queries answered with the result of an identical lookup in progress
+    coalesced: u64,
+}
+
+impl ResolverStats {
+    pub fn new(queries: u64, coalesced: u64) -> Self {
+        Self { queries, coalesced }
+    }
+}
+
+// Typed answers of QueryRecords. Names are given without the trailing dot,
+// and the TTL is what is left of it when the answer comes from the cache.
+
+#[derive(Serialize, Type)]
+pub struct MxRecord {
+    pub name: String,
+    pub ttl: u32,
+    pub preference: u16,
+    pub exchange: String,
+}
+
+#[derive(Serialize, Type)]
+pub struct TxtRecord {
+    pub name: String,
+    pub ttl: u32,
+    // Character-strings in their order of appearance, to be joined by the
+    // caller when they hold a single value split for length (e.g. DKIM keys)
+    pub strings: Vec<String>,
+}
+
+#[derive(Serialize, Type)]
+pub struct SrvRecord {
+    pub name: String,
+    pub ttl: u32,
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+#[derive(Serialize, Type)]
+pub struct CaaRecord {
+    pub name: String,
+    pub ttl: u32,
+    pub flags: u8,
+    pub tag: String,
+    pub value: String,
+}
+
+#[derive(Serialize, Type)]
+pub struct NsRecord {
+    pub name: String,
+    pub ttl: u32,
+    pub nameserver: String,
+}
+
+#[derive(Serialize, Type)]
+pub struct SoaRecord {
+    pub name: String,
+    pub ttl: u32,
+    pub mname: String,
+    pub rname: String,
+    pub serial: u32,
+    pub refresh: u32,
+    pub retry: u32,
+    pub expire: u32,
+    pub minimum: u32,
+}
+
+/// A SVCB or HTTPS record (RFC 9460) with its SvcParams decoded. A priority
+/// of 0 makes it an alias of `target`, without parameters.
+#[derive(Serialize, Type)]
+pub struct SvcbRecord {
+    pub name: String,
+    pub ttl: u32,
+    pub priority: u16,
+    pub target: String,
+    // Keys of the parameters the client must understand to use the record
+    pub mandatory: Vec<String>,
+    pub alpn: Vec<String>,
+    pub no_default_alpn: bool,
+    // 0 when the record has none
+    pub port: u16,
+    pub ipv4_hints: Vec<String>,
+    // ECHConfigList of TLS Encrypted Client Hello
+    pub ech: Vec<u8>,
+    pub ipv6_hints: Vec<String>,
+    // Parameters of other keys, in presentation format
+    pub other_params: Vec<(String, String)>,
+}
+
+/// Records of a QueryRecords answer; only the list of the queried type is filled.
+#[derive(Serialize, Type, Default)]
+pub struct RecordSet {
+    pub mx: Vec<MxRecord>,
+    pub txt: Vec<TxtRecord>,
+    pub srv: Vec<SrvRecord>,
+    pub caa: Vec<CaaRecord>,
+    pub ns: Vec<NsRecord>,
+    pub soa: Vec<SoaRecord>,
+    pub svcb: Vec<SvcbRecord>,
+    pub https: Vec<SvcbRecord>,
+}
+
+/// An address to connect to for a service, found through its SRV records.
+#[derive(Serialize, Type)]
+pub struct ServiceAddress {
+    target: String,
+    priority: u16,
+    weight: u16,
+    // The IP address and port, as "192.0.2.1:443" or "[2001:db8::1]:443"
+    address: String,
+    ttl: u32,
+}
+
+impl ServiceAddress {
+    pub fn new(target: String, priority: u16, weight: u16, address: SocketAddr, ttl: u32) -> Self {
+        Self { target, priority, weight, address: address.to_string(), ttl }
     }
-}
\ No newline at end of file
+
+    pub fn socket_address(&self) -> Option<SocketAddr> {
+        self.address.parse().ok()
+    }
+}
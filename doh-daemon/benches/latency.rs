@@ -0,0 +1,262 @@
+//! Latency of a name resolved from the upstream against one answered from the
+//! cache, with a local DoH server as the upstream.
+//!
+//! Run with `cargo bench --bench latency`.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_sqlite::{JournalMode, PoolBuilder};
+use criterion::{criterion_group, criterion_main, Criterion};
+use rcgen::{BasicConstraints, CertificateParams, CertifiedKey, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::ServerConfig;
+use tempfile::TempDir;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::runtime::Runtime;
+use tokio_rustls::TlsAcceptor;
+
+use doh_daemon::database::DatabaseService;
+use doh_daemon::provider::Resolver;
+use doh_daemon::settings::ApplicationSettings;
+
+const SERVER_NAME: &str = "doh.test";
+const A: u32 = 1;
+
+/// A DoH server answering every A question with 192.0.2.1, and the
+/// configuration of a daemon using it as its only provider.
+struct Upstream {
+    directory: TempDir,
+}
+
+impl Upstream {
+    async fn start() -> Self {
+        let directory = tempfile::tempdir().unwrap();
+
+        let ca = authority();
+        let server = issue(&ca);
+
+        std::fs::write(directory.path().join("ca.pem"), ca.cert.pem()).unwrap();
+
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(server.key_pair.serialize_der()));
+
+        let mut config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![server.cert.der().clone(), ca.cert.der().clone()], key)
+            .unwrap();
+
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(serve(listener, TlsAcceptor::from(Arc::new(config))));
+
+        let path = |file: &str| directory.path().join(file).to_string_lossy().into_owned();
+
+        let config = format!(
+            "[sqlite]\nconnection={}\n\n\
+             [resolver]\nprovider=stub\nwarmup=false\n\n\
+             [provider.stub]\nurl=https://{}:{}/dns-query\nbootstrap=127.0.0.1\nformat=message\nca_bundle={}\n",
+            path("doh.db"),
+            SERVER_NAME,
+            port,
+            path("ca.pem"));
+
+        std::fs::write(directory.path().join("config.ini"), config).unwrap();
+
+        Self { directory }
+    }
+
+    async fn resolver(&self) -> Resolver {
+        std::env::set_var("CONFIG_FILE", self.directory.path().join("config.ini"));
+
+        let settings = ApplicationSettings::configs();
+
+        let pool = PoolBuilder::new()
+            .path(settings.sqlite().connection_str())
+            .journal_mode(JournalMode::Wal)
+            .num_conns(4)
+            .open()
+            .await
+            .unwrap();
+
+        let database = DatabaseService::new(pool);
+        database.create_tables().await.unwrap();
+
+        let (events, _) = tokio::sync::mpsc::unbounded_channel();
+
+        Resolver::new(database, settings, events).unwrap()
+    }
+}
+
+async fn serve(listener: TcpListener, acceptor: TlsAcceptor) {
+    loop {
+        let (stream, _) = listener.accept().await.unwrap();
+        stream.set_nodelay(true).unwrap();
+        let acceptor = acceptor.clone();
+
+        tokio::spawn(async move {
+            if let Ok(stream) = acceptor.accept(stream).await {
+                let _ = answer(BufReader::new(stream)).await;
+            }
+        });
+    }
+}
+
+/// Answers the HTTP/1.1 POST requests of one keep-alive connection.
+async fn answer<S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin>(mut stream: BufReader<S>) -> std::io::Result<()> {
+    loop {
+        let mut length = 0;
+
+        loop {
+            let mut line = String::new();
+
+            if stream.read_line(&mut line).await? == 0 {
+                return Ok(());
+            }
+
+            if line == "\r\n" {
+                break;
+            }
+
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    length = value.trim().parse().unwrap_or(0);
+                }
+            }
+        }
+
+        let mut query = vec![0; length];
+        stream.read_exact(&mut query).await?;
+
+        let reply = reply(&query);
+
+        let mut response = format!(
+            "HTTP/1.1 200 OK\r\ncontent-type: application/dns-message\r\ncontent-length: {}\r\n\r\n",
+            reply.len()).into_bytes();
+
+        response.extend_from_slice(&reply);
+
+        stream.get_mut().write_all(&response).await?;
+        stream.get_mut().flush().await?;
+    }
+}
+
+/// The reply to `query`, with one answer when it asks for an A record.
+fn reply(query: &[u8]) -> Vec<u8> {
+    let mut end = 12;
+
+    while query[end] != 0 {
+        end += 1 + query[end] as usize;
+    }
+
+    end += 5;
+
+    let is_a = query[end - 4..end - 2] == [0, 1];
+
+    let mut reply = query[..2].to_vec();
+    reply.extend_from_slice(&[0x81, 0x80, 0, 1, 0, is_a as u8, 0, 0, 0, 0]);
+    reply.extend_from_slice(&query[12..end]);
+
+    if is_a {
+        reply.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0x01, 0x2c, 0, 4, 192, 0, 2, 1]);
+    }
+
+    reply
+}
+
+fn authority() -> CertifiedKey {
+    let key_pair = KeyPair::generate().unwrap();
+
+    let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.distinguished_name.push(DnType::CommonName, "Bench CA");
+
+    let cert = params.self_signed(&key_pair).unwrap();
+
+    CertifiedKey { cert, key_pair }
+}
+
+fn issue(ca: &CertifiedKey) -> CertifiedKey {
+    let key_pair = KeyPair::generate().unwrap();
+
+    let mut params = CertificateParams::new(vec![SERVER_NAME.to_string()]).unwrap();
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+
+    let cert = params.signed_by(&key_pair, &ca.cert, &ca.key_pair).unwrap();
+
+    CertifiedKey { cert, key_pair }
+}
+
+fn latency(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let upstream = runtime.block_on(Upstream::start());
+    let names = AtomicU64::new(0);
+    let name = || format!("host-{}.bench.test", names.fetch_add(1, Ordering::Relaxed));
+
+    let mut group = c.benchmark_group("resolve");
+
+    // A daemon that just started: the connection to the upstream is opened
+    // by the query
+    group.bench_function("first query", |b| {
+        b.iter_custom(|iters| runtime.block_on(async {
+            let mut elapsed = Duration::ZERO;
+
+            for _ in 0..iters {
+                let resolver = upstream.resolver().await;
+                let name = name();
+
+                let start = Instant::now();
+                resolver.resolve(0, &name, A).await.unwrap();
+                elapsed += start.elapsed();
+            }
+
+            elapsed
+        }))
+    });
+
+    let resolver = runtime.block_on(upstream.resolver());
+
+    // Names not in the cache, over the connection already open
+    group.bench_function("cache miss", |b| {
+        b.iter_custom(|iters| runtime.block_on(async {
+            let mut elapsed = Duration::ZERO;
+
+            for _ in 0..iters {
+                let name = name();
+
+                let start = Instant::now();
+                resolver.resolve(0, &name, A).await.unwrap();
+                elapsed += start.elapsed();
+            }
+
+            elapsed
+        }))
+    });
+
+    let cached = name();
+
+    runtime.block_on(resolver.resolve(0, &cached, A)).unwrap();
+
+    group.bench_function("cache hit", |b| {
+        b.iter_custom(|iters| runtime.block_on(async {
+            let start = Instant::now();
+
+            for _ in 0..iters {
+                resolver.resolve(0, &cached, A).await.unwrap();
+            }
+
+            start.elapsed()
+        }))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, latency);
+criterion_main!(benches);
@@ -0,0 +1,451 @@
+use std::sync::Arc;
+
+use ring::digest::{digest, SHA256};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{CertificateError, ClientConfig, DigitallySignedStruct, OtherError, RootCertStore, SignatureScheme};
+use tracing::{error, warn};
+
+use doh_common::error::Error;
+
+use crate::settings::CertificateSettings;
+
+const DER_SEQUENCE: u8 = 0x30;
+
+/// Verifies the certificates of one upstream against the web PKI, or its own
+/// CA bundle, and, when there are any, its pins: the SHA-256 digests of the
+/// SubjectPublicKeyInfo of one of the certificates of the validated path,
+/// its trust anchor included (RFC 7469 section 2.4).
+#[derive(Debug)]
+pub struct CertificateVerifier {
+    webpki: Arc<WebPkiServerVerifier>,
+    roots: Arc<RootCertStore>,
+    pins: Vec<[u8; 32]>,
+    // Certificate chain and key presented when the server asks for them.
+    identity: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+}
+
+impl CertificateVerifier {
+    pub fn new(settings: &CertificateSettings) -> Result<Arc<Self>, Error> {
+        let mut roots = RootCertStore::empty();
+
+        match settings.ca_bundle() {
+            Some(path) => {
+                let (added, ignored) = roots.add_parsable_certificates(read_certificates(path)?);
+
+                if added == 0 {
+                    error!("CA bundle {} holds no usable certificate", path);
+                    return Err(Error::UpstreamError);
+                }
+
+                if ignored > 0 {
+                    warn!("ignoring {} invalid certificates of CA bundle {}", ignored, path);
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+
+        let roots = Arc::new(roots);
+
+        let webpki = WebPkiServerVerifier::builder_with_provider(roots.clone(), provider())
+            .build()
+            .map_err(|e| {
+                error!("unable to configure certificate verification: {}", e);
+                Error::UpstreamError
+            })?;
+
+        let identity = match settings.client_certificate() {
+            Some((certificate, key)) => {
+                let key = PrivateKeyDer::from_pem_file(key).map_err(|e| {
+                    error!("unable to read client key {}: {}", key, e);
+                    Error::UpstreamError
+                })?;
+
+                Some((read_certificates(certificate)?, key))
+            }
+            None => None,
+        };
+
+        Ok(Arc::new(Self {
+            webpki,
+            roots,
+            pins: settings.pins().to_vec(),
+            identity,
+        }))
+    }
+
+    /// TLS configuration of the clients verifying certificates with `self`.
+    pub fn client_config(self: &Arc<Self>) -> Result<ClientConfig, Error> {
+        let config = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| {
+                error!("unable to configure TLS: {}", e);
+                Error::UpstreamError
+            })?
+            .dangerous()
+            .with_custom_certificate_verifier(self.clone());
+
+        match &self.identity {
+            Some((certificates, key)) => config
+                .with_client_auth_cert(certificates.clone(), key.clone_key())
+                .map_err(|e| {
+                    error!("unable to use the client certificate: {}", e);
+                    Error::UpstreamError
+                }),
+            None => Ok(config.with_no_client_auth()),
+        }
+    }
+
+    /// Whether a path from `end_entity` to one of the roots carries a pinned
+    /// key. Only the certificates of the path count: any other the server
+    /// sends along, trusted or not, proves nothing.
+    fn is_path_pinned(&self, end_entity: &CertificateDer<'_>, intermediates: &[CertificateDer<'_>], now: UnixTime) -> bool {
+        let Ok(certificate) = webpki::EndEntityCert::try_from(end_entity) else {
+            return false;
+        };
+
+        // Refusing a path has webpki look for another one
+        let pinned = |path: &webpki::VerifiedPath<'_>| {
+            let anchor = der_sequence(&path.anchor().subject_public_key_info);
+
+            let mut keys = std::iter::once(path.end_entity().subject_public_key_info())
+                .chain(path.intermediate_certificates().map(|certificate| certificate.subject_public_key_info()));
+
+            if self.is_pinned(&anchor) || keys.any(|spki| self.is_pinned(&spki)) {
+                Ok(())
+            } else {
+                Err(webpki::Error::UnknownIssuer)
+            }
+        };
+
+        certificate
+            .verify_for_usage(
+                provider().signature_verification_algorithms.all,
+                &self.roots.roots,
+                intermediates,
+                now,
+                webpki::KeyUsage::server_auth(),
+                None,
+                Some(&pinned))
+            .is_ok()
+    }
+
+    fn is_pinned(&self, spki: &[u8]) -> bool {
+        let hash = digest(&SHA256, spki);
+
+        self.pins.iter().any(|pin| pin.as_slice() == hash.as_ref())
+    }
+}
+
+impl ServerCertVerifier for CertificateVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+
+        let verified = self.webpki.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+
+        if self.pins.is_empty() || self.is_path_pinned(end_entity, intermediates, now) {
+            return Ok(verified);
+        }
+
+        error!("certificate of {:?} matches none of the pinned keys", server_name);
+
+        Err(rustls::Error::InvalidCertificate(CertificateError::Other(OtherError(Arc::new(PinMismatch)))))
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+
+        self.webpki.verify_tls12_signature(message, certificate, signature)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+
+        self.webpki.verify_tls13_signature(message, certificate, signature)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.webpki.supported_verify_schemes()
+    }
+}
+
+/// Marks the handshakes refused for the pins, in the error they fail with, so
+/// that every connection finds out why its own handshake failed.
+#[derive(Debug)]
+struct PinMismatch;
+
+impl std::fmt::Display for PinMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the certificate matches none of the pinned keys")
+    }
+}
+
+impl std::error::Error for PinMismatch {}
+
+/// [`Error::PinMismatch`] or [`Error::TlsError`] when the handshake behind
+/// the error of a failed connection refused the certificate of the server.
+pub fn rejection(error: &(dyn std::error::Error + 'static)) -> Option<Error> {
+    let mut current = Some(error);
+
+    while let Some(error) = current {
+        // An I/O error hides what it wraps from source()
+        let inner = error
+            .downcast_ref::<std::io::Error>()
+            .and_then(|e| e.get_ref())
+            .map(|inner| inner as &(dyn std::error::Error + 'static));
+
+        match inner.unwrap_or(error).downcast_ref::<rustls::Error>() {
+            Some(rustls::Error::InvalidCertificate(CertificateError::Other(OtherError(other)))) if other.is::<PinMismatch>() => {
+                return Some(Error::PinMismatch);
+            }
+            Some(rustls::Error::InvalidCertificate(_)) => return Some(Error::TlsError),
+            _ => {}
+        }
+
+        current = inner.or_else(|| error.source());
+    }
+
+    None
+}
+
+/// The certificates of a PEM file, at least one.
+fn read_certificates(path: &str) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let certificates = CertificateDer::pem_file_iter(path)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| {
+            error!("unable to read certificates {}: {}", path, e);
+            Error::UpstreamError
+        })?;
+
+    if certificates.is_empty() {
+        error!("no certificate in {}", path);
+        return Err(Error::UpstreamError);
+    }
+
+    Ok(certificates)
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Wraps `content` in a DER SEQUENCE, as trust anchors keep their
+/// SubjectPublicKeyInfo without its header.
+fn der_sequence(content: &[u8]) -> Vec<u8> {
+    let mut der = vec![DER_SEQUENCE];
+
+    if content.len() < 0x80 {
+        der.push(content.len() as u8);
+    } else {
+        let length: Vec<u8> = content.len().to_be_bytes().into_iter().skip_while(|byte| *byte == 0).collect();
+
+        der.push(0x80 | length.len() as u8);
+        der.extend_from_slice(&length);
+    }
+
+    der.extend_from_slice(content);
+
+    der
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::Duration;
+
+    use rcgen::ExtendedKeyUsagePurpose;
+    use reqwest::Url;
+
+    use super::*;
+    use crate::client::HttpClient;
+    use crate::settings::Proxy;
+    use crate::testing::{authority, https_server, intermediate, issue, tls_config, Pki, SERVER_NAME};
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    /// How an I/O error carries the error of a handshake.
+    fn handshake_error(error: rustls::Error) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, error)
+    }
+
+    /// An error with a source, as the HTTP client wraps the ones of its connections.
+    #[derive(Debug)]
+    struct Wrapper(std::io::Error);
+
+    impl std::fmt::Display for Wrapper {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "connection failed")
+        }
+    }
+
+    impl std::error::Error for Wrapper {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    fn pin_mismatch() -> rustls::Error {
+        rustls::Error::InvalidCertificate(CertificateError::Other(OtherError(Arc::new(PinMismatch))))
+    }
+
+    #[test]
+    fn tells_the_rejections_apart_from_their_error() {
+        let mismatch = Wrapper(handshake_error(pin_mismatch()));
+        let untrusted = Wrapper(handshake_error(rustls::Error::InvalidCertificate(CertificateError::UnknownIssuer)));
+        let other = Wrapper(std::io::Error::from(std::io::ErrorKind::ConnectionRefused));
+
+        assert!(matches!(rejection(&mismatch), Some(Error::PinMismatch)));
+        assert!(matches!(rejection(&untrusted), Some(Error::TlsError)));
+        assert!(rejection(&other).is_none());
+        assert!(rejection(&handshake_error(rustls::Error::HandshakeNotComplete)).is_none());
+    }
+
+    #[tokio::test]
+    async fn concurrent_connections_each_report_their_own_failure() {
+        let pki = Pki::new();
+
+        let address = https_server(pki.server_config(&[b"http/1.1"]), |_| async { (200, vec![]) }).await;
+
+        // A port nothing listens on
+        let closed = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap();
+
+        let settings = CertificateSettings::new(Some(&pki.ca_bundle()), None, vec![[0; 32]]);
+        let verifier = CertificateVerifier::new(&settings).unwrap();
+
+        let client = |port: u16| {
+            let url = format!("https://{}:{}/", SERVER_NAME, port);
+            let client = HttpClient::new(&[LOCALHOST], &Url::parse(&url).unwrap(), &Proxy::Direct, verifier.clone(), Duration::from_secs(5)).unwrap();
+
+            async move { client.request_bytes(&url).await }
+        };
+
+        let pinned: Vec<_> = (0..8).map(|_| tokio::spawn(client(address.port()))).collect();
+        let refused: Vec<_> = (0..8).map(|_| tokio::spawn(client(closed.port()))).collect();
+
+        for result in futures::future::join_all(pinned).await {
+            assert!(matches!(result.unwrap(), Err(Error::PinMismatch)));
+        }
+
+        for result in futures::future::join_all(refused).await {
+            assert!(matches!(result.unwrap(), Err(Error::UpstreamError)));
+        }
+    }
+
+    /// Requests `/` from the local server at `address` with `settings`.
+    async fn fetch(address: std::net::SocketAddr, settings: &CertificateSettings) -> Result<Vec<u8>, Error> {
+        let url = format!("https://{}:{}/", SERVER_NAME, address.port());
+        let client = HttpClient::new(&[LOCALHOST], &Url::parse(&url).unwrap(), &Proxy::Direct, CertificateVerifier::new(settings)?, Duration::from_secs(5))?;
+
+        client.request_bytes(&url).await
+    }
+
+    #[tokio::test]
+    async fn trusts_the_custom_ca_bundle() {
+        let pki = Pki::new();
+
+        let address = https_server(pki.server_config(&[b"http/1.1"]), |_| async { (200, b"ok".to_vec()) }).await;
+
+        let body = fetch(address, &CertificateSettings::new(Some(&pki.ca_bundle()), None, vec![])).await.unwrap();
+
+        assert_eq!(body, b"ok");
+    }
+
+    #[tokio::test]
+    async fn refuses_a_chain_of_an_untrusted_ca() {
+        let pki = Pki::new();
+
+        let address = https_server(pki.server_config(&[b"http/1.1"]), |_| async { (200, vec![]) }).await;
+
+        // Neither another bundle nor the system roots know the CA
+        let other = fetch(address, &CertificateSettings::new(Some(&pki.other_ca_bundle()), None, vec![])).await;
+        let system = fetch(address, &CertificateSettings::new(None, None, vec![])).await;
+
+        assert!(matches!(other, Err(Error::TlsError)));
+        assert!(matches!(system, Err(Error::TlsError)));
+    }
+
+    #[tokio::test]
+    async fn presents_the_client_certificate() {
+        let pki = Pki::new();
+        let expected = pki.client_certificate();
+
+        let address = https_server(pki.mtls_server_config(&[b"http/1.1"]), move |request| {
+            let presented = request.client_certificate == Some(expected.clone());
+
+            async move { if presented { (200, b"ok".to_vec()) } else { (403, vec![]) } }
+        }).await;
+
+        let (certificate, key) = pki.client_files();
+
+        let with_identity = fetch(address, &CertificateSettings::new(Some(&pki.ca_bundle()), Some((&certificate, &key)), vec![])).await;
+        let without_identity = fetch(address, &CertificateSettings::new(Some(&pki.ca_bundle()), None, vec![])).await;
+
+        assert_eq!(with_identity.unwrap(), b"ok");
+        assert!(without_identity.is_err());
+    }
+
+    /// The pin of the key of a certificate.
+    fn pin(certificate: &CertificateDer<'_>) -> [u8; 32] {
+        let spki = webpki::EndEntityCert::try_from(certificate).unwrap().subject_public_key_info();
+
+        digest(&SHA256, spki.as_ref()).as_ref().try_into().unwrap()
+    }
+
+    #[tokio::test]
+    async fn pins_any_key_of_the_validated_path() {
+        let pki = Pki::new();
+        let root = authority("Pinned Root");
+        let ca = intermediate(&root, "Pinned CA");
+        let server = issue(&ca, ExtendedKeyUsagePurpose::ServerAuth, &[SERVER_NAME, "127.0.0.1"]);
+
+        let bundle = pki.path("pinned-root.pem");
+        std::fs::write(&bundle, root.cert.pem()).unwrap();
+
+        let address = https_server(tls_config(&server, &[ca.cert.der().clone()], &[b"http/1.1"], None), |_| async { (200, b"ok".to_vec()) }).await;
+
+        // The root is never sent, it is pinned as the trust anchor of the path
+        for certificate in [server.cert.der(), ca.cert.der(), root.cert.der()] {
+            let body = fetch(address, &CertificateSettings::new(Some(&bundle), None, vec![pin(certificate)])).await;
+
+            assert_eq!(body.unwrap(), b"ok");
+        }
+
+        let unrelated = fetch(address, &CertificateSettings::new(Some(&bundle), None, vec![pin(&pki.server_certificate())])).await;
+
+        assert!(matches!(unrelated, Err(Error::PinMismatch)));
+    }
+
+    #[tokio::test]
+    async fn a_pinned_certificate_off_the_path_proves_nothing() {
+        let pki = Pki::new();
+        let root = authority("Pinned Root");
+        let ca = intermediate(&root, "Pinned CA");
+
+        // Both CAs are trusted, yet the certificate of the server is only
+        // issued by the one whose key is not pinned
+        let bundle = pki.path("both-roots.pem");
+        std::fs::write(&bundle, std::fs::read_to_string(pki.ca_bundle()).unwrap() + &root.cert.pem()).unwrap();
+
+        let test_ca = CertificateDer::from_pem_file(pki.ca_bundle()).unwrap();
+
+        let address = https_server(pki.server_config_with_chain(&[b"http/1.1"], &[test_ca, ca.cert.der().clone()]), |_| async { (200, vec![]) }).await;
+
+        let result = fetch(address, &CertificateSettings::new(Some(&bundle), None, vec![pin(ca.cert.der())])).await;
+
+        assert!(matches!(result, Err(Error::PinMismatch)));
+    }
+}
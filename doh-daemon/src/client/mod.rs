@@ -1,68 +1,379 @@
 use std::net::{IpAddr, SocketAddr};
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tracing::{debug, instrument};
-use reqwest::Url;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Client, Response, StatusCode, Url};
 use serde::de::DeserializeOwned;
 
-#[instrument(skip(headers, query_params))]
-pub async fn request<'de, B>(
-                                server_address: IpAddr,
-                             port: u16,
-                             url: &str,
-                             headers: &Vec<(&str, &str)>,
-                             query_params: &Vec<(&str, &str)>) -> Result<B, doh_common::error::Error>
-where B: DeserializeOwned {
+use crate::client::certificate::CertificateVerifier;
+use crate::settings::Proxy;
 
-    let address = SocketAddr::new(server_address, port);
+pub mod certificate;
+pub mod plain;
+pub mod proxy;
+pub mod tls;
 
-    let url = Url::parse(url)?;
+const DNS_MESSAGE_MEDIA_TYPE: &str = "application/dns-message";
+const OBLIVIOUS_MESSAGE_MEDIA_TYPE: &str = "application/oblivious-dns-message";
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
 
-    let client = reqwest::ClientBuilder::new()
-        .resolve(url.domain().unwrap(), address)
-        .user_agent("???")
-        .brotli(true)
-        .gzip(true)
-        .timeout(Duration::from_secs(3))
-        .tcp_keepalive(Some(Duration::from_secs(30)))
-        .http2_keep_alive_timeout(Duration::from_secs(30))
-        .build()?;
+#[derive(Clone, Debug, PartialEq)]
+pub enum HttpMethod {
+    Get,
+    Post,
+}
+
+/// HTTP client bound to one upstream server. It is built once and kept for the
+/// lifetime of the daemon so its connections are reused: HTTP/2 multiplexes
+/// concurrent queries over a single TLS connection kept alive with pings.
+#[derive(Clone, Debug)]
+pub struct HttpClient {
+    client: Client,
+}
+
+impl HttpClient {
+    /// Connects to the `server_addresses`, unless a proxy resolves the host name of `url`.
+    pub fn new(
+        server_addresses: &[IpAddr],
+        url: &Url,
+        proxy: &Proxy,
+        verifier: Arc<CertificateVerifier>,
+        timeout: Duration) -> Result<Self, doh_common::error::Error> {
+        let port = url.port_or_known_default().unwrap_or(443);
+
+        let addresses: Vec<SocketAddr> = server_addresses
+            .iter()
+            .map(|ip| SocketAddr::new(*ip, port))
+            .collect();
+
+        let domain = url.domain().ok_or(doh_common::error::Error::UpstreamError)?;
+
+        let mut tls = verifier.client_config()?;
+        tls.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
 
-    let mut request_builder = client.get(url);
+        let mut builder = reqwest::ClientBuilder::new().use_preconfigured_tls(tls);
 
-    for (key, value) in headers {
-        request_builder = request_builder.header(key.to_string(), value.to_string());
+        match proxy::http_proxy(proxy)? {
+            Some(http_proxy) => builder = builder.proxy(http_proxy),
+            None if *proxy == Proxy::Direct => builder = builder.no_proxy(),
+            None => {}
+        }
+
+        let client = builder
+            .resolve_to_addrs(domain, &addresses)
+            .user_agent("???")
+            .brotli(true)
+            .gzip(true)
+            .timeout(timeout)
+            .connect_timeout(timeout)
+            .pool_idle_timeout(Duration::from_secs(90))
+            .pool_max_idle_per_host(4)
+            .tcp_keepalive(Some(Duration::from_secs(30)))
+            .tcp_nodelay(true)
+            .http2_keep_alive_interval(Some(Duration::from_secs(30)))
+            .http2_keep_alive_timeout(Duration::from_secs(30))
+            .http2_keep_alive_while_idle(true)
+            .build()?;
+
+        Ok(Self { client })
     }
 
-    request_builder = request_builder.query(query_params);
+    /// Opens the connection ahead of the first query, so it does not pay for the
+    /// TCP and TLS handshakes. The status of the reply does not matter.
+    #[instrument(skip(self))]
+    pub async fn warm_up(&self, url: &str) -> Result<(), doh_common::error::Error> {
+        let response = self.client.head(url).send().await.map_err(failure)?;
+
+        debug!("warm up reply {:?} over {:?}", response.status(), response.version());
+
+        Ok(())
+    }
+
+    #[instrument(skip(self, headers))]
+    pub async fn request<B>(&self, url: &str, headers: &[(String, String)]) -> Result<B, doh_common::error::Error>
+    where B: DeserializeOwned {
+
+        let url = Url::parse(url)?;
 
-    let request = request_builder.build()?;
+        let mut request_builder = self.client.get(url);
 
-    let response = client.execute(request).await?;
+        for (key, value) in headers {
+            request_builder = request_builder.header(key, value);
+        }
 
-    let status = response.status();
+        let request = request_builder.build()?;
+
+        let response = self.client.execute(request).await.map_err(failure)?;
+
+        let status = response.status();
+
+        if status.is_success() {
+
+            //debug!("Response body: {}", body.clone());
+
+            let body = response.bytes().await?;
+
+            debug!("response body: {}", String::from_utf8_lossy(&body));
+
+            let obj : B = serde_json::from_slice(&body)?;
+
+            Ok(obj)
+        } else {
+
+            let error = status_error(&response);
+
+            let body = response.bytes().await?;
+
+            debug!("response status {:?}: {:?}", status, String::from_utf8_lossy(&body));
+
+            Err(error)
+
+        }
+    }
 
-    if status.is_success() {
+    /// Fetches a binary document, such as the key configuration of an ODoH target.
+    #[instrument(skip(self))]
+    pub async fn request_bytes(&self, url: &str) -> Result<Vec<u8>, doh_common::error::Error> {
+        let response = self.client.get(Url::parse(url)?).send().await.map_err(failure)?;
 
-        //debug!("Response body: {}", body.clone());
+        let status = response.status();
+
+        let error = status_error(&response);
 
         let body = response.bytes().await?;
 
-        debug!("response body: {}", String::from_utf8_lossy(&body.to_vec()));
+        if status.is_success() {
+            Ok(body.to_vec())
+        } else {
+            debug!("response status {:?}: {:?}", status, String::from_utf8_lossy(&body));
+
+            Err(error)
+        }
+    }
+
+    /// Sends a binary DNS message as specified by RFC 8484. For GET the message must
+    /// already be part of `url`, in its `dns` query parameter; for POST it is the body.
+    #[instrument(skip(self, headers, message))]
+    pub async fn request_message(
+        &self,
+        url: &str,
+        method: &HttpMethod,
+        headers: &[(String, String)],
+        message: &[u8]) -> Result<Vec<u8>, doh_common::error::Error> {
+
+        self.exchange(url, method, DNS_MESSAGE_MEDIA_TYPE, headers, message).await
+    }
+
+    /// Sends an encrypted Oblivious DoH message (RFC 9230) to a relay.
+    #[instrument(skip(self, headers, message))]
+    pub async fn request_oblivious_message(
+        &self,
+        url: &str,
+        headers: &[(String, String)],
+        message: &[u8]) -> Result<Vec<u8>, doh_common::error::Error> {
+
+        self.exchange(url, &HttpMethod::Post, OBLIVIOUS_MESSAGE_MEDIA_TYPE, headers, message).await
+    }
+
+    async fn exchange(
+        &self,
+        url: &str,
+        method: &HttpMethod,
+        media_type: &str,
+        headers: &[(String, String)],
+        message: &[u8]) -> Result<Vec<u8>, doh_common::error::Error> {
+
+        let url = Url::parse(url)?;
+
+        let mut request_builder = match method {
+            HttpMethod::Get => self.client.get(url),
+            HttpMethod::Post => {
+                self.client.post(url)
+                    .header(reqwest::header::CONTENT_TYPE, media_type)
+                    .body(message.to_vec())
+            }
+        };
+
+        request_builder = request_builder.header(reqwest::header::ACCEPT, media_type);
+
+        for (key, value) in headers {
+            request_builder = request_builder.header(key, value);
+        }
 
-        let obj : B = serde_json::from_slice(&body)?;
+        let request = request_builder.build()?;
 
-        Ok(obj)
-    } else {
+        let response = self.client.execute(request).await.map_err(failure)?;
+
+        let status = response.status();
+
+        let error = status_error(&response);
 
         let body = response.bytes().await?;
 
-        debug!("response status {:?}: {:?}", status, String::from_utf8_lossy(&body.to_vec()));
+        if status.is_success() {
+
+            debug!("response body: {} bytes", body.len());
+
+            Ok(body.to_vec())
+        } else {
+
+            debug!("response status {:?}: {:?}", status, String::from_utf8_lossy(&body));
+
+            Err(error)
+        }
+    }
+}
+
+fn failure(error: reqwest::Error) -> doh_common::error::Error {
+    certificate::rejection(&error).unwrap_or_else(|| error.into())
+}
+
+/// The error of a reply with a status other than success, along with how long
+/// its Retry-After header asks to wait.
+fn status_error(response: &Response) -> doh_common::error::Error {
+    let retry_after = response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| parse_retry_after(value, SystemTime::now()));
+
+    match response.status() {
+        StatusCode::TOO_MANY_REQUESTS => doh_common::error::Error::RateLimited { retry_after },
+        status => doh_common::error::Error::HttpError { status: status.as_u16(), retry_after },
+    }
+}
+
+/// Reads a Retry-After value, either a number of seconds or an HTTP date
+/// such as `Sun, 06 Nov 1994 08:49:37 GMT` (RFC 9110 section 10.2.3).
+fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    // IMF-fixdate, the only format senders are to use
+    let fields: Vec<&str> = value.split_whitespace().collect();
+
+    let [_, day, month, year, time, "GMT"] = fields.as_slice() else {
+        return None;
+    };
+
+    let month = MONTHS.iter().position(|name| name == month)? as i64 + 1;
+    let day = digits(day, 2)?;
+    let year = digits(year, 4)?;
+
+    let clock = time.split(':').map(|field| digits(field, 2)).collect::<Option<Vec<_>>>()?;
+
+    let [hours, minutes, seconds] = clock[..] else {
+        return None;
+    };
+
+    // The server sends the header, nothing says the date exists; 60 seconds
+    // leave room for a leap second
+    if !(1..=days_in_month(year, month)).contains(&day) || hours > 23 || minutes > 59 || seconds > 60 {
+        return None;
+    }
+
+    let seconds = days_from_civil(year, month, day)
+        .checked_mul(86400)?
+        .checked_add(hours * 3600 + minutes * 60 + seconds)?;
+
+    let date = UNIX_EPOCH.checked_add(Duration::from_secs(u64::try_from(seconds).ok()?))?;
+
+    // A date in the past asks for no wait at all
+    Some(date.duration_since(now).unwrap_or_default())
+}
+
+/// A field of exactly `count` decimal digits.
+fn digits(field: &str, count: usize) -> Option<i64> {
+    if field.len() != count || !field.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
 
-        Err(doh_common::error::Error::UpstreamError)
+    field.parse().ok()
+}
 
+fn days_in_month(year: i64, month: i64) -> i64 {
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+
+    match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
     }
 }
 
+/// Days between 1970-01-01 and a date of the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+/// Expands the subset of RFC 6570 URI templates used by DoH servers: simple
+/// `{var}` substitutions and the form-style query expansions `{?var,...}` and
+/// `{&var,...}`. Variables without a value are left out.
+pub fn expand_url_template(template: &str, variables: &[(&str, &str)]) -> String {
+    let mut url = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        url.push_str(&rest[..start]);
+
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => {
+                rest = &rest[start..];
+                break;
+            }
+        };
+
+        let expression = &rest[start + 1..end];
+
+        let (separator, names) = match expression.chars().next() {
+            Some('?') => (Some('?'), &expression[1..]),
+            Some('&') => (Some('&'), &expression[1..]),
+            _ => (None, expression),
+        };
+
+        let mut first = true;
+
+        for name in names.split(',').map(|name| name.trim()) {
+            let value = match variables.iter().find(|(key, _)| *key == name) {
+                Some((_, value)) => value,
+                None => continue,
+            };
+
+            let encoded: String = url::form_urlencoded::byte_serialize(value.as_bytes()).collect();
+
+            match separator {
+                Some(separator) => {
+                    url.push(if first { separator } else { '&' });
+                    url.push_str(name);
+                    url.push('=');
+                }
+                None if !first => url.push(','),
+                None => {}
+            }
+
+            url.push_str(&encoded);
+            first = false;
+        }
+
+        rest = &rest[end + 1..];
+    }
+
+    url.push_str(rest);
+
+    url
+}
 
 // pub fn request<'de, B>(server_address: IpAddr,
 //                        port: u16,
@@ -115,4 +426,95 @@ where B: DeserializeOwned {
 //
 //         Err(doh_common::error::Error::UpstreamError)
 //     }
-// }
\ No newline at end of file
+// }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sun, 06 Nov 1994 08:49:37 GMT
+    const EXAMPLE: u64 = 784111777;
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn reads_delta_seconds() {
+        assert_eq!(parse_retry_after("120", at(0)), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 0 ", at(0)), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn reads_an_imf_fixdate() {
+        let date = "Sun, 06 Nov 1994 08:49:37 GMT";
+
+        assert_eq!(parse_retry_after(date, at(EXAMPLE - 90)), Some(Duration::from_secs(90)));
+        assert_eq!(parse_retry_after(date, at(EXAMPLE)), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn a_date_in_the_past_asks_for_no_wait() {
+        assert_eq!(parse_retry_after("Sun, 06 Nov 1994 08:49:37 GMT", at(EXAMPLE + 3600)), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn rejects_malformed_values() {
+        for value in [
+            "",
+            "-5",
+            "1.5",
+            "soon",
+            // Obsolete formats senders are not to use
+            "Sunday, 06-Nov-94 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 1994",
+            "Sun, 06 Nov 1994 08:49:37 UTC",
+            "Sun, 06 Foo 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1994 08:49 GMT",
+            "Sun, 06 Nov 1994 08:xx:37 GMT",
+            "Thu, 01 Jan 1900 00:00:00 GMT",
+            // Fields out of their range
+            "Sun, 06 Nov 1994 99:99:99 GMT",
+            "Sun, 06 Nov 1994 24:00:00 GMT",
+            "Sun, 06 Nov 1994 08:60:00 GMT",
+            "Sun, 06 Nov 1994 08:49:61 GMT",
+            "Sun, 00 Nov 1994 08:49:37 GMT",
+            "Sun, 31 Nov 1994 08:49:37 GMT",
+            "Sun, 29 Feb 1900 08:49:37 GMT",
+            "Sun, 99 Nov 1994 08:49:37 GMT",
+            // Not of the fixed width of the format
+            "Sun, 6 Nov 1994 08:49:37 GMT",
+            "Sun, 06 Nov 94 08:49:37 GMT",
+            "Sun, 06 Nov 1994 8:49:37 GMT",
+            "Sun, 06 Nov +994 08:49:37 GMT",
+            "Sun, 06 Nov 1994 08:49:37:00 GMT",
+            // Years that would overflow the count of seconds
+            "Sun, 06 Nov 9223372036854775807 08:49:37 GMT",
+            "Sun, 06 Nov 292277026596 08:49:37 GMT",
+            "Sun, 06 Nov -9223372036854775808 08:49:37 GMT",
+        ] {
+            assert_eq!(parse_retry_after(value, at(EXAMPLE)), None, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn reads_the_edges_of_the_ranges() {
+        let cases = [
+            ("Sat, 31 Dec 9999 23:59:59 GMT", 253402300799),
+            ("Thu, 29 Feb 2024 00:00:00 GMT", 1709164800),
+            ("Sat, 31 Dec 2016 23:59:60 GMT", 1483228800),
+        ];
+
+        for (value, seconds) in cases {
+            assert_eq!(parse_retry_after(value, at(0)), Some(Duration::from_secs(seconds)), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn counts_days_across_leap_years() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        assert_eq!(days_from_civil(2024, 2, 29), 19782);
+        assert_eq!(days_from_civil(1900, 1, 1), -25567);
+    }
+}
@@ -0,0 +1,200 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tracing::{debug, error, instrument, warn};
+
+use doh_common::error::Error;
+
+const DNS_PORT: u16 = 53;
+const MAX_UDP_REPLY: usize = 4096;
+// https://www.rfc-editor.org/rfc/rfc1035#section-4.1.1
+const FLAG_TC: u8 = 0x02;
+
+/// Classic, unencrypted DNS over UDP, retried over TCP when the reply is
+/// truncated. Only used as a last resort, since it gives away every query.
+#[derive(Debug)]
+pub struct PlainClient {
+    udp_timeout: Duration,
+    tcp_timeout: Duration,
+    random: SystemRandom,
+}
+
+impl PlainClient {
+    pub fn new(udp_timeout: Duration, tcp_timeout: Duration) -> Self {
+        Self { udp_timeout, tcp_timeout, random: SystemRandom::new() }
+    }
+
+    /// Sends the message to each name server in turn until one of them replies,
+    /// returning the reply and the name server that sent it.
+    #[instrument(skip(self, message))]
+    pub async fn request_message(&self, nameservers: &[SocketAddr], message: &[u8]) -> Result<(Vec<u8>, SocketAddr), Error> {
+        if message.len() < 12 {
+            return Err(Error::UpstreamError);
+        }
+
+        let mut query = message.to_vec();
+
+        // Random IDs and source ports are the only defence against spoofed replies
+        let mut id = [0u8; 2];
+        self.random.fill(&mut id).map_err(|_| Error::UpstreamError)?;
+        query[0..2].copy_from_slice(&id);
+
+        let mut last_error = Error::UpstreamError;
+
+        for nameserver in nameservers {
+            let reply = match self.request_udp(*nameserver, &query).await {
+                Ok(reply) if reply[2] & FLAG_TC != 0 => {
+                    debug!("reply from {} truncated, retrying over TCP", nameserver);
+                    self.request_tcp(*nameserver, &query).await
+                }
+                result => result,
+            };
+
+            match reply {
+                Ok(reply) => return Ok((reply, *nameserver)),
+                Err(e) => {
+                    warn!("name server {} failed: {}", nameserver, e);
+                    last_error = e;
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+
+    async fn request_udp(&self, nameserver: SocketAddr, query: &[u8]) -> Result<Vec<u8>, Error> {
+        let local: SocketAddr = match nameserver {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(nameserver).await?;
+        socket.send(query).await?;
+
+        let mut buffer = vec![0u8; MAX_UDP_REPLY];
+
+        let exchange = async {
+            loop {
+                let length = socket.recv(&mut buffer).await?;
+
+                // Ignore anything that does not answer this very query
+                if length >= 12 && buffer[0..2] == query[0..2] {
+                    return Ok::<usize, std::io::Error>(length);
+                }
+            }
+        };
+
+        match tokio::time::timeout(self.udp_timeout, exchange).await {
+            Ok(length) => Ok(buffer[..length?].to_vec()),
+            Err(_) => {
+                error!("timeout waiting for {} over UDP", nameserver);
+                Err(Error::UpstreamTimeout)
+            }
+        }
+    }
+
+    async fn request_tcp(&self, nameserver: SocketAddr, query: &[u8]) -> Result<Vec<u8>, Error> {
+        let exchange = async {
+            let mut stream = TcpStream::connect(nameserver).await?;
+
+            let mut frame = Vec::with_capacity(query.len() + 2);
+            frame.extend_from_slice(&(query.len() as u16).to_be_bytes());
+            frame.extend_from_slice(query);
+
+            stream.write_all(&frame).await?;
+
+            let length = stream.read_u16().await? as usize;
+            let mut reply = vec![0u8; length];
+            stream.read_exact(&mut reply).await?;
+
+            Ok::<Vec<u8>, std::io::Error>(reply)
+        };
+
+        match tokio::time::timeout(self.tcp_timeout, exchange).await {
+            Ok(reply) => {
+                let reply = reply?;
+
+                if reply.len() < 12 || reply[0..2] != query[0..2] {
+                    error!("unexpected reply from {} over TCP", nameserver);
+                    return Err(Error::MalformedReply);
+                }
+
+                Ok(reply)
+            }
+            Err(_) => {
+                error!("timeout waiting for {} over TCP", nameserver);
+                Err(Error::UpstreamTimeout)
+            }
+        }
+    }
+}
+
+/// Reads the `nameserver` entries of a resolv.conf file.
+pub fn read_resolv_conf(path: &str) -> Result<Vec<SocketAddr>, Error> {
+    let content = std::fs::read_to_string(path)?;
+
+    let nameservers = content
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.starts_with('#') && !line.starts_with(';'))
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+
+            match (parts.next(), parts.next()) {
+                (Some("nameserver"), Some(address)) => {
+                    // Link-local IPv6 addresses may carry a zone, e.g. fe80::1%eth0
+                    let address = address.split('%').next().unwrap_or(address);
+
+                    address.parse::<IpAddr>().ok()
+                }
+                _ => None,
+            }
+        })
+        .map(|ip| SocketAddr::new(ip, DNS_PORT))
+        .collect();
+
+    Ok(nameservers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_nameservers_of_resolv_conf() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("resolv.conf");
+
+        std::fs::write(&path, "\
+            # Generated by NetworkManager\n\
+            search example.test\n\
+            nameserver 192.0.2.53\n\
+            ; nameserver 192.0.2.54\n\
+            #nameserver 192.0.2.55\n\
+            \x20\x20nameserver\t2001:db8::53  # trailing comment\n\
+            nameserver fe80::1%eth0\n\
+            nameserver not-an-address\n\
+            nameserver\n\
+            options edns0 trust-ad\n").unwrap();
+
+        let nameservers = read_resolv_conf(path.to_str().unwrap()).unwrap();
+
+        assert_eq!(nameservers, vec![
+            "192.0.2.53:53".parse::<SocketAddr>().unwrap(),
+            "[2001:db8::53]:53".parse().unwrap(),
+            // Without its zone
+            "[fe80::1]:53".parse().unwrap(),
+        ]);
+    }
+
+    #[test]
+    fn a_missing_resolv_conf_is_an_error() {
+        let directory = tempfile::tempdir().unwrap();
+
+        assert!(read_resolv_conf(directory.path().join("resolv.conf").to_str().unwrap()).is_err());
+    }
+}
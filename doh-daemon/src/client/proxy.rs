@@ -0,0 +1,396 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::{debug, error};
+
+use doh_common::error::Error;
+
+use crate::settings::{Proxy, ProxyServer};
+
+const SOCKS_VERSION: u8 = 5;
+const SOCKS_AUTH_NONE: u8 = 0;
+const SOCKS_AUTH_PASSWORD: u8 = 2;
+const SOCKS_CONNECT: u8 = 1;
+const SOCKS_ADDRESS_IPV4: u8 = 1;
+const SOCKS_ADDRESS_DOMAIN: u8 = 3;
+const SOCKS_ADDRESS_IPV6: u8 = 4;
+// Longest status line and headers accepted from an HTTP proxy
+const MAX_CONNECT_REPLY: usize = 8192;
+
+/// The proxy of the reqwest clients. `None` when the environment decides.
+pub fn http_proxy(proxy: &Proxy) -> Result<Option<reqwest::Proxy>, Error> {
+    let (scheme, server) = match proxy {
+        Proxy::Environment | Proxy::Direct => return Ok(None),
+        Proxy::Socks5(server) => ("socks5h", server),
+        Proxy::HttpConnect(server) => ("http", server),
+    };
+
+    let mut http_proxy = reqwest::Proxy::all(format!("{}://{}", scheme, authority(server.host(), server.port())))?;
+
+    if let Some((user, password)) = server.credentials() {
+        http_proxy = http_proxy.basic_auth(user, password);
+    }
+
+    Ok(Some(http_proxy))
+}
+
+/// Opens a TCP connection to `host` through the proxy, which resolves the name.
+pub async fn connect(proxy: &Proxy, host: &str, port: u16) -> Result<TcpStream, Error> {
+    match proxy {
+        Proxy::Socks5(server) => {
+            let mut stream = TcpStream::connect((server.host(), server.port())).await?;
+            socks5_handshake(&mut stream, server, host, port).await?;
+            Ok(stream)
+        }
+        Proxy::HttpConnect(server) => {
+            let mut stream = TcpStream::connect((server.host(), server.port())).await?;
+            http_connect(&mut stream, server, host, port).await?;
+            Ok(stream)
+        }
+        Proxy::Environment | Proxy::Direct => Err(Error::UpstreamError),
+    }
+}
+
+/// SOCKS5 CONNECT as specified by RFC 1928, with the username and password
+/// authentication of RFC 1929 when there are credentials.
+async fn socks5_handshake(stream: &mut TcpStream, server: &ProxyServer, host: &str, port: u16) -> Result<(), Error> {
+    let method = match server.credentials() {
+        Some(_) => SOCKS_AUTH_PASSWORD,
+        None => SOCKS_AUTH_NONE,
+    };
+
+    stream.write_all(&[SOCKS_VERSION, 1, method]).await?;
+
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+
+    if choice[0] != SOCKS_VERSION || choice[1] != method {
+        error!("SOCKS proxy {} refused authentication method {}", server.host(), method);
+        return Err(Error::UpstreamError);
+    }
+
+    if let Some((user, password)) = server.credentials() {
+        if user.len() > u8::MAX as usize || password.len() > u8::MAX as usize {
+            error!("SOCKS proxy user name and password are limited to 255 bytes");
+            return Err(Error::UpstreamError);
+        }
+
+        let mut request = vec![1, user.len() as u8];
+        request.extend_from_slice(user.as_bytes());
+        request.push(password.len() as u8);
+        request.extend_from_slice(password.as_bytes());
+
+        stream.write_all(&request).await?;
+
+        let mut status = [0u8; 2];
+        stream.read_exact(&mut status).await?;
+
+        if status[1] != 0 {
+            error!("SOCKS proxy {} rejected the credentials", server.host());
+            return Err(Error::UpstreamError);
+        }
+    }
+
+    let mut request = vec![SOCKS_VERSION, SOCKS_CONNECT, 0];
+
+    match host.parse::<std::net::IpAddr>() {
+        Ok(std::net::IpAddr::V4(ip)) => {
+            request.push(SOCKS_ADDRESS_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(std::net::IpAddr::V6(ip)) => {
+            request.push(SOCKS_ADDRESS_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) if host.len() <= u8::MAX as usize => {
+            request.push(SOCKS_ADDRESS_DOMAIN);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+        Err(_) => return Err(Error::UpstreamError),
+    }
+
+    request.extend_from_slice(&port.to_be_bytes());
+
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+
+    if reply[0] != SOCKS_VERSION || reply[1] != 0 {
+        error!("SOCKS proxy {} could not connect to {}:{}, reply {}", server.host(), host, port, reply[1]);
+        return Err(Error::UpstreamError);
+    }
+
+    // The address the proxy connected from, which is of no use here
+    let length = match reply[3] {
+        SOCKS_ADDRESS_IPV4 => 4,
+        SOCKS_ADDRESS_IPV6 => 16,
+        SOCKS_ADDRESS_DOMAIN => stream.read_u8().await? as usize,
+        _ => return Err(Error::UpstreamError),
+    };
+
+    let mut bound = vec![0u8; length + 2];
+    stream.read_exact(&mut bound).await?;
+
+    debug!("SOCKS proxy {} connected to {}:{}", server.host(), host, port);
+
+    Ok(())
+}
+
+/// Opens a tunnel with the CONNECT method of HTTP/1.1 (RFC 9110 section 9.3.6).
+async fn http_connect(stream: &mut TcpStream, server: &ProxyServer, host: &str, port: u16) -> Result<(), Error> {
+    let target = authority(host, port);
+
+    let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", target, target);
+
+    if let Some((user, password)) = server.credentials() {
+        let token = STANDARD.encode(format!("{}:{}", user, password));
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+    }
+
+    request.push_str("\r\n");
+
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read a byte at a time, so nothing that follows the headers is lost
+    let mut reply = Vec::new();
+
+    while !reply.ends_with(b"\r\n\r\n") {
+        if reply.len() >= MAX_CONNECT_REPLY {
+            return Err(Error::UpstreamError);
+        }
+
+        reply.push(stream.read_u8().await?);
+    }
+
+    let reply = String::from_utf8_lossy(&reply);
+    let status = reply.lines().next().unwrap_or_default();
+
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => {
+            debug!("HTTP proxy {} connected to {}", server.host(), target);
+            Ok(())
+        }
+        _ => {
+            error!("HTTP proxy {} could not connect to {}: {}", server.host(), target, status);
+            Err(Error::UpstreamError)
+        }
+    }
+}
+
+fn authority(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::net::Ipv4Addr;
+
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    use super::*;
+
+    const TARGET: &str = "doh.test";
+    const TARGET_PORT: u16 = 853;
+    const CREDENTIALS: (&str, &str) = ("user", "secret");
+
+    /// A proxy on a local port playing `script` with the first client.
+    async fn stand_in<F, R>(script: F) -> (u16, JoinHandle<()>)
+    where
+        F: FnOnce(TcpStream) -> R + Send + 'static,
+        R: Future<Output = ()> + Send,
+    {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            script(stream).await;
+        });
+
+        (port, handle)
+    }
+
+    async fn read(stream: &mut TcpStream, length: usize) -> Vec<u8> {
+        let mut bytes = vec![0u8; length];
+        stream.read_exact(&mut bytes).await.unwrap();
+        bytes
+    }
+
+    /// Reads an HTTP request up to the end of its headers.
+    async fn read_head(stream: &mut TcpStream) -> String {
+        let mut head = vec![];
+
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await.unwrap());
+        }
+
+        String::from_utf8(head).unwrap()
+    }
+
+    fn socks5(port: u16, credentials: Option<(&str, &str)>) -> Proxy {
+        Proxy::Socks5(ProxyServer::new("127.0.0.1", port, credentials))
+    }
+
+    fn http(port: u16, credentials: Option<(&str, &str)>) -> Proxy {
+        Proxy::HttpConnect(ProxyServer::new("127.0.0.1", port, credentials))
+    }
+
+    /// Checks that the tunnel carries what the proxy sends after its reply.
+    async fn assert_tunnel(mut stream: TcpStream, handle: JoinHandle<()>) {
+        assert_eq!(read(&mut stream, 5).await, b"hello");
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn socks5_leaves_the_name_to_the_proxy() {
+        let (port, handle) = stand_in(|mut stream| async move {
+            assert_eq!(read(&mut stream, 3).await, [SOCKS_VERSION, 1, SOCKS_AUTH_NONE]);
+            stream.write_all(&[SOCKS_VERSION, SOCKS_AUTH_NONE]).await.unwrap();
+
+            let mut request = vec![SOCKS_VERSION, SOCKS_CONNECT, 0, SOCKS_ADDRESS_DOMAIN, TARGET.len() as u8];
+            request.extend_from_slice(TARGET.as_bytes());
+            request.extend_from_slice(&TARGET_PORT.to_be_bytes());
+
+            assert_eq!(read(&mut stream, request.len()).await, request);
+
+            stream.write_all(&[SOCKS_VERSION, 0, 0, SOCKS_ADDRESS_IPV4, 10, 0, 0, 1, 0x30, 0x39]).await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+        }).await;
+
+        let stream = connect(&socks5(port, None), TARGET, TARGET_PORT).await.unwrap();
+
+        assert_tunnel(stream, handle).await;
+    }
+
+    #[tokio::test]
+    async fn socks5_authenticates_with_the_credentials() {
+        let (port, handle) = stand_in(|mut stream| async move {
+            assert_eq!(read(&mut stream, 3).await, [SOCKS_VERSION, 1, SOCKS_AUTH_PASSWORD]);
+            stream.write_all(&[SOCKS_VERSION, SOCKS_AUTH_PASSWORD]).await.unwrap();
+
+            assert_eq!(read(&mut stream, 13).await, b"\x01\x04user\x06secret");
+            stream.write_all(&[1, 0]).await.unwrap();
+
+            // An address literal is sent as such
+            assert_eq!(read(&mut stream, 10).await, [SOCKS_VERSION, SOCKS_CONNECT, 0, SOCKS_ADDRESS_IPV4, 192, 0, 2, 1, 0x03, 0x55]);
+
+            // The bound address may be a name as well
+            stream.write_all(&[SOCKS_VERSION, 0, 0, SOCKS_ADDRESS_DOMAIN, 5, b'p', b'r', b'o', b'x', b'y', 0x30, 0x39]).await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+        }).await;
+
+        let stream = connect(&socks5(port, Some(CREDENTIALS)), "192.0.2.1", TARGET_PORT).await.unwrap();
+
+        assert_tunnel(stream, handle).await;
+    }
+
+    #[tokio::test]
+    async fn socks5_fails_when_the_credentials_are_rejected() {
+        let (port, handle) = stand_in(|mut stream| async move {
+            read(&mut stream, 3).await;
+            stream.write_all(&[SOCKS_VERSION, SOCKS_AUTH_PASSWORD]).await.unwrap();
+
+            read(&mut stream, 13).await;
+            stream.write_all(&[1, 1]).await.unwrap();
+        }).await;
+
+        assert!(connect(&socks5(port, Some(CREDENTIALS)), TARGET, TARGET_PORT).await.is_err());
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn socks5_fails_when_no_method_is_acceptable() {
+        let (port, handle) = stand_in(|mut stream| async move {
+            read(&mut stream, 3).await;
+            stream.write_all(&[SOCKS_VERSION, 0xFF]).await.unwrap();
+        }).await;
+
+        assert!(connect(&socks5(port, None), TARGET, TARGET_PORT).await.is_err());
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn socks5_fails_when_the_proxy_cannot_connect() {
+        let (port, handle) = stand_in(|mut stream| async move {
+            read(&mut stream, 3).await;
+            stream.write_all(&[SOCKS_VERSION, SOCKS_AUTH_NONE]).await.unwrap();
+
+            read(&mut stream, 7 + TARGET.len()).await;
+
+            // Host unreachable
+            stream.write_all(&[SOCKS_VERSION, 4, 0, SOCKS_ADDRESS_IPV4, 0, 0, 0, 0, 0, 0]).await.unwrap();
+        }).await;
+
+        assert!(connect(&socks5(port, None), TARGET, TARGET_PORT).await.is_err());
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn http_connect_authenticates_with_the_credentials() {
+        let (port, handle) = stand_in(|mut stream| async move {
+            let head = read_head(&mut stream).await;
+
+            assert!(head.starts_with("CONNECT doh.test:853 HTTP/1.1\r\n"), "{}", head);
+            assert!(head.contains("Host: doh.test:853\r\n"), "{}", head);
+            assert!(head.contains("Proxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n"), "{}", head);
+
+            // The tunnel starts right after the headers, in the same segment
+            stream.write_all(b"HTTP/1.1 200 Connection established\r\nVia: stand-in\r\n\r\nhello").await.unwrap();
+        }).await;
+
+        let stream = connect(&http(port, Some(CREDENTIALS)), TARGET, TARGET_PORT).await.unwrap();
+
+        assert_tunnel(stream, handle).await;
+    }
+
+    #[tokio::test]
+    async fn http_connect_brackets_ipv6_literals() {
+        let (port, handle) = stand_in(|mut stream| async move {
+            let head = read_head(&mut stream).await;
+
+            assert!(head.starts_with("CONNECT [2001:db8::1]:853 HTTP/1.1\r\n"), "{}", head);
+            assert!(!head.contains("Proxy-Authorization"), "{}", head);
+
+            stream.write_all(b"HTTP/1.1 200 OK\r\n\r\nhello").await.unwrap();
+        }).await;
+
+        let stream = connect(&http(port, None), "2001:db8::1", TARGET_PORT).await.unwrap();
+
+        assert_tunnel(stream, handle).await;
+    }
+
+    #[tokio::test]
+    async fn http_connect_fails_on_other_statuses() {
+        for status in ["407 Proxy Authentication Required", "502 Bad Gateway", "101 Switching Protocols"] {
+            let (port, handle) = stand_in(move |mut stream| async move {
+                read_head(&mut stream).await;
+                stream.write_all(format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).as_bytes()).await.unwrap();
+            }).await;
+
+            assert!(connect(&http(port, Some(CREDENTIALS)), TARGET, TARGET_PORT).await.is_err(), "{}", status);
+            handle.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn http_connect_fails_on_endless_headers() {
+        let (port, handle) = stand_in(|mut stream| async move {
+            read_head(&mut stream).await;
+
+            let header = format!("HTTP/1.1 200 OK\r\n{}", "X-Padding: 0\r\n".repeat(MAX_CONNECT_REPLY / 14 + 1));
+            let _ = stream.write_all(header.as_bytes()).await;
+        }).await;
+
+        assert!(connect(&http(port, None), TARGET, TARGET_PORT).await.is_err());
+        handle.await.unwrap();
+    }
+}
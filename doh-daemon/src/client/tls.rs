@@ -0,0 +1,544 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rustls::pki_types::ServerName;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+use tracing::{debug, error, instrument};
+
+use doh_common::error::Error;
+
+use crate::client::certificate::{self, CertificateVerifier};
+use crate::client::proxy;
+use crate::settings::Proxy;
+
+type PendingQueries = Arc<Mutex<HashMap<u16, oneshot::Sender<Vec<u8>>>>>;
+
+/// DNS-over-TLS client (RFC 7858). A single connection is kept open and shared
+/// by all queries, which are pipelined and matched to their answers by ID, so
+/// the TCP and TLS handshakes are only paid when the server closes it.
+pub struct TlsClient {
+    server_name: ServerName<'static>,
+    hostname: String,
+    port: u16,
+    addresses: Vec<SocketAddr>,
+    proxy: Proxy,
+    connector: TlsConnector,
+    // Bounds the connection and, separately, the wait for each reply.
+    timeout: Duration,
+    connection: tokio::sync::Mutex<Option<Arc<Connection>>>,
+    next_id: AtomicU16,
+}
+
+struct Connection {
+    writer: tokio::sync::Mutex<WriteHalf<TlsStream<TcpStream>>>,
+    pending: PendingQueries,
+    closed: Arc<AtomicBool>,
+}
+
+impl std::fmt::Debug for TlsClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "TlsClient({:?})", self.server_name)
+    }
+}
+
+impl TlsClient {
+    /// Connects to the `server_addresses`, unless a proxy resolves `hostname`.
+    pub fn new(
+        hostname: &str,
+        port: u16,
+        server_addresses: &[IpAddr],
+        proxy: &Proxy,
+        verifier: Arc<CertificateVerifier>,
+        timeout: Duration) -> Result<Self, Error> {
+        let server_name = ServerName::try_from(hostname.to_string())
+            .map_err(|e| {
+                error!("invalid TLS server name {}: {}", hostname, e);
+                Error::UpstreamError
+            })?;
+
+        let config = verifier.client_config()?;
+
+        let addresses = server_addresses
+            .iter()
+            .map(|ip| SocketAddr::new(*ip, port))
+            .collect();
+
+        Ok(Self {
+            server_name,
+            hostname: hostname.to_string(),
+            port,
+            addresses,
+            proxy: proxy.clone(),
+            connector: TlsConnector::from(Arc::new(config)),
+            timeout,
+            connection: tokio::sync::Mutex::new(None),
+            next_id: AtomicU16::new(1),
+        })
+    }
+
+    /// Sends a DNS message and waits for the matching reply. The message ID is
+    /// rewritten, since IDs must be unique among the queries in flight.
+    #[instrument(skip_all, fields(server = ?self.server_name))]
+    pub async fn request_message(&self, message: &[u8]) -> Result<Vec<u8>, Error> {
+        if message.len() < 2 || message.len() > u16::MAX as usize {
+            return Err(Error::UpstreamError);
+        }
+
+        let connection = self.connection().await?;
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+
+        let (sender, receiver) = oneshot::channel();
+
+        connection.register(id, sender)?;
+
+        // Taken out of the pending queries however the wait ends, the caller
+        // giving up on it included
+        let _pending = PendingQuery { connection: &connection, id };
+
+        let mut frame = Vec::with_capacity(message.len() + 2);
+        frame.extend_from_slice(&(message.len() as u16).to_be_bytes());
+        frame.extend_from_slice(&id.to_be_bytes());
+        frame.extend_from_slice(&message[2..]);
+
+        let written = tokio::time::timeout(self.timeout, async {
+            let mut writer = connection.writer.lock().await;
+
+            writer.write_all(&frame).await?;
+            writer.flush().await
+        }).await;
+
+        match written {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                connection.closed.store(true, Ordering::Relaxed);
+                return Err(e.into());
+            }
+            Err(_) => {
+                connection.closed.store(true, Ordering::Relaxed);
+                error!("timeout sending query {}", id);
+                return Err(Error::UpstreamTimeout);
+            }
+        }
+
+        match tokio::time::timeout(self.timeout, receiver).await {
+            Ok(Ok(mut reply)) => {
+                reply[..2].copy_from_slice(&message[..2]);
+                Ok(reply)
+            }
+            Ok(Err(_)) => {
+                debug!("connection closed before query {} was answered", id);
+                Err(Error::UpstreamError)
+            }
+            Err(_) => {
+                // The server may have silently dropped the connection, which
+                // the next queries would otherwise keep waiting on
+                connection.closed.store(true, Ordering::Relaxed);
+                error!("query {} timed out", id);
+                Err(Error::UpstreamTimeout)
+            }
+        }
+    }
+
+    /// Opens the connection ahead of the first query.
+    pub async fn warm_up(&self) -> Result<(), Error> {
+        self.connection().await.map(|_| ())
+    }
+
+    async fn connection(&self) -> Result<Arc<Connection>, Error> {
+        let mut current = self.connection.lock().await;
+
+        if let Some(connection) = current.as_ref() {
+            if !connection.closed.load(Ordering::Relaxed) {
+                return Ok(connection.clone());
+            }
+        }
+
+        let connection = Arc::new(self.connect().await?);
+
+        *current = Some(connection.clone());
+
+        Ok(connection)
+    }
+
+    async fn connect(&self) -> Result<Connection, Error> {
+        if self.proxy.is_tunnel() {
+            debug!("connecting to {} through the proxy", self.hostname);
+
+            let tunnel = proxy::connect(&self.proxy, &self.hostname, self.port);
+
+            let stream = match tokio::time::timeout(self.timeout, tunnel).await {
+                Ok(stream) => stream?,
+                Err(_) => {
+                    error!("timeout connecting to {} through the proxy", self.hostname);
+                    return Err(Error::UpstreamTimeout);
+                }
+            };
+
+            return self.handshake(stream, &self.hostname).await;
+        }
+
+        let mut last_error = Error::UpstreamError;
+
+        for address in &self.addresses {
+            debug!("connecting to {}", address);
+
+            let stream = match tokio::time::timeout(self.timeout, TcpStream::connect(address)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    error!("error connecting to {}: {}", address, e);
+                    last_error = e.into();
+                    continue;
+                }
+                Err(_) => {
+                    error!("timeout connecting to {}", address);
+                    last_error = Error::UpstreamTimeout;
+                    continue;
+                }
+            };
+
+            match self.handshake(stream, address).await {
+                Ok(connection) => return Ok(connection),
+                Err(e) => last_error = e,
+            }
+        }
+
+        Err(last_error)
+    }
+
+    async fn handshake(&self, stream: TcpStream, peer: impl std::fmt::Display) -> Result<Connection, Error> {
+        let _ = stream.set_nodelay(true);
+
+        let handshake = self.connector.connect(self.server_name.clone(), stream);
+
+        let stream = match tokio::time::timeout(self.timeout, handshake).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(e)) => {
+                error!("TLS handshake with {} failed: {}", peer, e);
+                return Err(certificate::rejection(&e).unwrap_or(Error::TlsError));
+            }
+            Err(_) => {
+                error!("timeout during TLS handshake with {}", peer);
+                return Err(Error::UpstreamTimeout);
+            }
+        };
+
+        let (reader, writer) = tokio::io::split(stream);
+
+        let pending: PendingQueries = Arc::new(Mutex::new(HashMap::new()));
+        let closed = Arc::new(AtomicBool::new(false));
+
+        tokio::spawn(read_replies(reader, pending.clone(), closed.clone()));
+
+        Ok(Connection {
+            writer: tokio::sync::Mutex::new(writer),
+            pending,
+            closed,
+        })
+    }
+}
+
+impl Connection {
+    /// Waits for the reply to query `id`, unless the server already closed the
+    /// connection: the replies would never come. Checked under the lock the
+    /// reader takes when it gives up, so no query can slip in after it.
+    fn register(&self, id: u16, sender: oneshot::Sender<Vec<u8>>) -> Result<(), Error> {
+        let mut pending = self.pending.lock().unwrap();
+
+        if self.closed.load(Ordering::Relaxed) {
+            debug!("connection closed before query {} was sent", id);
+            return Err(Error::UpstreamError);
+        }
+
+        pending.insert(id, sender);
+
+        Ok(())
+    }
+}
+
+/// A query waiting for its reply.
+struct PendingQuery<'a> {
+    connection: &'a Connection,
+    id: u16,
+}
+
+impl Drop for PendingQuery<'_> {
+    fn drop(&mut self) {
+        self.connection.pending.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Dispatches the replies read from the connection to the queries waiting for
+/// them, until the server closes the connection.
+async fn read_replies(mut reader: ReadHalf<TlsStream<TcpStream>>, pending: PendingQueries, closed: Arc<AtomicBool>) {
+    loop {
+        let length = match reader.read_u16().await {
+            Ok(length) => length as usize,
+            Err(e) => {
+                debug!("connection closed: {}", e);
+                break;
+            }
+        };
+
+        let mut reply = vec![0u8; length];
+
+        if let Err(e) = reader.read_exact(&mut reply).await {
+            error!("error reading reply: {}", e);
+            break;
+        }
+
+        if reply.len() < 2 {
+            continue;
+        }
+
+        let id = u16::from_be_bytes([reply[0], reply[1]]);
+
+        match pending.lock().unwrap().remove(&id) {
+            Some(sender) => {
+                let _ = sender.send(reply);
+            }
+            None => debug!("discarding reply to unknown query {}", id),
+        }
+    }
+
+    let mut pending = pending.lock().unwrap();
+
+    closed.store(true, Ordering::Relaxed);
+
+    // Dropping the senders wakes up the queries still waiting
+    pending.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Instant;
+
+    use tokio::io::AsyncRead;
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    use super::*;
+    use crate::settings::Proxy;
+    use crate::testing::{Pki, SERVER_NAME};
+
+    const CALLER_ID: u16 = 0xABCD;
+
+    fn query(tag: u8) -> Vec<u8> {
+        let mut query = CALLER_ID.to_be_bytes().to_vec();
+        query.extend_from_slice(&[0x01, 0x00, tag]);
+        query
+    }
+
+    async fn read_frame(stream: &mut (impl AsyncRead + Unpin)) -> Option<Vec<u8>> {
+        let length = stream.read_u16().await.ok()?;
+        let mut frame = vec![0u8; length as usize];
+        stream.read_exact(&mut frame).await.ok()?;
+        Some(frame)
+    }
+
+    async fn listen(pki: &Pki) -> (TcpListener, TlsAcceptor) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        (listener, TlsAcceptor::from(pki.server_config(&[])))
+    }
+
+    fn client(pki: &Pki, listener: &TcpListener, timeout: Duration) -> TlsClient {
+        let port = listener.local_addr().unwrap().port();
+
+        TlsClient::new(SERVER_NAME, port, &[IpAddr::V4(Ipv4Addr::LOCALHOST)], &Proxy::Direct, pki.verifier(), timeout).unwrap()
+    }
+
+    #[tokio::test]
+    async fn pipelines_queries_on_one_connection() {
+        let pki = Pki::new();
+        let (listener, acceptor) = listen(&pki).await;
+        let client = client(&pki, &listener, Duration::from_secs(5));
+        let accepted = Arc::new(AtomicUsize::new(0));
+
+        let server = {
+            let accepted = accepted.clone();
+
+            tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                accepted.fetch_add(1, Ordering::Relaxed);
+
+                // Any other connection would be a failure to reuse this one
+                let listener = tokio::spawn(async move {
+                    while listener.accept().await.is_ok() {
+                        accepted.fetch_add(1, Ordering::Relaxed);
+                    }
+                });
+
+                let mut stream = acceptor.accept(stream).await.unwrap();
+
+                // Every query arrives before the first reply is sent
+                let mut queries = vec![];
+                for _ in 0..3 {
+                    queries.push(read_frame(&mut stream).await.unwrap());
+                }
+
+                // Answered out of order, each reply split across writes
+                for query in queries.iter().rev() {
+                    let mut reply = query[..2].to_vec();
+                    reply.extend_from_slice(&[query[4], 0xFF]);
+
+                    stream.write_all(&(reply.len() as u16).to_be_bytes()).await.unwrap();
+                    stream.flush().await.unwrap();
+                    stream.write_all(&reply[..1]).await.unwrap();
+                    stream.flush().await.unwrap();
+                    stream.write_all(&reply[1..]).await.unwrap();
+                    stream.flush().await.unwrap();
+                }
+
+                listener.abort();
+
+                queries
+            })
+        };
+
+        let queries = [query(1), query(2), query(3)];
+
+        let (first, second, third) = tokio::join!(
+            client.request_message(&queries[0]),
+            client.request_message(&queries[1]),
+            client.request_message(&queries[2]));
+
+        // The caller gets its own ID back, with the reply to its own query
+        assert_eq!(first.unwrap(), vec![0xAB, 0xCD, 1, 0xFF]);
+        assert_eq!(second.unwrap(), vec![0xAB, 0xCD, 2, 0xFF]);
+        assert_eq!(third.unwrap(), vec![0xAB, 0xCD, 3, 0xFF]);
+
+        let queries = server.await.unwrap();
+        let mut ids: Vec<u16> = queries.iter().map(|query| u16::from_be_bytes([query[0], query[1]])).collect();
+
+        // The length prefix covers the message only, its body is untouched
+        assert!(queries.iter().all(|query| query.len() == 5 && query[2..4] == [0x01, 0x00]));
+
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 3, "queries in flight need distinct IDs");
+        assert!(!ids.contains(&CALLER_ID));
+
+        assert_eq!(accepted.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn fails_fast_when_the_connection_closes_mid_flight() {
+        let pki = Pki::new();
+        let (listener, acceptor) = listen(&pki).await;
+        let client = client(&pki, &listener, Duration::from_secs(10));
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(stream).await.unwrap();
+
+            read_frame(&mut stream).await.unwrap();
+            // Closed without answering
+        });
+
+        let started = Instant::now();
+
+        assert!(matches!(client.request_message(&query(1)).await, Err(Error::UpstreamError)));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn no_query_waits_on_a_closed_connection() {
+        let pki = Pki::new();
+        let (listener, acceptor) = listen(&pki).await;
+        let client = client(&pki, &listener, Duration::from_secs(10));
+
+        tokio::spawn(async move {
+            // The first connection is closed right after the handshake
+            let (stream, _) = listener.accept().await.unwrap();
+            drop(acceptor.accept(stream).await.unwrap());
+
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(stream).await.unwrap();
+
+            while let Some(query) = read_frame(&mut stream).await {
+                stream.write_all(&(query.len() as u16).to_be_bytes()).await.unwrap();
+                stream.write_all(&query).await.unwrap();
+            }
+        });
+
+        let connection = client.connection().await.unwrap();
+
+        let started = Instant::now();
+        while !connection.closed.load(Ordering::Relaxed) {
+            assert!(started.elapsed() < Duration::from_secs(2), "server close went unnoticed");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // A query racing the close is refused rather than left to time out
+        let (sender, _receiver) = oneshot::channel();
+        assert!(matches!(connection.register(7, sender), Err(Error::UpstreamError)));
+        assert!(connection.pending.lock().unwrap().is_empty());
+
+        // The next query opens a new connection
+        assert_eq!(client.request_message(&query(9)).await.unwrap(), query(9));
+    }
+
+    #[tokio::test]
+    async fn a_timed_out_query_retires_the_connection() {
+        let pki = Pki::new();
+        let (listener, acceptor) = listen(&pki).await;
+        let client = client(&pki, &listener, Duration::from_millis(200));
+
+        tokio::spawn(async move {
+            // The first connection swallows the queries without a word
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut silent = acceptor.accept(stream).await.unwrap();
+
+            tokio::spawn(async move { while read_frame(&mut silent).await.is_some() {} });
+
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(stream).await.unwrap();
+
+            while let Some(query) = read_frame(&mut stream).await {
+                stream.write_all(&(query.len() as u16).to_be_bytes()).await.unwrap();
+                stream.write_all(&query).await.unwrap();
+            }
+        });
+
+        let silent = client.connection().await.unwrap();
+
+        assert!(matches!(client.request_message(&query(1)).await, Err(Error::UpstreamTimeout)));
+        assert!(silent.closed.load(Ordering::Relaxed));
+        assert!(silent.pending.lock().unwrap().is_empty());
+
+        assert_eq!(client.request_message(&query(2)).await.unwrap(), query(2));
+    }
+
+    #[tokio::test]
+    async fn an_abandoned_query_leaves_no_pending_entry() {
+        let pki = Pki::new();
+        let (listener, acceptor) = listen(&pki).await;
+        let client = client(&pki, &listener, Duration::from_secs(10));
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(stream).await.unwrap();
+
+            while read_frame(&mut stream).await.is_some() {}
+        });
+
+        let connection = client.connection().await.unwrap();
+
+        // The caller stops waiting long before the timeout
+        let abandoned = tokio::time::timeout(Duration::from_millis(100), client.request_message(&query(1))).await;
+
+        assert!(abandoned.is_err());
+        assert!(connection.pending.lock().unwrap().is_empty());
+        assert!(!connection.closed.load(Ordering::Relaxed));
+    }
+}
+
@@ -1,4 +1,4 @@
-use async_sqlite::rusqlite::params;
+use async_sqlite::rusqlite::{self, params, Connection};
 use async_sqlite::{Pool};
 use doh_common::{AuditDnsQuery, AuditDnsQueryPage};
 use std::fmt::{Debug, Formatter};
@@ -9,12 +9,56 @@ use tracing::instrument;
 use doh_common::error::Error;
 
 use crate::provider::DnsReply;
-use crate::settings::{ApplicationSettings, TTlConfig};
+use crate::settings::ForwardRule;
+
+/// An answer as found in the cache.
+#[derive(Debug)]
+pub struct CachedAnswer {
+    pub reply: DnsReply,
+    /// Validated with DNSSEC.
+    pub authenticated: bool,
+    /// Resolved, or one along its CNAME chain, over classic DNS.
+    pub downgraded: bool,
+    /// Seconds the answer may still be kept by whoever it is given to.
+    pub ttl: u32,
+    /// Aliases followed from the cached name to the owner of the answer.
+    pub cname_chain: Vec<String>,
+}
+
+/// An answer to keep in the cache.
+#[derive(Debug)]
+pub struct AnswerEntry<'a> {
+    pub reply: &'a DnsReply,
+    /// Validated with DNSSEC.
+    pub authenticated: bool,
+    /// Resolved, or one along its CNAME chain, over classic DNS.
+    pub downgraded: bool,
+    /// Seconds the answer is kept, those of the shortest lived record along
+    /// its CNAME chain or the configured TTL.
+    pub ttl: u32,
+    /// Aliases followed from the cached name to the owner of the answer.
+    pub cname_chain: &'a [String],
+}
+
+/// A query as recorded in the audit log.
+#[derive(Debug, Default)]
+pub struct AuditEntry {
+    pub process_name: String,
+    pub host: String,
+    pub family: u32,
+    /// Upstream that answered, none when the answer came from the cache.
+    pub upstream: Option<String>,
+    /// Resolved over classic DNS.
+    pub downgraded: bool,
+    /// Validated with DNSSEC.
+    pub authenticated: bool,
+    /// Suffix of the forwarding rule that matched the host.
+    pub forward_rule: Option<String>,
+}
 
 #[derive(Clone)]
 pub struct DatabaseService {
     pool: Arc<Pool>,
-    application_settings: ApplicationSettings,
 }
 
 impl Debug for DatabaseService {
@@ -24,10 +68,9 @@ impl Debug for DatabaseService {
 }
 
 impl DatabaseService {
-    pub fn new(connection: Pool, application_settings: ApplicationSettings) -> Self {
+    pub fn new(connection: Pool) -> Self {
         Self {
             pool: Arc::new(connection),
-            application_settings,
         }
     }
 
@@ -40,19 +83,35 @@ impl DatabaseService {
             process_name VARCHAR(255),
             dns_name     VARCHAR(1024),
             dns_family   INTEGER,
+            upstream     VARCHAR(255),
+            downgraded   INTEGER DEFAULT 0,
+            forward_rule VARCHAR(1024),
+            authenticated INTEGER DEFAULT 0,
             created TIMESTAMP DEFAULT CURRENT_TIMESTAMP
         )"#,  [])?;
 
+            add_column_if_missing(connection, "audit_dns_query", "upstream", "VARCHAR(255)")?;
+            add_column_if_missing(connection, "audit_dns_query", "downgraded", "INTEGER DEFAULT 0")?;
+            add_column_if_missing(connection, "audit_dns_query", "forward_rule", "VARCHAR(1024)")?;
+            add_column_if_missing(connection, "audit_dns_query", "authenticated", "INTEGER DEFAULT 0")?;
+
             connection.execute(
                 r#"CREATE TABLE IF NOT EXISTS dns_reply (
             id           INTEGER PRIMARY KEY AUTOINCREMENT,
             dns_name     VARCHAR(1024),
             dns_family   INTEGER,
             answer       VARCHAR(1024),
+            authenticated INTEGER DEFAULT 0,
+            downgraded   INTEGER DEFAULT 0,
+            cname_chain  VARCHAR(4096),
             expired      TIMESTAMP,
             created TIMESTAMP DEFAULT CURRENT_TIMESTAMP
         )"#, [])?;
 
+            add_column_if_missing(connection, "dns_reply", "authenticated", "INTEGER DEFAULT 0")?;
+            add_column_if_missing(connection, "dns_reply", "downgraded", "INTEGER DEFAULT 0")?;
+            add_column_if_missing(connection, "dns_reply", "cname_chain", "VARCHAR(4096)")?;
+
             connection.execute(
                 r#"CREATE TABLE IF NOT EXISTS blacklist_hosts (
             id           INTEGER PRIMARY KEY AUTOINCREMENT,
@@ -60,6 +119,25 @@ impl DatabaseService {
             created TIMESTAMP DEFAULT CURRENT_TIMESTAMP
         )"#, [])?;
 
+            connection.execute(
+                r#"CREATE TABLE IF NOT EXISTS forward_rule (
+            id           INTEGER PRIMARY KEY AUTOINCREMENT,
+            suffix       VARCHAR(1024) UNIQUE,
+            upstream     VARCHAR(255),
+            created TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        )"#, [])?;
+
+            connection.execute(
+                r#"CREATE TABLE IF NOT EXISTS dnssec_key (
+            id           INTEGER PRIMARY KEY AUTOINCREMENT,
+            zone         VARCHAR(1024),
+            dnskey       BLOB,
+            expired      TIMESTAMP,
+            created TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        )"#, [])?;
+
+            connection.execute(r#"CREATE INDEX IF NOT EXISTS idx_dnssec_key_lookup ON dnssec_key (zone, expired)"#, [])?;
+
             connection.execute(r#"CREATE INDEX IF NOT EXISTS idx_dns_reply_lookup ON dns_reply (dns_name, dns_family, expired)"#, [])?;
 
             connection.execute(
@@ -69,53 +147,67 @@ impl DatabaseService {
         }).await.map_err(|e| e.into())
     }
 
-    pub async fn get_dns_answer(&self, host: &str, family: u32) -> Result<Option<DnsReply>, Error> {
+    /// Returns the cached answer, whether it was validated with DNSSEC and
+    /// whether it came over classic DNS.
+    pub async fn get_dns_answer(&self, host: &str, family: u32) -> Result<Option<CachedAnswer>, Error> {
 
         let host_clone = host.to_lowercase();
 
-        let answer_json_str = self.pool.conn(move |connection| {
+        let (answer_json_str, authenticated, downgraded, remaining, chain_json_str) = self.pool.conn(move |connection| {
 
-            let str : String = connection
-                .query_one("SELECT answer FROM dns_reply WHERE dns_name=? AND dns_family=? AND expired >= strftime('%s', 'now') LIMIT 1",
-                           params![host_clone.to_lowercase(), family as i64], |row| row.get(0))?;
+            let row : (String, bool, bool, i64, Option<String>) = connection
+                .query_one("SELECT answer, COALESCE(authenticated, 0), COALESCE(downgraded, 0), expired - strftime('%s', 'now'), cname_chain FROM dns_reply WHERE dns_name=? AND dns_family=? AND expired >= strftime('%s', 'now') LIMIT 1",
+                           params![host_clone.to_lowercase(), family as i64], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?)))?;
 
-            Ok(str)
+            Ok(row)
         }).await.map_err::<doh_common::error::Error, _>(|e| e.into())?;
 
         let reply = serde_json::from_str::<DnsReply>(answer_json_str.as_str())?;
 
-        Ok(Some(reply))
+        let cname_chain = match chain_json_str {
+            Some(chain) => serde_json::from_str::<Vec<String>>(&chain)?,
+            None => vec![],
+        };
+
+        // Counting down to the expiration the answer was saved with
+        let ttl = remaining.clamp(0, u32::MAX as i64) as u32;
+
+        Ok(Some(CachedAnswer {
+            reply,
+            authenticated,
+            downgraded,
+            ttl,
+            cname_chain,
+        }))
     }
 
-    pub async fn create_dns_answer(
-        &self,
-        host: &str,
-        family: u32,
-        reply: &DnsReply,
-    ) -> Result<bool, Error> {
+    pub async fn create_dns_answer(&self, host: &str, family: u32, answer: AnswerEntry<'_>) -> Result<bool, Error> {
         let instant = std::time::SystemTime::now();
 
-        let duration = if let TTlConfig::Custom(ttl) = self.application_settings.ttl() {
-            std::time::Duration::from_secs(*ttl)
-        } else {
-            std::time::Duration::from_secs(reply.get_expiration().unwrap_or(60) as u64)
-        };
+        let duration = std::time::Duration::from_secs(answer.ttl as u64);
 
         let expiration = instant.add(duration).duration_since(UNIX_EPOCH)?.as_secs();
 
-        let reply_json_str = serde_json::to_string(reply)?;
+        let reply_json_str = serde_json::to_string(answer.reply)?;
+
+        let chain_json_str = serde_json::to_string(answer.cname_chain)?;
+
+        let (authenticated, downgraded) = (answer.authenticated, answer.downgraded);
 
         let host_clone = host.to_lowercase();
 
         self.pool.conn(move |connection| {
             let mut statement = connection.prepare(
-                "INSERT INTO dns_reply (dns_name, dns_family, answer, expired) VALUES (?,?,?,?)",
+                "INSERT INTO dns_reply (dns_name, dns_family, answer, authenticated, downgraded, cname_chain, expired) VALUES (?,?,?,?,?,?,?)",
             )?;
 
             let rows_affected = statement.execute(params![
                 host_clone.to_lowercase(),
                 family,
                 reply_json_str,
+                authenticated,
+                downgraded,
+                chain_json_str,
                 expiration as i64
             ])?;
 
@@ -168,23 +260,104 @@ impl DatabaseService {
         }).await.map_err(|e| e.into())
     }
 
-    pub async fn create_dns_audit(
-        &self,
-        process_name: &str,
-        host: &str,
-        family: u32,
-    ) -> Result<bool, Error> {
+    pub async fn get_forward_rules(&self) -> Result<Vec<ForwardRule>, Error> {
+        self.pool.conn(|connection| {
+            let mut statement = connection.prepare("SELECT suffix, upstream FROM forward_rule ORDER BY id")?;
 
-        let host_clone = host.to_lowercase();
-        let process_name_clone = process_name.to_lowercase();
+            let rules = statement
+                .query_map([], |row| {
+                    Ok(ForwardRule::new(&row.get::<_, String>("suffix")?, &row.get::<_, String>("upstream")?))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            Ok(rules)
+        }).await.map_err(|e| e.into())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn create_forward_rule(&self, rule: &ForwardRule) -> Result<bool, Error> {
+        let suffix = rule.suffix().to_string();
+        let upstream = rule.upstream().to_string();
 
         self.pool.conn(move |connection| {
             let mut statement = connection.prepare(
-                "INSERT INTO audit_dns_query (process_name, dns_name, dns_family) VALUES (?, ?, ?)",
+                "INSERT INTO forward_rule (suffix, upstream) VALUES (?, ?) ON CONFLICT(suffix) DO UPDATE SET upstream=excluded.upstream",
+            )?;
+
+            let rows_affected = statement.execute(params![suffix, upstream])?;
+
+            Ok(rows_affected > 0)
+        }).await.map_err(|e| e.into())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn delete_forward_rule(&self, suffix: &str) -> Result<bool, Error> {
+        let suffix_clone = suffix.to_lowercase();
+
+        self.pool.conn(move |connection| {
+            let mut statement =
+                connection.prepare("DELETE FROM forward_rule WHERE suffix=?")?;
+
+            let rows_affected = statement.execute(params![suffix_clone])?;
+
+            Ok(rows_affected > 0)
+        }).await.map_err(|e| e.into())
+    }
+
+    /// Returns the DNSKEY records of `zone` validated from a trust anchor,
+    /// none once they expired.
+    pub async fn get_dnssec_keys(&self, zone: &str) -> Result<Vec<Vec<u8>>, Error> {
+        let zone = zone.to_lowercase();
+
+        self.pool.conn(move |connection| {
+            let mut statement = connection.prepare(
+                "SELECT dnskey FROM dnssec_key WHERE zone=? AND expired >= strftime('%s', 'now')")?;
+
+            let keys = statement
+                .query_map(params![zone], |row| row.get::<_, Vec<u8>>("dnskey"))?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            Ok(keys)
+        }).await.map_err(|e| e.into())
+    }
+
+    /// Replaces the validated DNSKEY records of `zone`, kept until `expiration`
+    /// in seconds since the epoch.
+    pub async fn create_dnssec_keys(&self, zone: &str, keys: Vec<Vec<u8>>, expiration: u64) -> Result<bool, Error> {
+        let zone = zone.to_lowercase();
+
+        self.pool.conn(move |connection| {
+            connection.execute("DELETE FROM dnssec_key WHERE zone=?", params![zone])?;
+
+            let mut statement = connection.prepare(
+                "INSERT INTO dnssec_key (zone, dnskey, expired) VALUES (?,?,?)",
+            )?;
+
+            for key in keys {
+                statement.execute(params![zone, key, expiration as i64])?;
+            }
+
+            Ok(true)
+        }).await.map_err(|e| e.into())
+    }
+
+    pub async fn create_dns_audit(&self, entry: AuditEntry) -> Result<bool, Error> {
+
+        self.pool.conn(move |connection| {
+            let mut statement = connection.prepare(
+                "INSERT INTO audit_dns_query (process_name, dns_name, dns_family, upstream, downgraded, authenticated, forward_rule) VALUES (?, ?, ?, ?, ?, ?, ?)",
             )?;
 
             let rows_affected =
-                statement.execute(params![process_name_clone, host_clone.to_lowercase(), family])?;
+                statement.execute(params![
+                    entry.process_name.to_lowercase(),
+                    entry.host.to_lowercase(),
+                    entry.family,
+                    entry.upstream,
+                    entry.downgraded,
+                    entry.authenticated,
+                    entry.forward_rule
+                ])?;
 
             Ok(rows_affected > 0)
         }).await.map_err(|e| e.into())
@@ -195,18 +368,22 @@ impl DatabaseService {
 
             let offset = page * 10;
             let mut statement = connection.prepare(
-                "SELECT process_name, dns_name, strftime('%s', 'created') FROM audit_dns_query ORDER BY id DESC LIMIT 10 OFFSET ?"
+                "SELECT process_name, dns_name, CAST(strftime('%s', created) AS INTEGER) AS created, COALESCE(upstream, '') AS upstream, COALESCE(downgraded, 0) AS downgraded, COALESCE(forward_rule, '') AS forward_rule, COALESCE(authenticated, 0) AS authenticated FROM audit_dns_query ORDER BY id DESC LIMIT 10 OFFSET ?"
             )?;
 
-            let mut rows = statement.query(params![offset])?;
+            let mut rows = statement.query(params![offset as i64])?;
             let mut result = Vec::with_capacity(10);
 
             while let Some(row) = rows.next()? {
                 let process_name = row.get::<_, String>("process_name")?;
                 let dns_name = row.get::<_, String>("dns_name")?;
                 let created = row.get::<_, i64>("created")?;
+                let upstream = row.get::<_, String>("upstream")?;
+                let downgraded = row.get::<_, bool>("downgraded")?;
+                let forward_rule = row.get::<_, String>("forward_rule")?;
+                let authenticated = row.get::<_, bool>("authenticated")?;
 
-                result.push(AuditDnsQuery::new(process_name, dns_name, created as u64));
+                result.push(AuditDnsQuery::new(process_name, dns_name, created as u64, upstream, downgraded, forward_rule, authenticated));
             }
 
             Ok(AuditDnsQueryPage::new(page, result))
@@ -214,3 +391,97 @@ impl DatabaseService {
         }).await.map_err(|e| e.into())
     }
 }
+
+/// Adds a column introduced after the table was first created, so databases
+/// from older versions keep working.
+fn add_column_if_missing(connection: &Connection, table: &str, column: &str, definition: &str) -> Result<(), rusqlite::Error> {
+    let mut statement = connection.prepare(&format!("PRAGMA table_info({})", table))?;
+
+    let exists = statement
+        .query_map([], |row| row.get::<_, String>("name"))?
+        .filter_map(|name| name.ok())
+        .any(|name| name == column);
+
+    if !exists {
+        connection.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), [])?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::database;
+
+    /// An answer of A records of `name`, one per TTL.
+    fn reply(name: &str, ttls: &[u32]) -> DnsReply {
+        let answers: Vec<String> = ttls
+            .iter()
+            .map(|ttl| format!(r#"{{"name":"{}.","type":1,"TTL":{},"data":"192.0.2.1"}}"#, name, ttl))
+            .collect();
+
+        serde_json::from_str(&format!(
+            r#"{{"Status":0,"TC":false,"RD":true,"RA":true,"AD":false,"CD":false,"Question":[{{"name":"{}.","type":1}}],"Answer":[{}]}}"#,
+            name,
+            answers.join(","))).unwrap()
+    }
+
+    fn entry(reply: &DnsReply, ttl: u32) -> AnswerEntry<'_> {
+        AnswerEntry { reply, authenticated: false, downgraded: false, ttl, cname_chain: &[] }
+    }
+
+    #[tokio::test]
+    async fn keeps_whether_an_answer_came_over_classic_dns() {
+        let database = database().await;
+
+        let plain = reply("plain.example.test", &[300]);
+        let private = reply("www.example.test", &[300]);
+
+        database.create_dns_answer("plain.example.test", 1, AnswerEntry { downgraded: true, ..entry(&plain, 300) }).await.unwrap();
+        database.create_dns_answer("www.example.test", 1, AnswerEntry { authenticated: true, ..entry(&private, 300) }).await.unwrap();
+
+        let plain = database.get_dns_answer("plain.example.test", 1).await.unwrap().unwrap();
+        assert!(plain.downgraded && !plain.authenticated);
+
+        let private = database.get_dns_answer("WWW.example.test", 1).await.unwrap().unwrap();
+        assert!(!private.downgraded && private.authenticated);
+    }
+
+    #[tokio::test]
+    async fn audit_entries_carry_the_time_they_were_made() {
+        let database = database().await;
+
+        let before = std::time::SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+        database.create_dns_audit(AuditEntry {
+            process_name: "curl".to_string(),
+            host: "www.example.test".to_string(),
+            family: 1,
+            ..AuditEntry::default()
+        }).await.unwrap();
+
+        let page = serde_json::to_value(database.get_dns_audit(0).await.unwrap()).unwrap();
+        let created = page["results"][0]["create"].as_u64().unwrap();
+
+        // CURRENT_TIMESTAMP has a resolution of a second
+        assert!((before..=before + 2).contains(&created), "{} is not around {}", created, before);
+    }
+
+    #[tokio::test]
+    async fn answers_expire_after_the_ttl_they_are_saved_with() {
+        let database = database().await;
+
+        // Shorter than any of the records, as the TTL of a CNAME along the way may be
+        let answer = reply("www.example.test", &[300, 120]);
+        let chain = ["alias.example.test".to_string()];
+
+        database.create_dns_answer("alias.example.test", 1, AnswerEntry { cname_chain: &chain, ..entry(&answer, 60) }).await.unwrap();
+
+        let cached = database.get_dns_answer("alias.example.test", 1).await.unwrap().unwrap();
+
+        // Give or take the second that may have gone by
+        assert!((59..=60).contains(&cached.ttl), "{}", cached.ttl);
+        assert_eq!(cached.cname_chain, chain);
+    }
+}
@@ -1,10 +1,15 @@
 
-use tracing::{info, instrument};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tracing::{error, info, instrument};
 use zbus::interface;
+use zbus::object_server::SignalEmitter;
 
-use doh_common::AuditDnsQueryPage;
+use doh_common::error::ResolveError;
+use doh_common::{AuditDnsQueryPage, ForwardRule, RecordSet, ResolverStats, ServiceAddress, UpstreamHealth};
 
-use crate::provider::Resolver;
+use crate::provider::{DnsRecordType, Resolver, ResolverEvent};
+
+pub const OBJECT_PATH: &str = "/com/glaciaos/NameResolver";
 
 #[derive(Debug)]
 pub struct DoHBusService {
@@ -21,20 +26,100 @@ impl DoHBusService {
 impl DoHBusService {
     #[instrument(skip(self))]
     async fn resolve_name(
-        &mut self,
+        &self,
         process_id: u32,
         name: &str,
         family: u32,
-    ) -> zbus::fdo::Result<libnss::host::Host> {
+    ) -> Result<libnss::host::Host, ResolveError> {
         info!("received query: {} - {} {}", process_id, name, family);
 
-        let result = self
-            .resolver
+        if family != DnsRecordType::A.as_uint() && family != DnsRecordType::AAAA.as_uint() {
+            return Err(doh_common::error::Error::UnsupportedFamily(family).into());
+        }
+
+        self.resolver
             .resolve(process_id, name, family)
             .await
-            .map_err(|e| e.into());
+            .map_err(|e| e.into())
+    }
+
+    /// The IPv4 and IPv6 addresses of a name, looked up in parallel.
+    #[instrument(skip(self))]
+    async fn resolve_name_all(
+        &self,
+        process_id: u32,
+        name: &str,
+    ) -> Result<Vec<libnss::host::Host>, ResolveError> {
+        info!("received query: {} - {} all", process_id, name);
+
+        self.resolver
+            .resolve_all(process_id, name)
+            .await
+            .map_err(|e| e.into())
+    }
+
+    /// The name an address points back to, from its PTR records.
+    #[instrument(skip(self))]
+    async fn resolve_address(
+        &self,
+        process_id: u32,
+        address: &str,
+    ) -> Result<libnss::host::Host, ResolveError> {
+        info!("received reverse query: {} - {}", process_id, address);
+
+        let address = address
+            .parse()
+            .map_err(|_| ResolveError::Unavail(format!("invalid address {}", address)))?;
+
+        self.resolver
+            .resolve_address(process_id, address)
+            .await
+            .map_err(|e| e.into())
+    }
+
+    /// The records of `record_type` (MX, TXT, SRV, CAA, NS, SOA, SVCB or HTTPS)
+    /// owned by a name.
+    #[instrument(skip(self))]
+    async fn query_records(
+        &self,
+        process_id: u32,
+        name: &str,
+        record_type: u32,
+    ) -> Result<RecordSet, ResolveError> {
+        let record_type = match DnsRecordType::try_from(record_type as i32) {
+            Ok(record_type @ (DnsRecordType::MX
+                | DnsRecordType::TXT
+                | DnsRecordType::SRV
+                | DnsRecordType::CAA
+                | DnsRecordType::NS
+                | DnsRecordType::SOA
+                | DnsRecordType::SVCB
+                | DnsRecordType::HTTPS)) => record_type,
+            _ => return Err(ResolveError::Unavail(format!("unsupported record type {}", record_type))),
+        };
+
+        info!("received query: {} - {} {}", process_id, name, record_type);
+
+        self.resolver
+            .query_records(process_id, name, record_type)
+            .await
+            .map_err(|e| e.into())
+    }
 
-        result
+    /// Where to connect to `_service._proto.name`, from its SRV records, as
+    /// socket addresses in the order they should be tried in.
+    #[instrument(skip(self))]
+    async fn resolve_service(
+        &self,
+        process_id: u32,
+        name: &str,
+    ) -> Result<Vec<ServiceAddress>, ResolveError> {
+        info!("received service query: {} - {}", process_id, name);
+
+        self.resolver
+            .resolve_service(process_id, name)
+            .await
+            .map_err(|e| e.into())
     }
 
     async fn block_host(&mut self, name: &str) -> zbus::fdo::Result<bool> {
@@ -56,4 +141,66 @@ impl DoHBusService {
             .await
             .map_err(|e: doh_common::error::Error | e.into())
     }
+
+    async fn get_upstream_health(&self) -> Vec<UpstreamHealth> {
+        self.resolver.get_upstream_health()
+    }
+
+    async fn get_stats(&self) -> ResolverStats {
+        self.resolver.get_stats()
+    }
+
+    /// Sends the names under `suffix` to the configured upstream named `upstream`.
+    async fn add_forward_rule(&mut self, suffix: &str, upstream: &str) -> zbus::fdo::Result<bool> {
+        self.resolver
+            .add_forward_rule(suffix, upstream)
+            .await
+            .map_err(|e: doh_common::error::Error | e.into())
+    }
+
+    async fn remove_forward_rule(&mut self, suffix: &str) -> zbus::fdo::Result<bool> {
+        self.resolver
+            .remove_forward_rule(suffix)
+            .await
+            .map_err(|e: doh_common::error::Error | e.into())
+    }
+
+    async fn get_forward_rules(&self) -> Vec<ForwardRule> {
+        self.resolver.get_forward_rules()
+    }
+
+    /// Emitted when no upstream answered and the name was resolved over classic DNS.
+    #[zbus(signal)]
+    async fn privacy_downgraded(emitter: &SignalEmitter<'_>, process_id: u32, name: &str, nameserver: &str) -> zbus::Result<()>;
+
+    /// Emitted when the certificate of an upstream matched none of its pinned
+    /// keys and the connection was refused.
+    #[zbus(signal)]
+    async fn pin_mismatch(emitter: &SignalEmitter<'_>, upstream: &str) -> zbus::Result<()>;
+}
+
+/// Publishes the events of the resolver as signals of the service.
+pub async fn forward_events(connection: zbus::Connection, mut events: UnboundedReceiver<ResolverEvent>) {
+    let emitter = match SignalEmitter::new(&connection, OBJECT_PATH) {
+        Ok(emitter) => emitter,
+        Err(e) => {
+            error!("unable to emit signals: {}", e);
+            return;
+        }
+    };
+
+    while let Some(event) = events.recv().await {
+        let result = match event {
+            ResolverEvent::PrivacyDowngraded { process_id, name, nameserver } => {
+                DoHBusService::privacy_downgraded(&emitter, process_id, &name, &nameserver).await
+            }
+            ResolverEvent::PinMismatch { upstream } => {
+                DoHBusService::pin_mismatch(&emitter, &upstream).await
+            }
+        };
+
+        if let Err(e) = result {
+            error!("unable to emit signal: {}", e);
+        }
+    }
 }
@@ -0,0 +1,8 @@
+pub mod provider;
+pub mod client;
+pub mod dbus;
+pub mod database;
+pub mod sysinfo;
+pub mod settings;
+#[cfg(test)]
+mod testing;
@@ -1,19 +1,13 @@
 use std::{error::Error, future::pending};
 use log::{info};
 use zbus::{connection};
-use crate::database::DatabaseService;
-use crate::provider::Resolver;
-use crate::settings::ApplicationSettings;
+use doh_daemon::database::DatabaseService;
+use doh_daemon::dbus;
+use doh_daemon::provider::Resolver;
+use doh_daemon::settings::ApplicationSettings;
 
 use async_sqlite::{JournalMode, PoolBuilder};
 
-mod provider;
-mod client;
-mod dbus;
-mod database;
-mod sysinfo;
-mod settings;
-
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -31,22 +25,36 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .open()
         .await?;
 
-    let database_service = DatabaseService::new(pool, settings.clone());
+    let database_service = DatabaseService::new(pool);
 
     info!("Creating tables");
 
     database_service.create_tables().await.expect("Unable to create base tables");
 
-    let resolver = Resolver::new(database_service, settings);
+    let warm_up = settings.warm_up();
+
+    let (events, events_receiver) = tokio::sync::mpsc::unbounded_channel();
+
+    let resolver = Resolver::new(database_service, settings, events)?;
+
+    resolver.restore_forward_rules().await.expect("Unable to load forwarding rules");
+
+    if warm_up {
+        info!("Warming up upstream connections");
+
+        resolver.warm_up().await;
+    }
 
     let service = dbus::DoHBusService::new(resolver);
 
-    let _conn = connection::Builder::session()?
+    let conn = connection::Builder::session()?
         .name("com.glaciaos.NameResolver")?
-        .serve_at("/com/glaciaos/NameResolver", service)?
+        .serve_at(dbus::OBJECT_PATH, service)?
         .build()
         .await?;
 
+    tokio::spawn(dbus::forward_events(conn.clone(), events_receiver));
+
     // Do other things or go to wait forever
     pending::<()>().await;
 
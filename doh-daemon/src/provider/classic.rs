@@ -0,0 +1,81 @@
+use std::net::{IpAddr, SocketAddr};
+
+use tracing::{debug, error, instrument, warn};
+use doh_common::error::Error;
+use crate::client::plain::{read_resolv_conf, PlainClient};
+use crate::provider::ecs::Subnet;
+use crate::provider::message::Message;
+use crate::provider::{DnsRecordType, DnsReply};
+use crate::settings::{FallbackSettings, PlainSettings};
+
+/// Classic DNS over UDP and TCP, either to the name servers of resolv.conf as
+/// the last resort when no upstream answers, or to fixed servers for an
+/// upstream of kind `dns`. Whoever is on the path sees the queries.
+#[derive(Debug)]
+pub struct ClassicDns {
+    client: PlainClient,
+    nameservers: Nameservers,
+}
+
+#[derive(Debug)]
+enum Nameservers {
+    ResolvConf(String),
+    Fixed(Vec<SocketAddr>),
+}
+
+impl ClassicDns {
+    pub fn fallback(settings: &FallbackSettings) -> Self {
+        Self {
+            client: PlainClient::new(settings.udp_timeout(), settings.tcp_timeout()),
+            nameservers: Nameservers::ResolvConf(settings.resolv_conf().to_string()),
+        }
+    }
+
+    pub fn servers(settings: &PlainSettings, addresses: &[IpAddr]) -> Self {
+        Self {
+            client: PlainClient::new(settings.udp_timeout(), settings.tcp_timeout()),
+            nameservers: Nameservers::Fixed(
+                addresses.iter().map(|ip| SocketAddr::new(*ip, settings.port())).collect()),
+        }
+    }
+
+    /// Returns the reply and the address of the name server that sent it.
+    #[instrument(skip(self))]
+    pub async fn resolve(&self, domain: &str, record_type: DnsRecordType, subnet: Option<&Subnet>) -> Result<(DnsReply, String), Error> {
+        let query = Message::query(domain, &record_type).with_client_subnet(subnet);
+
+        let (message, nameserver) = self.exchange(&query).await?;
+
+        Ok((message.into_reply()?, nameserver))
+    }
+
+    /// Sends the query to the name servers in turn, returning the reply and
+    /// the address of the name server that sent it.
+    pub async fn exchange(&self, query: &Message) -> Result<(Message, String), Error> {
+        let domain = query.questions.first().map(|question| question.name.as_str()).unwrap_or_default();
+
+        let nameservers = match &self.nameservers {
+            Nameservers::ResolvConf(path) => {
+                let nameservers = read_resolv_conf(path)?;
+
+                if nameservers.is_empty() {
+                    error!("no name server in {}", path);
+                    return Err(Error::UpstreamError);
+                }
+
+                warn!("resolving {} over classic DNS, the query is not encrypted", domain);
+
+                nameservers
+            }
+            Nameservers::Fixed(nameservers) => {
+                debug!("resolving {} over classic DNS", domain);
+
+                nameservers.clone()
+            }
+        };
+
+        let (body, nameserver) = self.client.request_message(&nameservers, &query.encode()?).await?;
+
+        Ok((Message::decode(&body)?, nameserver.ip().to_string()))
+    }
+}
@@ -0,0 +1,123 @@
+use ring::digest;
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+
+// https://www.iana.org/assignments/dns-sec-alg-numbers
+const ALGORITHM_RSASHA256: u8 = 8;
+const ALGORITHM_RSASHA512: u8 = 10;
+const ALGORITHM_ECDSAP256SHA256: u8 = 13;
+const ALGORITHM_ECDSAP384SHA384: u8 = 14;
+const ALGORITHM_ED25519: u8 = 15;
+
+const DIGEST_SHA1: u8 = 1;
+const DIGEST_SHA256: u8 = 2;
+const DIGEST_SHA384: u8 = 4;
+
+const NSEC3_HASH_SHA1: u8 = 1;
+
+/// Signature algorithms the validator implements, those RFC 8624 requires
+/// validators to support, except for the SHA-1 ones it deprecates. Zones
+/// signed with any other algorithm are treated as unsigned.
+pub fn is_supported_algorithm(algorithm: u8) -> bool {
+    matches!(algorithm,
+        ALGORITHM_RSASHA256 | ALGORITHM_RSASHA512 | ALGORITHM_ECDSAP256SHA256 | ALGORITHM_ECDSAP384SHA384 | ALGORITHM_ED25519)
+}
+
+pub fn is_supported_digest(digest_type: u8) -> bool {
+    matches!(digest_type, DIGEST_SHA1 | DIGEST_SHA256 | DIGEST_SHA384)
+}
+
+/// Checks the signature of `data` with a public key in the DNSKEY format of
+/// its algorithm.
+pub fn verify(algorithm: u8, public_key: &[u8], data: &[u8], signature: &[u8]) -> bool {
+    match algorithm {
+        ALGORITHM_RSASHA256 => verify_rsa(&signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY, public_key, data, signature),
+        ALGORITHM_RSASHA512 => verify_rsa(&signature::RSA_PKCS1_1024_8192_SHA512_FOR_LEGACY_USE_ONLY, public_key, data, signature),
+        // The point is sent without the uncompressed form prefix (RFC 6605 section 4)
+        ALGORITHM_ECDSAP256SHA256 => UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, [&[0x04], public_key].concat())
+            .verify(data, signature)
+            .is_ok(),
+        ALGORITHM_ECDSAP384SHA384 => UnparsedPublicKey::new(&signature::ECDSA_P384_SHA384_FIXED, [&[0x04], public_key].concat())
+            .verify(data, signature)
+            .is_ok(),
+        ALGORITHM_ED25519 => UnparsedPublicKey::new(&signature::ED25519, public_key)
+            .verify(data, signature)
+            .is_ok(),
+        _ => false,
+    }
+}
+
+/// RSA public keys are the exponent length, the exponent and the modulus
+/// (RFC 3110 section 2).
+fn verify_rsa(parameters: &signature::RsaParameters, public_key: &[u8], data: &[u8], signature: &[u8]) -> bool {
+    let (exponent_length, start) = match public_key.first() {
+        Some(0) if public_key.len() >= 3 => (u16::from_be_bytes([public_key[1], public_key[2]]) as usize, 3),
+        Some(length) => (*length as usize, 1),
+        None => return false,
+    };
+
+    let (Some(e), Some(n)) = (public_key.get(start..start + exponent_length), public_key.get(start + exponent_length..)) else {
+        return false;
+    };
+
+    RsaPublicKeyComponents { n, e }.verify(parameters, data, signature).is_ok()
+}
+
+/// Digest of a DS record (RFC 4034 section 5.1.4), over the owner name in
+/// canonical wire form and the data of the DNSKEY record.
+pub fn ds_digest(digest_type: u8, owner: &[u8], dnskey: &[u8]) -> Option<Vec<u8>> {
+    let algorithm = match digest_type {
+        DIGEST_SHA1 => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+        DIGEST_SHA256 => &digest::SHA256,
+        DIGEST_SHA384 => &digest::SHA384,
+        _ => return None,
+    };
+
+    let mut context = digest::Context::new(algorithm);
+    context.update(owner);
+    context.update(dnskey);
+
+    Some(context.finish().as_ref().to_vec())
+}
+
+/// Hashed owner name of NSEC3 (RFC 5155 section 5), from the name in
+/// canonical wire form.
+pub fn nsec3_hash(hash_algorithm: u8, name: &[u8], salt: &[u8], iterations: u16) -> Option<Vec<u8>> {
+    if hash_algorithm != NSEC3_HASH_SHA1 {
+        return None;
+    }
+
+    let hash = |data: &[u8]| {
+        let mut context = digest::Context::new(&digest::SHA1_FOR_LEGACY_USE_ONLY);
+        context.update(data);
+        context.update(salt);
+        context.finish().as_ref().to_vec()
+    };
+
+    let mut hashed = hash(name);
+
+    for _ in 0..iterations {
+        hashed = hash(&hashed);
+    }
+
+    Some(hashed)
+}
+
+/// Decodes the Base 32 Encoding with Extended Hex Alphabet (RFC 4648
+/// section 7) of NSEC3 owner names, without padding.
+pub fn decode_base32hex(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len() * 5 / 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for c in text.chars() {
+        buffer = buffer << 5 | c.to_digit(32)?;
+        bits += 5;
+
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(bytes)
+}
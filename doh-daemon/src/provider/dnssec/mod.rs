@@ -0,0 +1,902 @@
+use std::cmp::Ordering;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tracing::{debug, error, instrument, warn};
+
+use doh_common::error::Error;
+
+use crate::database::DatabaseService;
+use crate::provider::message::{Message, Record};
+use crate::provider::upstream::Upstream;
+use crate::provider::DnsReply;
+use crate::settings::DnssecSettings;
+
+use self::records::{canonical_name, is_subdomain, normalize, parent, Dnskey, Ds, Nsec, Nsec3, Rrsig};
+use self::records::{TYPE_DNSKEY, TYPE_DS, TYPE_NSEC, TYPE_NSEC3, TYPE_RRSIG};
+
+mod crypto;
+mod records;
+
+const CLASS_IN: u16 = 1;
+const TYPE_OPT: u16 = 41;
+const TYPE_NS: u16 = 2;
+const TYPE_SOA: u16 = 6;
+
+// https://www.rfc-editor.org/rfc/rfc1035#section-4.1.1
+const RCODE_NOERROR: u8 = 0;
+const RCODE_NXDOMAIN: u8 = 3;
+
+/// Beyond this many extra iterations NSEC3 records are treated as unsigned
+/// (RFC 9276 section 3.2), hashing them would cost more than it proves.
+const MAX_NSEC3_ITERATIONS: u16 = 150;
+
+/// Outcome of validating an answer (RFC 4035 section 4.3).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Validation {
+    /// Every record is signed along a chain of keys from a trust anchor.
+    Secure,
+    /// Some record is in a zone proven to be unsigned, or the answer carries
+    /// no DNSSEC records to check.
+    Insecure,
+    /// Some record should be signed and is not, or its signature is wrong.
+    Bogus,
+}
+
+/// What is known of the keys of a zone.
+enum ZoneKeys {
+    Secure(Vec<Dnskey>),
+    Insecure,
+    Bogus,
+}
+
+/// What is known of the DS records of a zone.
+enum Delegation {
+    Signed(Vec<Ds>),
+    Unsigned,
+    Bogus,
+}
+
+/// What a denial of existence for the DS records of a name proves.
+enum Denial {
+    /// The name is a delegation to an unsigned zone.
+    InsecureDelegation,
+    /// The name is not a delegation, its records belong to the same zone.
+    NoDelegation,
+    /// The denial is in an unsigned zone itself.
+    Insecure,
+    Bogus,
+}
+
+/// Validates answers with DNSSEC from the configured trust anchors down,
+/// fetching the DNSKEY and DS records along the chain from the upstream that
+/// answered. Validated keys are kept in the database until they expire.
+pub struct Validator {
+    database: DatabaseService,
+    trust_anchors: Vec<Ds>,
+}
+
+impl std::fmt::Debug for Validator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Validator({} trust anchors)", self.trust_anchors.len())
+    }
+}
+
+impl Validator {
+    pub fn new(database: DatabaseService, settings: &DnssecSettings) -> Self {
+        let trust_anchors = settings
+            .trust_anchors()
+            .iter()
+            .map(|anchor| Ds {
+                key_tag: anchor.key_tag(),
+                algorithm: anchor.algorithm(),
+                digest_type: anchor.digest_type(),
+                digest: anchor.digest().to_vec(),
+            })
+            .collect();
+
+        Self { database, trust_anchors }
+    }
+
+    /// Validates every record set in the answer section of a reply. Replies
+    /// of the JSON APIs and negative answers carry nothing to check and are
+    /// insecure.
+    #[instrument(skip_all, fields(upstream = upstream.name()))]
+    pub async fn validate(&self, upstream: &Upstream, reply: &DnsReply) -> Validation {
+        let Some(message) = reply.message.as_ref() else {
+            debug!("reply carries no DNSSEC records");
+            return Validation::Insecure;
+        };
+
+        let sets = record_sets(&message.answers);
+
+        if sets.is_empty() {
+            return Validation::Insecure;
+        }
+
+        let mut validation = Validation::Secure;
+
+        for (owner, rtype, records) in sets {
+            let signatures = signatures(&message.answers, &owner, rtype);
+
+            let result = if signatures.is_empty() {
+                self.prove_unsigned(upstream, &owner).await
+            } else {
+                self.verify_set(upstream, &owner, &records, signatures).await
+            };
+
+            match result {
+                Validation::Bogus => {
+                    warn!("records of type {} at {} are bogus", rtype, owner);
+                    return Validation::Bogus;
+                }
+                Validation::Insecure => validation = Validation::Insecure,
+                Validation::Secure => {}
+            }
+        }
+
+        validation
+    }
+
+    /// Checks that one of the signatures over a record set was made by a
+    /// validated key of its signer.
+    async fn verify_set(&self, upstream: &Upstream, owner: &str, records: &[&Record], signatures: Vec<Rrsig>) -> Validation {
+        let now = now();
+
+        for signature in signatures.iter().filter(|signature| is_subdomain(owner, &signature.signer)) {
+            let keys = match Box::pin(self.zone_keys(upstream, &signature.signer)).await {
+                ZoneKeys::Secure(keys) => keys,
+                ZoneKeys::Insecure => return Validation::Insecure,
+                ZoneKeys::Bogus => return Validation::Bogus,
+            };
+
+            if verify_signature(signature, owner, records, &keys, now) {
+                return Validation::Secure;
+            }
+        }
+
+        Validation::Bogus
+    }
+
+    /// The keys of `zone`, validated through the DS records of its parent,
+    /// or the trust anchors for the root.
+    async fn zone_keys(&self, upstream: &Upstream, zone: &str) -> ZoneKeys {
+        match self.database.get_dnssec_keys(zone).await {
+            Ok(keys) if !keys.is_empty() => {
+                return ZoneKeys::Secure(keys.iter().filter_map(|key| Dnskey::parse(key)).collect());
+            }
+            Ok(_) => {}
+            Err(e) => error!("unable to read the keys of {}: {}", zone, e),
+        }
+
+        let digests = if zone == "." {
+            self.trust_anchors.clone()
+        } else {
+            match self.delegation(upstream, zone).await {
+                Delegation::Signed(digests) => digests,
+                Delegation::Unsigned => return ZoneKeys::Insecure,
+                Delegation::Bogus => return ZoneKeys::Bogus,
+            }
+        };
+
+        self.validate_keys(upstream, zone, &digests).await
+    }
+
+    /// The DS records of `zone`, validated with the keys of its parent.
+    async fn delegation(&self, upstream: &Upstream, zone: &str) -> Delegation {
+        let Ok(message) = fetch(upstream, zone, TYPE_DS).await else {
+            return Delegation::Bogus;
+        };
+
+        let records = records_of(&message.answers, zone, TYPE_DS);
+
+        if records.is_empty() {
+            return match self.deny_ds(upstream, zone, &message).await {
+                Denial::InsecureDelegation | Denial::Insecure => Delegation::Unsigned,
+                // The zone was named as a signer, it must be a delegation
+                Denial::NoDelegation | Denial::Bogus => Delegation::Bogus,
+            };
+        }
+
+        let mut signatures = signatures(&message.answers, zone, TYPE_DS);
+        signatures.retain(|signature| signature.signer != zone);
+
+        match self.verify_set(upstream, zone, &records, signatures).await {
+            Validation::Secure => Delegation::Signed(records.iter().filter_map(|record| Ds::parse(&record.rdata)).collect()),
+            Validation::Insecure => Delegation::Unsigned,
+            Validation::Bogus => Delegation::Bogus,
+        }
+    }
+
+    /// Fetches the keys of `zone` and keeps them if one matching a DS record
+    /// signed them. With no DS record of a supported algorithm and digest the
+    /// zone counts as unsigned (RFC 4035 section 5.2).
+    async fn validate_keys(&self, upstream: &Upstream, zone: &str, digests: &[Ds]) -> ZoneKeys {
+        let digests: Vec<&Ds> = digests
+            .iter()
+            .filter(|ds| crypto::is_supported_algorithm(ds.algorithm) && crypto::is_supported_digest(ds.digest_type))
+            .collect();
+
+        if digests.is_empty() {
+            debug!("no supported DS record for {}", zone);
+            return ZoneKeys::Insecure;
+        }
+
+        let Ok(message) = fetch(upstream, zone, TYPE_DNSKEY).await else {
+            return ZoneKeys::Bogus;
+        };
+
+        let records = records_of(&message.answers, zone, TYPE_DNSKEY);
+
+        let keys: Vec<Dnskey> = records
+            .iter()
+            .filter_map(|record| Dnskey::parse(&record.rdata))
+            .filter(|key| key.is_zone_key())
+            .collect();
+
+        let owner = canonical_name(zone);
+
+        let entry_points: Vec<Dnskey> = keys
+            .iter()
+            .filter(|key| digests.iter().any(|ds| {
+                ds.key_tag == key.key_tag()
+                    && ds.algorithm == key.algorithm
+                    && crypto::ds_digest(ds.digest_type, &owner, key.rdata()).as_deref() == Some(ds.digest.as_slice())
+            }))
+            .filter_map(|key| Dnskey::parse(key.rdata()))
+            .collect();
+
+        let now = now();
+
+        let signature = signatures(&message.answers, zone, TYPE_DNSKEY)
+            .into_iter()
+            .filter(|signature| signature.signer == zone)
+            .find(|signature| verify_signature(signature, zone, &records, &entry_points, now));
+
+        let Some(signature) = signature else {
+            warn!("no valid signature over the keys of {}", zone);
+            return ZoneKeys::Bogus;
+        };
+
+        let ttl = records.iter().map(|record| record.ttl).min().unwrap_or(0).min(signature.original_ttl);
+        let expiration = (now as u64 + ttl as u64).min(signature.expiration as u64);
+
+        let rdatas = keys.iter().map(|key| key.rdata().to_vec()).collect();
+
+        if let Err(e) = self.database.create_dnssec_keys(zone, rdatas, expiration).await {
+            error!("unable to save the keys of {}: {}", zone, e);
+        }
+
+        debug!("validated {} keys of {}", keys.len(), zone);
+
+        ZoneKeys::Secure(keys)
+    }
+
+    /// An unsigned answer is insecure only if some zone between the root and
+    /// its owner is proven to be unsigned. Walks down the names, asking for
+    /// their DS records, until an unsigned delegation shows up.
+    async fn prove_unsigned(&self, upstream: &Upstream, owner: &str) -> Validation {
+        let mut names = vec![];
+        let mut name = Some(owner.to_string());
+
+        while let Some(current) = name.filter(|current| current != ".") {
+            name = parent(&current).map(|parent| normalize(&parent));
+            names.push(current);
+        }
+
+        for name in names.iter().rev() {
+            let Ok(message) = fetch(upstream, name, TYPE_DS).await else {
+                return Validation::Bogus;
+            };
+
+            let records = records_of(&message.answers, name, TYPE_DS);
+
+            if !records.is_empty() {
+                let mut signatures = signatures(&message.answers, name, TYPE_DS);
+                signatures.retain(|signature| signature.signer != *name);
+
+                match self.verify_set(upstream, name, &records, signatures).await {
+                    Validation::Secure => continue,
+                    result => return result,
+                }
+            }
+
+            match self.deny_ds(upstream, name, &message).await {
+                Denial::InsecureDelegation | Denial::Insecure => return Validation::Insecure,
+                Denial::NoDelegation => continue,
+                Denial::Bogus => return Validation::Bogus,
+            }
+        }
+
+        debug!("{} is in a signed zone but its records are not signed", owner);
+
+        Validation::Bogus
+    }
+
+    /// Checks the NSEC or NSEC3 records proving that `name` has no DS records.
+    async fn deny_ds(&self, upstream: &Upstream, name: &str, message: &Message) -> Denial {
+        if message.rcode() != RCODE_NOERROR && message.rcode() != RCODE_NXDOMAIN {
+            return Denial::Bogus;
+        }
+
+        for record in message.authority.iter().filter(|record| record.rtype == TYPE_NSEC || record.rtype == TYPE_NSEC3) {
+            let owner = normalize(&record.name);
+
+            let denial = match record.rtype {
+                TYPE_NSEC => nsec_denial(&owner, &record.rdata, name),
+                _ => nsec3_denial(&owner, &record.rdata, name),
+            };
+
+            let Some(denial) = denial else {
+                continue;
+            };
+
+            let mut signatures = signatures(&message.authority, &owner, record.rtype);
+            signatures.retain(|signature| signature.signer != name && is_subdomain(name, &signature.signer));
+
+            match self.verify_set(upstream, &owner, &[record], signatures).await {
+                Validation::Secure => return denial,
+                Validation::Insecure => return Denial::Insecure,
+                Validation::Bogus => continue,
+            }
+        }
+
+        debug!("no proof that {} has no DS records", name);
+
+        Denial::Bogus
+    }
+}
+
+/// What an NSEC record at `owner` proves about the DS records of `name`,
+/// none when it says nothing about them.
+fn nsec_denial(owner: &str, rdata: &[u8], name: &str) -> Option<Denial> {
+    let nsec = Nsec::parse(rdata)?;
+
+    if owner == name {
+        return types_denial(nsec.types.contains(TYPE_DS), nsec.types.contains(TYPE_NS), nsec.types.contains(TYPE_SOA));
+    }
+
+    // An empty non-terminal, there is nothing at the name but names below it
+    let next = normalize(&nsec.next);
+
+    if canonical_cmp(owner, name) == Ordering::Less
+        && canonical_cmp(name, &next) == Ordering::Less
+        && is_subdomain(&next, name) {
+        return Some(Denial::NoDelegation);
+    }
+
+    None
+}
+
+/// Same as `nsec_denial` for an NSEC3 record, whose owner is the hash of a
+/// name followed by the zone.
+fn nsec3_denial(owner: &str, rdata: &[u8], name: &str) -> Option<Denial> {
+    let nsec3 = Nsec3::parse(rdata)?;
+    let zone = normalize(&parent(owner)?);
+
+    if !is_subdomain(name, &zone) {
+        return None;
+    }
+
+    if nsec3.iterations > MAX_NSEC3_ITERATIONS {
+        warn!("NSEC3 of {} uses {} iterations, treating it as unsigned", zone, nsec3.iterations);
+        return Some(Denial::Insecure);
+    }
+
+    let owner_hash = crypto::decode_base32hex(owner.split('.').next()?)?;
+    let hash = crypto::nsec3_hash(nsec3.hash_algorithm, &canonical_name(name), &nsec3.salt, nsec3.iterations)?;
+
+    if hash == owner_hash {
+        return types_denial(nsec3.types.contains(TYPE_DS), nsec3.types.contains(TYPE_NS), nsec3.types.contains(TYPE_SOA));
+    }
+
+    // The names above were found on the way down, so the name itself is the
+    // next closer name and a covering opt-out record proves an unsigned delegation
+    let covered = if owner_hash < nsec3.next_hashed {
+        owner_hash < hash && hash < nsec3.next_hashed
+    } else {
+        owner_hash < hash || hash < nsec3.next_hashed
+    };
+
+    (covered && nsec3.is_opt_out()).then_some(Denial::InsecureDelegation)
+}
+
+fn types_denial(has_ds: bool, has_ns: bool, has_soa: bool) -> Option<Denial> {
+    if has_ds {
+        return None;
+    }
+
+    // NS without SOA is the parent side of a zone cut
+    if has_ns && !has_soa {
+        Some(Denial::InsecureDelegation)
+    } else {
+        Some(Denial::NoDelegation)
+    }
+}
+
+fn verify_signature(signature: &Rrsig, owner: &str, records: &[&Record], keys: &[Dnskey], now: u32) -> bool {
+    if !signature.is_current(now) {
+        debug!("signature by {} is outside of its validity period", signature.signer);
+        return false;
+    }
+
+    let Some(data) = signature.signed_data(owner, records) else {
+        return false;
+    };
+
+    keys.iter()
+        .filter(|key| key.algorithm == signature.algorithm && key.key_tag() == signature.key_tag)
+        .any(|key| crypto::verify(signature.algorithm, &key.public_key, &data, &signature.signature))
+}
+
+/// Asks the upstream for records along with their signatures.
+async fn fetch(upstream: &Upstream, name: &str, rtype: u16) -> Result<Message, Error> {
+    debug!("fetching records of type {} at {}", rtype, name);
+
+    let query = Message::question(name, rtype).with_dnssec_ok(true);
+
+    upstream.exchange(query).await.inspect_err(|e| {
+        warn!("unable to fetch records of type {} at {}: {}", rtype, name, e);
+    })
+}
+
+/// Groups the records of a section by owner and type, leaving out the
+/// signatures.
+fn record_sets(records: &[Record]) -> Vec<(String, u16, Vec<&Record>)> {
+    let mut sets: Vec<(String, u16, Vec<&Record>)> = vec![];
+
+    for record in records.iter().filter(|record| record.class == CLASS_IN && record.rtype != TYPE_RRSIG && record.rtype != TYPE_OPT) {
+        let owner = normalize(&record.name);
+
+        match sets.iter_mut().find(|(name, rtype, _)| *name == owner && *rtype == record.rtype) {
+            Some((_, _, set)) => set.push(record),
+            None => sets.push((owner, record.rtype, vec![record])),
+        }
+    }
+
+    sets
+}
+
+fn records_of<'a>(records: &'a [Record], owner: &str, rtype: u16) -> Vec<&'a Record> {
+    records
+        .iter()
+        .filter(|record| record.rtype == rtype && normalize(&record.name) == owner)
+        .collect()
+}
+
+/// The signatures over the records of type `rtype` at `owner`.
+fn signatures(records: &[Record], owner: &str, rtype: u16) -> Vec<Rrsig> {
+    records_of(records, owner, TYPE_RRSIG)
+        .iter()
+        .filter_map(|record| Rrsig::parse(&record.rdata))
+        .filter(|signature| signature.type_covered == rtype)
+        .collect()
+}
+
+/// Canonical order of names (RFC 4034 section 6.1), label by label from the
+/// root, both names normalized.
+fn canonical_cmp(a: &str, b: &str) -> Ordering {
+    let labels = |name: &str| {
+        let wire = canonical_name(name);
+        let mut labels = vec![];
+        let mut position = 0;
+
+        while let Some(&length) = wire.get(position).filter(|length| **length > 0) {
+            labels.push(wire[position + 1..position + 1 + length as usize].to_vec());
+            position += 1 + length as usize;
+        }
+
+        labels.reverse();
+        labels
+    };
+
+    labels(a).cmp(&labels(b))
+}
+
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as u32)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use ring::digest;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    use super::*;
+    use crate::testing::{database, https_server, Pki, Request};
+
+    const ALGORITHM_ED25519: u8 = 15;
+    const DIGEST_SHA256: u8 = 2;
+    const TYPE_A: u16 = 1;
+    const TTL: u32 = 3600;
+
+    // Signatures of the fixture are valid from 2020 to 2050, the expired one
+    // from 2000 to 2001. Validity periods are compared in serial number
+    // arithmetic, which leaves them shorter than 68 years
+    const INCEPTION: u32 = 1_577_836_800;
+    const EXPIRATION: u32 = 2_524_608_000;
+    const EXPIRED: (u32, u32) = (946_684_800, 978_307_200);
+
+    /// The key of a zone, from a fixed seed so that the fixture is the same
+    /// on every run.
+    struct Key {
+        zone: String,
+        pair: Ed25519KeyPair,
+    }
+
+    impl Key {
+        fn new(zone: &str, seed: u8) -> Self {
+            Self { zone: zone.to_string(), pair: Ed25519KeyPair::from_seed_unchecked(&[seed; 32]).unwrap() }
+        }
+
+        /// Data of the DNSKEY record, a key signing key.
+        fn dnskey(&self) -> Vec<u8> {
+            [[0x01, 0x01, 3, ALGORITHM_ED25519].as_slice(), self.pair.public_key().as_ref()].concat()
+        }
+
+        fn key_tag(&self) -> u16 {
+            Dnskey::parse(&self.dnskey()).unwrap().key_tag()
+        }
+
+        fn ds_digest(&self) -> Vec<u8> {
+            digest::digest(&digest::SHA256, &[wire(&self.zone), self.dnskey()].concat()).as_ref().to_vec()
+        }
+
+        /// Data of the DS record the parent publishes for the key.
+        fn ds(&self) -> Vec<u8> {
+            [self.key_tag().to_be_bytes().as_slice(), &[ALGORITHM_ED25519, DIGEST_SHA256], &self.ds_digest()].concat()
+        }
+
+        /// The records of a set along with their signature.
+        fn sign(&self, records: Vec<Record>) -> Vec<Record> {
+            self.sign_between(records, INCEPTION, EXPIRATION)
+        }
+
+        fn sign_between(&self, mut records: Vec<Record>, inception: u32, expiration: u32) -> Vec<Record> {
+            let owner = records[0].name.clone();
+            let rtype = records[0].rtype;
+            let labels = owner.split('.').filter(|label| !label.is_empty()).count() as u8;
+
+            let fields = [
+                rtype.to_be_bytes().as_slice(),
+                &[ALGORITHM_ED25519, labels],
+                &TTL.to_be_bytes(),
+                &expiration.to_be_bytes(),
+                &inception.to_be_bytes(),
+                &self.key_tag().to_be_bytes(),
+                &wire(&self.zone),
+            ].concat();
+
+            let mut rdatas: Vec<&[u8]> = records.iter().map(|record| record.rdata.as_slice()).collect();
+            rdatas.sort();
+
+            let mut data = fields.clone();
+
+            for rdata in rdatas {
+                data.extend(wire(&owner));
+                data.extend(rtype.to_be_bytes());
+                data.extend(CLASS_IN.to_be_bytes());
+                data.extend(TTL.to_be_bytes());
+                data.extend((rdata.len() as u16).to_be_bytes());
+                data.extend(rdata);
+            }
+
+            let signature = record(&owner, TYPE_RRSIG, [fields, self.pair.sign(&data).as_ref().to_vec()].concat());
+
+            records.push(signature);
+            records
+        }
+    }
+
+    /// Lower case wire form of a name.
+    fn wire(name: &str) -> Vec<u8> {
+        let mut wire = vec![];
+
+        for label in name.split('.').filter(|label| !label.is_empty()) {
+            wire.push(label.len() as u8);
+            wire.extend(label.to_ascii_lowercase().bytes());
+        }
+
+        wire.push(0);
+        wire
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn record(name: &str, rtype: u16, rdata: Vec<u8>) -> Record {
+        Record { name: name.to_string(), rtype, class: CLASS_IN, ttl: TTL, rdata }
+    }
+
+    fn address(name: &str) -> Record {
+        record(name, TYPE_A, vec![192, 0, 2, 1])
+    }
+
+    /// Type bitmap of an NSEC or NSEC3 record, for types below 256.
+    fn type_bitmap(types: &[u16]) -> Vec<u8> {
+        let mut bitmap = [0u8; 32];
+
+        for rtype in types {
+            bitmap[*rtype as usize / 8] |= 0x80 >> (rtype % 8);
+        }
+
+        let length = bitmap.iter().rposition(|byte| *byte != 0).map_or(0, |i| i + 1);
+
+        [[0, length as u8].as_slice(), &bitmap[..length]].concat()
+    }
+
+    fn nsec(owner: &str, next: &str, types: &[u16]) -> Record {
+        record(owner, TYPE_NSEC, [wire(next), type_bitmap(types)].concat())
+    }
+
+    /// The NSEC3 hash of a name, without salt nor extra iterations as RFC
+    /// 9276 recommends.
+    fn nsec3_hash(name: &str) -> Vec<u8> {
+        digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &wire(name)).as_ref().to_vec()
+    }
+
+    fn base32hex(bytes: &[u8]) -> String {
+        const ALPHABET: &[u8] = b"0123456789abcdefghijklmnopqrstuv";
+
+        let mut text = String::new();
+        let mut buffer: u32 = 0;
+        let mut bits = 0;
+
+        for byte in bytes {
+            buffer = buffer << 8 | *byte as u32;
+            bits += 8;
+
+            while bits >= 5 {
+                bits -= 5;
+                text.push(ALPHABET[(buffer >> bits & 0x1f) as usize] as char);
+            }
+        }
+
+        text
+    }
+
+    fn nsec3(zone: &str, hash: &[u8], next: &[u8], opt_out: bool, types: &[u16]) -> Record {
+        let owner = format!("{}.{}", base32hex(hash), zone);
+        let fields = [1, opt_out as u8, 0, 0, 0, next.len() as u8];
+
+        record(&owner, TYPE_NSEC3, [fields.as_slice(), next, &type_bitmap(types)].concat())
+    }
+
+    /// The rcode, answer and authority records of a reply.
+    type Reply = (u8, Vec<Record>, Vec<Record>);
+
+    /// The replies of the upstream, by name and type of the question.
+    #[derive(Default)]
+    struct Zone {
+        replies: HashMap<(String, u16), Reply>,
+        queries: Mutex<Vec<(String, u16)>>,
+    }
+
+    impl Zone {
+        fn answer(&mut self, records: Vec<Record>) {
+            let key = (normalize(&records[0].name), records[0].rtype);
+
+            self.replies.insert(key, (RCODE_NOERROR, records, vec![]));
+        }
+
+        /// Answers that `name` has no records of type `rtype`, with the proof
+        /// of it in the authority section.
+        fn deny(&mut self, name: &str, rtype: u16, authority: Vec<Record>) {
+            self.replies.insert((normalize(name), rtype), (RCODE_NOERROR, vec![], authority));
+        }
+
+        fn reply(&self, query: &[u8]) -> Vec<u8> {
+            let mut message = Message::decode(query).unwrap();
+
+            let key = (normalize(&message.questions[0].name), message.questions[0].qtype);
+
+            self.queries.lock().unwrap().push(key.clone());
+
+            let (rcode, answers, authority) = self.replies
+                .get(&key)
+                .cloned()
+                .unwrap_or((RCODE_NXDOMAIN, vec![], vec![]));
+
+            message.flags = 0x8180 | rcode as u16;
+            message.answers = answers;
+            message.authority = authority;
+            message.additional.clear();
+
+            message.encode().unwrap()
+        }
+
+        fn queries(&self, name: &str, rtype: u16) -> usize {
+            self.queries.lock().unwrap().iter().filter(|query| **query == (name.to_string(), rtype)).count()
+        }
+    }
+
+    /// A root with the trust anchor key delegating to:
+    /// - `signed.`, signed and denying with NSEC,
+    /// - `hashed.`, signed and denying with NSEC3,
+    /// - `unsigned.`, proven unsigned by an NSEC record of the root,
+    /// - `forged.`, whose denial is signed by a key nobody trusts.
+    fn fixture() -> (Zone, Key) {
+        let root = Key::new(".", 1);
+        let signed = Key::new("signed.", 2);
+        let hashed = Key::new("hashed.", 3);
+        let stranger = Key::new(".", 4);
+
+        let mut zone = Zone::default();
+
+        for key in [&root, &signed, &hashed] {
+            zone.answer(key.sign(vec![record(&key.zone, TYPE_DNSKEY, key.dnskey())]));
+        }
+
+        for child in [&signed, &hashed] {
+            zone.answer(root.sign(vec![record(&child.zone, TYPE_DS, child.ds())]));
+        }
+
+        let delegation = [TYPE_NS, TYPE_RRSIG, TYPE_NSEC];
+
+        zone.deny("unsigned.", TYPE_DS, root.sign(vec![nsec("unsigned.", "zz.", &delegation)]));
+        zone.answer(vec![address("www.unsigned.")]);
+
+        zone.deny("forged.", TYPE_DS, stranger.sign(vec![nsec("forged.", "zz.", &delegation)]));
+        zone.answer(vec![address("www.forged.")]);
+
+        zone.answer(signed.sign(vec![address("www.signed.")]));
+
+        let mut bad = signed.sign(vec![address("bad.signed.")]);
+        *bad[1].rdata.last_mut().unwrap() ^= 1;
+        zone.answer(bad);
+
+        zone.answer(signed.sign_between(vec![address("old.signed.")], EXPIRED.0, EXPIRED.1));
+
+        zone.answer(vec![address("nosig.signed.")]);
+        zone.deny("nosig.signed.", TYPE_DS, signed.sign(vec![nsec("nosig.signed.", "old.signed.", &[TYPE_A, TYPE_RRSIG, TYPE_NSEC])]));
+
+        let everything = ([0u8; 20], [0xffu8; 20]);
+
+        zone.answer(vec![address("www.child.hashed.")]);
+        zone.deny("child.hashed.", TYPE_DS, hashed.sign(vec![nsec3("hashed.", &nsec3_hash("child.hashed."), &everything.1, false, &[TYPE_NS])]));
+
+        zone.answer(vec![address("www.optout.hashed.")]);
+        zone.deny("optout.hashed.", TYPE_DS, hashed.sign(vec![nsec3("hashed.", &everything.0, &everything.1, true, &[TYPE_NS])]));
+
+        zone.answer(vec![address("www.strict.hashed.")]);
+        zone.deny("strict.hashed.", TYPE_DS, hashed.sign(vec![nsec3("hashed.", &everything.0, &everything.1, false, &[TYPE_NS])]));
+
+        (zone, root)
+    }
+
+    struct Setup {
+        validator: Validator,
+        upstream: Upstream,
+        zone: Arc<Zone>,
+    }
+
+    impl Setup {
+        async fn new() -> Self {
+            let pki = Pki::new();
+            let (zone, root) = fixture();
+            let zone = Arc::new(zone);
+
+            let address = https_server(pki.server_config(&[b"http/1.1"]), {
+                let zone = zone.clone();
+                move |request: Request| {
+                    let zone = zone.clone();
+                    async move { (200, zone.reply(&request.body)) }
+                }
+            }).await;
+
+            let settings = pki.application_settings(address, &format!(
+                "[dnssec]\nvalidate=true\ntrust_anchor=. IN DS {} {} {} {}\n",
+                root.key_tag(),
+                ALGORITHM_ED25519,
+                DIGEST_SHA256,
+                hex(&root.ds_digest())));
+
+            let (events, _) = tokio::sync::mpsc::unbounded_channel();
+
+            let upstream = Upstream::new(settings.upstreams()[0].clone(), settings.health(), settings.retry(), true, events).unwrap();
+            let validator = Validator::new(database().await, settings.dnssec());
+
+            Self { validator, upstream, zone }
+        }
+
+        /// Asks the upstream for the addresses of `name` and validates them.
+        async fn validate(&self, name: &str) -> Validation {
+            let reply = self.upstream
+                .exchange(Message::question(name, TYPE_A).with_dnssec_ok(true))
+                .await
+                .unwrap()
+                .into_reply()
+                .unwrap();
+
+            self.validator.validate(&self.upstream, &reply).await
+        }
+    }
+
+    #[tokio::test]
+    async fn signed_answers_are_secure() {
+        let setup = Setup::new().await;
+
+        assert_eq!(setup.validate("www.signed.").await, Validation::Secure);
+    }
+
+    #[tokio::test]
+    async fn validated_keys_are_kept() {
+        let setup = Setup::new().await;
+
+        assert_eq!(setup.validate("www.signed.").await, Validation::Secure);
+        assert_eq!(setup.validate("www.signed.").await, Validation::Secure);
+
+        assert_eq!(setup.zone.queries("signed.", TYPE_DNSKEY), 1);
+        assert_eq!(setup.zone.queries(".", TYPE_DNSKEY), 1);
+    }
+
+    #[tokio::test]
+    async fn answers_below_an_unsigned_delegation_are_insecure() {
+        let setup = Setup::new().await;
+
+        assert_eq!(setup.validate("www.unsigned.").await, Validation::Insecure);
+    }
+
+    #[tokio::test]
+    async fn a_wrong_signature_is_bogus() {
+        let setup = Setup::new().await;
+
+        assert_eq!(setup.validate("bad.signed.").await, Validation::Bogus);
+    }
+
+    #[tokio::test]
+    async fn an_expired_signature_is_bogus() {
+        let setup = Setup::new().await;
+
+        assert_eq!(setup.validate("old.signed.").await, Validation::Bogus);
+    }
+
+    #[tokio::test]
+    async fn unsigned_answers_of_a_signed_zone_are_bogus() {
+        let setup = Setup::new().await;
+
+        assert_eq!(setup.validate("nosig.signed.").await, Validation::Bogus);
+    }
+
+    #[tokio::test]
+    async fn a_denial_signed_by_an_unknown_key_is_bogus() {
+        let setup = Setup::new().await;
+
+        assert_eq!(setup.validate("www.forged.").await, Validation::Bogus);
+    }
+
+    #[tokio::test]
+    async fn nsec3_proves_an_unsigned_delegation() {
+        let setup = Setup::new().await;
+
+        assert_eq!(setup.validate("www.child.hashed.").await, Validation::Insecure);
+    }
+
+    #[tokio::test]
+    async fn a_covering_opt_out_nsec3_proves_an_unsigned_delegation() {
+        let setup = Setup::new().await;
+
+        assert_eq!(setup.validate("www.optout.hashed.").await, Validation::Insecure);
+    }
+
+    #[tokio::test]
+    async fn a_covering_nsec3_without_opt_out_proves_nothing() {
+        let setup = Setup::new().await;
+
+        assert_eq!(setup.validate("www.strict.hashed.").await, Validation::Bogus);
+    }
+
+    #[test]
+    fn hashes_names_as_rfc_5155_does() {
+        // https://www.rfc-editor.org/rfc/rfc5155#appendix-A
+        let hash = crypto::nsec3_hash(1, &wire("example."), &[0xaa, 0xbb, 0xcc, 0xdd], 12);
+
+        assert_eq!(hash, crypto::decode_base32hex("0p9mhaveqvm6t7vbl5lop2u3t2rp3tom"));
+    }
+}
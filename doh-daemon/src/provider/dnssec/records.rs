@@ -0,0 +1,379 @@
+use crate::provider::message::{read_uncompressed_name, write_name, Record};
+
+// https://www.rfc-editor.org/rfc/rfc4034#section-6.2
+const TYPE_NS: u16 = 2;
+const TYPE_CNAME: u16 = 5;
+const TYPE_SOA: u16 = 6;
+const TYPE_PTR: u16 = 12;
+const TYPE_MX: u16 = 15;
+const TYPE_SRV: u16 = 33;
+const TYPE_DNAME: u16 = 39;
+pub const TYPE_DS: u16 = 43;
+pub const TYPE_RRSIG: u16 = 46;
+pub const TYPE_NSEC: u16 = 47;
+pub const TYPE_DNSKEY: u16 = 48;
+pub const TYPE_NSEC3: u16 = 50;
+
+const CLASS_IN: u16 = 1;
+
+// https://www.rfc-editor.org/rfc/rfc4034#section-2.1.1
+const DNSKEY_FLAG_ZONE: u16 = 0x0100;
+const DNSKEY_PROTOCOL: u8 = 3;
+// https://www.rfc-editor.org/rfc/rfc5155#section-3.1.2.1
+const NSEC3_FLAG_OPT_OUT: u8 = 0x01;
+
+/// Length of the RRSIG fields that precede the signer name.
+const RRSIG_FIXED_LEN: usize = 18;
+
+/// A DNSKEY record (RFC 4034 section 2).
+#[derive(Debug)]
+pub struct Dnskey {
+    pub algorithm: u8,
+    pub public_key: Vec<u8>,
+    flags: u16,
+    protocol: u8,
+    rdata: Vec<u8>,
+}
+
+impl Dnskey {
+    pub fn parse(rdata: &[u8]) -> Option<Self> {
+        Some(Self {
+            flags: u16::from_be_bytes([*rdata.first()?, *rdata.get(1)?]),
+            protocol: *rdata.get(2)?,
+            algorithm: *rdata.get(3)?,
+            public_key: rdata.get(4..)?.to_vec(),
+            rdata: rdata.to_vec(),
+        })
+    }
+
+    /// Whether the key may sign the records of its zone.
+    pub fn is_zone_key(&self) -> bool {
+        self.flags & DNSKEY_FLAG_ZONE != 0 && self.protocol == DNSKEY_PROTOCOL
+    }
+
+    pub fn rdata(&self) -> &[u8] {
+        &self.rdata
+    }
+
+    /// https://www.rfc-editor.org/rfc/rfc4034#appendix-B
+    pub fn key_tag(&self) -> u16 {
+        let mut sum: u32 = 0;
+
+        for (i, byte) in self.rdata.iter().enumerate() {
+            sum += if i % 2 == 0 { (*byte as u32) << 8 } else { *byte as u32 };
+        }
+
+        sum += (sum >> 16) & 0xFFFF;
+
+        (sum & 0xFFFF) as u16
+    }
+}
+
+/// A DS record (RFC 4034 section 5), which a parent zone publishes for the
+/// keys of a signed child.
+#[derive(Clone, Debug)]
+pub struct Ds {
+    pub key_tag: u16,
+    pub algorithm: u8,
+    pub digest_type: u8,
+    pub digest: Vec<u8>,
+}
+
+impl Ds {
+    pub fn parse(rdata: &[u8]) -> Option<Self> {
+        Some(Self {
+            key_tag: u16::from_be_bytes([*rdata.first()?, *rdata.get(1)?]),
+            algorithm: *rdata.get(2)?,
+            digest_type: *rdata.get(3)?,
+            digest: rdata.get(4..)?.to_vec(),
+        })
+    }
+}
+
+/// An RRSIG record (RFC 4034 section 3).
+#[derive(Debug)]
+pub struct Rrsig {
+    pub type_covered: u16,
+    pub algorithm: u8,
+    pub labels: u8,
+    pub original_ttl: u32,
+    pub expiration: u32,
+    pub inception: u32,
+    pub key_tag: u16,
+    pub signer: String,
+    pub signature: Vec<u8>,
+    // The record data without the signature, signer name in canonical form.
+    signed_fields: Vec<u8>,
+}
+
+impl Rrsig {
+    pub fn parse(rdata: &[u8]) -> Option<Self> {
+        let fixed = rdata.get(..RRSIG_FIXED_LEN)?;
+        let (signer, end) = read_uncompressed_name(rdata, RRSIG_FIXED_LEN)?;
+
+        let u32_at = |at: usize| u32::from_be_bytes([fixed[at], fixed[at + 1], fixed[at + 2], fixed[at + 3]]);
+
+        Some(Self {
+            type_covered: u16::from_be_bytes([fixed[0], fixed[1]]),
+            algorithm: fixed[2],
+            labels: fixed[3],
+            original_ttl: u32_at(4),
+            expiration: u32_at(8),
+            inception: u32_at(12),
+            key_tag: u16::from_be_bytes([fixed[16], fixed[17]]),
+            signer: normalize(&signer),
+            signature: rdata.get(end..)?.to_vec(),
+            signed_fields: [fixed, &canonical_name(&signer)].concat(),
+        })
+    }
+
+    /// Whether `now` falls within the validity period, in the serial number
+    /// arithmetic of RFC 1982 since the times wrap around in 2106.
+    pub fn is_current(&self, now: u32) -> bool {
+        (now.wrapping_sub(self.inception) as i32) >= 0 && (self.expiration.wrapping_sub(now) as i32) >= 0
+    }
+
+    /// The data the signature covers (RFC 4034 section 3.1.8.1): the
+    /// signature fields followed by the records of the set in canonical form
+    /// and order.
+    pub fn signed_data(&self, owner: &str, records: &[&Record]) -> Option<Vec<u8>> {
+        let owner = canonical_name(owner);
+        let labels = label_count(&owner);
+
+        if self.labels > labels {
+            return None;
+        }
+
+        // Records synthesized from a wildcard are signed under the wildcard
+        let owner = if self.labels < labels {
+            [b"\x01*".as_slice(), strip_labels(&owner, (labels - self.labels) as usize)].concat()
+        } else {
+            owner
+        };
+
+        let mut rdatas: Vec<Vec<u8>> = records
+            .iter()
+            .map(|record| canonical_rdata(record.rtype, &record.rdata))
+            .collect();
+
+        rdatas.sort();
+        rdatas.dedup();
+
+        let mut data = self.signed_fields.clone();
+
+        for rdata in rdatas {
+            data.extend_from_slice(&owner);
+            data.extend_from_slice(&self.type_covered.to_be_bytes());
+            data.extend_from_slice(&CLASS_IN.to_be_bytes());
+            data.extend_from_slice(&self.original_ttl.to_be_bytes());
+            data.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            data.extend_from_slice(&rdata);
+        }
+
+        Some(data)
+    }
+}
+
+/// An NSEC record (RFC 4034 section 4): the next name of the zone, in
+/// canonical order, and the types present at the owner name.
+#[derive(Debug)]
+pub struct Nsec {
+    pub next: String,
+    pub types: TypeBitmap,
+}
+
+impl Nsec {
+    pub fn parse(rdata: &[u8]) -> Option<Self> {
+        let (next, end) = read_uncompressed_name(rdata, 0)?;
+
+        Some(Self { next, types: TypeBitmap::parse(rdata.get(end..)?)? })
+    }
+}
+
+/// An NSEC3 record (RFC 5155 section 3): like NSEC, over the hashes of the
+/// names rather than the names themselves.
+#[derive(Debug)]
+pub struct Nsec3 {
+    pub hash_algorithm: u8,
+    pub iterations: u16,
+    pub salt: Vec<u8>,
+    pub next_hashed: Vec<u8>,
+    pub types: TypeBitmap,
+    flags: u8,
+}
+
+impl Nsec3 {
+    pub fn parse(rdata: &[u8]) -> Option<Self> {
+        let salt_length = *rdata.get(4)? as usize;
+        let salt_end = 5 + salt_length;
+        let hash_length = *rdata.get(salt_end)? as usize;
+        let hash_end = salt_end + 1 + hash_length;
+
+        Some(Self {
+            hash_algorithm: *rdata.first()?,
+            flags: *rdata.get(1)?,
+            iterations: u16::from_be_bytes([*rdata.get(2)?, *rdata.get(3)?]),
+            salt: rdata.get(5..salt_end)?.to_vec(),
+            next_hashed: rdata.get(salt_end + 1..hash_end)?.to_vec(),
+            types: TypeBitmap::parse(rdata.get(hash_end..)?)?,
+        })
+    }
+
+    /// Whether unsigned delegations may be left out of the chain, in which
+    /// case a covering record proves nothing about their existence.
+    pub fn is_opt_out(&self) -> bool {
+        self.flags & NSEC3_FLAG_OPT_OUT != 0
+    }
+}
+
+/// The types of an NSEC or NSEC3 record, in the window block encoding of
+/// RFC 4034 section 4.1.2.
+#[derive(Debug)]
+pub struct TypeBitmap {
+    types: Vec<u16>,
+}
+
+impl TypeBitmap {
+    fn parse(mut bytes: &[u8]) -> Option<Self> {
+        let mut types = vec![];
+
+        while !bytes.is_empty() {
+            let window = *bytes.first()? as u16;
+            let length = *bytes.get(1)? as usize;
+            let bitmap = bytes.get(2..2 + length)?;
+
+            for (i, byte) in bitmap.iter().enumerate() {
+                for bit in 0..8 {
+                    if byte & (0x80 >> bit) != 0 {
+                        types.push(window << 8 | (i * 8 + bit) as u16);
+                    }
+                }
+            }
+
+            bytes = &bytes[2 + length..];
+        }
+
+        Some(Self { types })
+    }
+
+    pub fn contains(&self, rtype: u16) -> bool {
+        self.types.contains(&rtype)
+    }
+}
+
+/// Lower case, fully qualified form of a name used to compare zones.
+pub fn normalize(name: &str) -> String {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+
+    format!("{}.", name)
+}
+
+/// Whether `name` is `zone` itself or a name below it, both normalized.
+pub fn is_subdomain(name: &str, zone: &str) -> bool {
+    zone == "." || name == zone || name.ends_with(&format!(".{}", zone))
+}
+
+/// The name one label up, none for the root.
+pub fn parent(name: &str) -> Option<String> {
+    let wire = canonical_name(name);
+    let first = *wire.first()? as usize;
+
+    if first == 0 {
+        return None;
+    }
+
+    read_uncompressed_name(&wire, 1 + first).map(|(parent, _)| parent)
+}
+
+/// Uncompressed wire form of a name, in lower case (RFC 4034 section 6.2).
+/// The length octets never fall within the ASCII letters. Names met during
+/// validation were decoded from a message, so they always fit; one that
+/// didn't would come out empty and match nothing.
+pub fn canonical_name(name: &str) -> Vec<u8> {
+    let mut wire = Vec::with_capacity(name.len() + 2);
+
+    if write_name(&mut wire, name).is_err() {
+        return vec![];
+    }
+
+    wire.make_ascii_lowercase();
+
+    wire
+}
+
+/// Number of labels of a name in wire form, leaving out the root and a
+/// leading wildcard as the RRSIG labels field does.
+pub fn label_count(wire: &[u8]) -> u8 {
+    let mut count = 0u8;
+    let mut position = 0;
+
+    while let Some(&length) = wire.get(position) {
+        if length == 0 {
+            break;
+        }
+
+        if !(position == 0 && wire.get(1..2) == Some(b"*") && length == 1) {
+            count += 1;
+        }
+
+        position += 1 + length as usize;
+    }
+
+    count
+}
+
+/// Drops the first `count` labels of a name in wire form.
+fn strip_labels(wire: &[u8], count: usize) -> &[u8] {
+    let mut position = 0;
+
+    for _ in 0..count {
+        match wire.get(position) {
+            Some(&length) if length > 0 => position += 1 + length as usize,
+            _ => break,
+        }
+    }
+
+    wire.get(position..).unwrap_or(&[0])
+}
+
+/// Lowers the case of the names embedded in the data of the types listed in
+/// RFC 4034 section 6.2, as amended by RFC 6840 section 5.1.
+fn canonical_rdata(rtype: u16, rdata: &[u8]) -> Vec<u8> {
+    let mut rdata = rdata.to_vec();
+
+    match rtype {
+        TYPE_NS | TYPE_CNAME | TYPE_PTR | TYPE_DNAME => {
+            lowercase_name(&mut rdata, 0);
+        }
+        TYPE_SOA => {
+            if let Some(end) = lowercase_name(&mut rdata, 0) {
+                lowercase_name(&mut rdata, end);
+            }
+        }
+        TYPE_MX => {
+            lowercase_name(&mut rdata, 2);
+        }
+        TYPE_SRV => {
+            lowercase_name(&mut rdata, 6);
+        }
+        _ => {}
+    }
+
+    rdata
+}
+
+/// Lowers the case of the uncompressed name at `start`, returning where it ends.
+fn lowercase_name(rdata: &mut [u8], start: usize) -> Option<usize> {
+    let mut position = start;
+
+    loop {
+        let length = *rdata.get(position)? as usize;
+
+        if length == 0 {
+            return Some(position + 1);
+        }
+
+        rdata.get_mut(position + 1..position + 1 + length)?.make_ascii_lowercase();
+        position += 1 + length;
+    }
+}
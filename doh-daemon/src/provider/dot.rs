@@ -0,0 +1,18 @@
+use tracing::instrument;
+use doh_common::error::Error;
+use crate::client::tls::TlsClient;
+use crate::provider::message::Message;
+
+/// DNS-over-TLS as specified by RFC 7858, exchanging length-prefixed binary
+/// DNS messages over a long-lived TLS connection on port 853.
+pub struct DnsOverTls;
+
+impl DnsOverTls {
+    #[instrument(skip_all)]
+    pub async fn exchange(client: &TlsClient, query: &Message) -> Result<Message, Error> {
+
+        let body = client.request_message(&query.encode()?).await?;
+
+        Message::decode(&body)
+    }
+}
@@ -0,0 +1,235 @@
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use tokio::net::UdpSocket;
+use tracing::debug;
+
+use crate::settings::ClientSubnet;
+
+// https://www.rfc-editor.org/rfc/rfc7871#section-6
+const FAMILY_IPV4: u16 = 1;
+const FAMILY_IPV6: u16 = 2;
+
+/// The EDNS Client Subnet (RFC 7871) sent with a query: the network the
+/// client is in, which the upstream may use to pick nearby servers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Subnet {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl Subnet {
+    /// Tells the upstream not to use the client address at all.
+    pub fn none() -> Self {
+        Self { address: IpAddr::V4(Ipv4Addr::UNSPECIFIED), prefix: 0 }
+    }
+
+    /// Keeps the first `prefix` bits of `address`.
+    pub fn new(address: IpAddr, prefix: u8) -> Self {
+        let prefix = match address {
+            IpAddr::V4(_) => prefix.min(32),
+            IpAddr::V6(_) => prefix.min(128),
+        };
+
+        let address = match address {
+            IpAddr::V4(ip) => IpAddr::V4(Ipv4Addr::from(mask(u32::from(ip) as u128, 32, prefix) as u32)),
+            IpAddr::V6(ip) => IpAddr::V6(mask(u128::from(ip), 128, prefix).into()),
+        };
+
+        Self { address, prefix }
+    }
+
+    /// Subnet to send to an upstream reached at `upstream_addresses`, none
+    /// when the policy leaves the choice to the upstream.
+    pub async fn for_policy(policy: &ClientSubnet, upstream_addresses: &[IpAddr]) -> Option<Self> {
+        match policy {
+            ClientSubnet::Default => None,
+            ClientSubnet::Disabled => Some(Self::none()),
+            ClientSubnet::Prefix(address, prefix) => Some(Self::new(*address, *prefix)),
+            ClientSubnet::Truncate { v4, v6 } => {
+                let mut local = None;
+
+                for upstream in upstream_addresses {
+                    local = local_address(*upstream).await;
+
+                    if local.is_some() {
+                        break;
+                    }
+                }
+
+                let subnet = local
+                    .filter(is_public)
+                    .map(|address| match address {
+                        IpAddr::V4(_) => Self::new(address, *v4),
+                        IpAddr::V6(_) => Self::new(address, *v6),
+                    });
+
+                if subnet.is_none() {
+                    debug!("no public client address to truncate, sending none");
+                }
+
+                Some(subnet.unwrap_or_else(Self::none))
+            }
+        }
+    }
+
+    /// Data of the EDNS option, the address cut to the bytes the prefix covers.
+    pub fn option_data(&self) -> Vec<u8> {
+        let (family, octets) = match self.address {
+            IpAddr::V4(ip) => (FAMILY_IPV4, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (FAMILY_IPV6, ip.octets().to_vec()),
+        };
+
+        let length = (self.prefix as usize).div_ceil(8);
+
+        // Scope prefix length, always zero in queries
+        [family.to_be_bytes().as_slice(), &[self.prefix, 0], &octets[..length]].concat()
+    }
+}
+
+impl Display for Subnet {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+fn mask(address: u128, bits: u8, prefix: u8) -> u128 {
+    if prefix == 0 {
+        return 0;
+    }
+
+    let keep = u128::MAX << (128 - prefix as u32);
+
+    (address << (128 - bits as u32) & keep) >> (128 - bits as u32)
+}
+
+/// Address of the interface the system routes to `upstream` through. No
+/// packet is sent, connecting a UDP socket only selects the route.
+async fn local_address(upstream: IpAddr) -> Option<IpAddr> {
+    let local: SocketAddr = match upstream {
+        IpAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        IpAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+
+    let socket = UdpSocket::bind(local).await.ok()?;
+    socket.connect((upstream, 53)).await.ok()?;
+
+    socket.local_addr().ok().map(|address| address.ip())
+}
+
+/// Behind a NAT the interface has a private address, which says nothing about
+/// where the client is and would only reveal the layout of its network.
+fn is_public(address: &IpAddr) -> bool {
+    match address {
+        IpAddr::V4(ip) => {
+            let shared = ip.octets()[0] == 100 && (ip.octets()[1] & 0xC0) == 64;
+
+            !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified() || shared)
+        }
+        IpAddr::V6(ip) => {
+            let unique_local = (ip.segments()[0] & 0xFE00) == 0xFC00;
+            let link_local = (ip.segments()[0] & 0xFFC0) == 0xFE80;
+
+            !(ip.is_loopback() || ip.is_unspecified() || unique_local || link_local)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use super::*;
+
+    fn subnet(address: &str, prefix: u8) -> Subnet {
+        Subnet::new(address.parse().unwrap(), prefix)
+    }
+
+    #[test]
+    fn masks_the_bits_after_the_prefix() {
+        let v4 = u32::from(Ipv4Addr::new(198, 51, 100, 77)) as u128;
+        let v6 = u128::from("2001:db8:1234:5678:9abc::1".parse::<Ipv6Addr>().unwrap());
+
+        let cases = [
+            (v4, 32, 0, 0),
+            (v4, 32, 1, 0x8000_0000),
+            (v4, 32, 20, u32::from(Ipv4Addr::new(198, 51, 96, 0)) as u128),
+            (v4, 32, 24, u32::from(Ipv4Addr::new(198, 51, 100, 0)) as u128),
+            (v4, 32, 31, u32::from(Ipv4Addr::new(198, 51, 100, 76)) as u128),
+            (v4, 32, 32, v4),
+            (v6, 128, 0, 0),
+            (v6, 128, 56, u128::from("2001:db8:1234:5600::".parse::<Ipv6Addr>().unwrap())),
+            (v6, 128, 61, u128::from("2001:db8:1234:5678::".parse::<Ipv6Addr>().unwrap())),
+            (v6, 128, 67, u128::from("2001:db8:1234:5678:8000::".parse::<Ipv6Addr>().unwrap())),
+            (v6, 128, 128, v6),
+        ];
+
+        for (address, bits, prefix, masked) in cases {
+            assert_eq!(mask(address, bits, prefix), masked, "/{}", prefix);
+        }
+    }
+
+    #[test]
+    fn prefixes_are_capped_to_the_address_length() {
+        assert_eq!(subnet("192.0.2.1", 40), subnet("192.0.2.1", 32));
+        assert_eq!(subnet("2001:db8::1", 200), subnet("2001:db8::1", 128));
+        assert_eq!(subnet("2001:db8::1", 200).to_string(), "2001:db8::1/128");
+    }
+
+    // https://www.rfc-editor.org/rfc/rfc7871#section-6: FAMILY, SOURCE PREFIX-LENGTH,
+    // SCOPE PREFIX-LENGTH, then the address in as few octets as the prefix needs
+    #[test]
+    fn option_data_follows_rfc_7871() {
+        let cases = [
+            (Subnet::none(), vec![0, 1, 0, 0]),
+            (subnet("192.0.2.123", 24), vec![0, 1, 24, 0, 192, 0, 2]),
+            (subnet("198.51.100.77", 20), vec![0, 1, 20, 0, 198, 51, 96]),
+            (subnet("198.51.100.77", 32), vec![0, 1, 32, 0, 198, 51, 100, 77]),
+            (subnet("2001:db8:1234:5678::1", 56), vec![0, 2, 56, 0, 0x20, 0x01, 0x0d, 0xb8, 0x12, 0x34, 0x56]),
+            (subnet("2001:db8:1234:5678::1", 49), vec![0, 2, 49, 0, 0x20, 0x01, 0x0d, 0xb8, 0x12, 0x34, 0x00]),
+        ];
+
+        for (subnet, data) in cases {
+            assert_eq!(subnet.option_data(), data, "{}", subnet);
+        }
+    }
+
+    #[tokio::test]
+    async fn follows_the_policy() {
+        let addresses = ["127.0.0.1".parse().unwrap()];
+
+        assert_eq!(Subnet::for_policy(&ClientSubnet::Default, &addresses).await, None);
+        assert_eq!(Subnet::for_policy(&ClientSubnet::Disabled, &addresses).await, Some(Subnet::none()));
+        assert_eq!(
+            Subnet::for_policy(&ClientSubnet::Prefix("192.0.2.77".parse().unwrap(), 24), &addresses).await,
+            Some(subnet("192.0.2.0", 24)));
+
+        // Reached over the loopback interface, the client has no public address
+        let truncate = ClientSubnet::Truncate { v4: 24, v6: 56 };
+
+        assert_eq!(Subnet::for_policy(&truncate, &addresses).await, Some(Subnet::none()));
+        assert_eq!(Subnet::for_policy(&truncate, &[]).await, Some(Subnet::none()));
+    }
+
+    #[test]
+    fn private_addresses_are_not_sent() {
+        let cases = [
+            ("10.1.2.3", false),
+            ("172.16.0.1", false),
+            ("192.168.1.1", false),
+            ("100.64.0.1", false),
+            ("127.0.0.1", false),
+            ("169.254.1.1", false),
+            ("192.0.2.1", true),
+            ("100.128.0.1", true),
+            ("fd00::1", false),
+            ("fe80::1", false),
+            ("::1", false),
+            ("2001:db8::1", true),
+        ];
+
+        for (address, public) in cases {
+            assert_eq!(is_public(&address.parse().unwrap()), public, "{}", address);
+        }
+    }
+}
@@ -0,0 +1,349 @@
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tracing::{info, warn};
+
+use crate::settings::HealthSettings;
+
+/// Weight of the newest sample in the latency moving average.
+const LATENCY_EWMA_ALPHA: f64 = 0.3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CircuitState {
+    /// Queries flow normally.
+    Closed,
+    /// Too many consecutive failures, the upstream is skipped until the cool down ends.
+    Open,
+    /// The cool down ended and a single probe query is allowed through.
+    HalfOpen,
+}
+
+impl Display for CircuitState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CircuitState::Closed => write!(f, "closed"),
+            CircuitState::Open => write!(f, "open"),
+            CircuitState::HalfOpen => write!(f, "half-open"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct HealthState {
+    circuit: CircuitState,
+    consecutive_failures: u32,
+    latency_ewma: Option<f64>,
+    opened_at: Option<Instant>,
+    probe_in_flight: bool,
+    // Probes let through so far, telling the one in flight from older ones.
+    probes: u64,
+    successes: u64,
+    failures: u64,
+}
+
+/// Health of one upstream: consecutive failures, a latency EWMA and a circuit
+/// breaker that keeps a failing upstream out of rotation for a while.
+#[derive(Debug)]
+pub struct HealthTracker {
+    state: Mutex<HealthState>,
+    failure_threshold: u32,
+    open_duration: Duration,
+}
+
+impl HealthTracker {
+    pub fn new(settings: &HealthSettings) -> Self {
+        Self {
+            state: Mutex::new(HealthState {
+                circuit: CircuitState::Closed,
+                consecutive_failures: 0,
+                latency_ewma: None,
+                opened_at: None,
+                probe_in_flight: false,
+                probes: 0,
+                successes: 0,
+                failures: 0,
+            }),
+            failure_threshold: settings.failure_threshold(),
+            open_duration: settings.open_duration(),
+        }
+    }
+
+    /// Returns an attempt when a query may be sent to the upstream now. An open
+    /// circuit turns half-open once the cool down has elapsed, letting one probe through.
+    pub fn try_acquire(&self) -> Option<Attempt<'_>> {
+        let mut state = self.state.lock().unwrap();
+
+        let allowed = match state.circuit {
+            CircuitState::Closed => true,
+            CircuitState::Open => {
+                let cooled_down = state.opened_at
+                    .map(|opened_at| opened_at.elapsed() >= self.open_duration)
+                    .unwrap_or(true);
+
+                if cooled_down {
+                    state.circuit = CircuitState::HalfOpen;
+                    state.probe_in_flight = true;
+                }
+
+                cooled_down
+            }
+            CircuitState::HalfOpen => {
+                if state.probe_in_flight {
+                    false
+                } else {
+                    state.probe_in_flight = true;
+                    true
+                }
+            }
+        };
+
+        if !allowed {
+            return None;
+        }
+
+        let probe = (state.circuit == CircuitState::HalfOpen).then(|| {
+            state.probes += 1;
+            state.probes
+        });
+
+        Some(self.attempt(probe))
+    }
+
+    /// Returns an attempt regardless of the circuit state. It is never the
+    /// probe of a half-open circuit, so it leaves the one in flight alone.
+    pub fn force_acquire(&self) -> Attempt<'_> {
+        self.attempt(None)
+    }
+
+    fn attempt(&self, probe: Option<u64>) -> Attempt<'_> {
+        Attempt {
+            tracker: self,
+            started: Instant::now(),
+            finished: false,
+            probe,
+        }
+    }
+
+    fn record_success(&self, latency: Duration, probe: Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+
+        let sample = latency.as_secs_f64() * 1000.0;
+
+        state.latency_ewma = Some(match state.latency_ewma {
+            Some(average) => LATENCY_EWMA_ALPHA * sample + (1.0 - LATENCY_EWMA_ALPHA) * average,
+            None => sample,
+        });
+
+        if state.circuit != CircuitState::Closed {
+            info!("circuit closed after a successful probe");
+        }
+
+        state.circuit = CircuitState::Closed;
+        state.consecutive_failures = 0;
+        state.opened_at = None;
+        state.successes += 1;
+        state.end_probe(probe);
+    }
+
+    fn record_failure(&self, probe: Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+
+        state.consecutive_failures += 1;
+        state.failures += 1;
+        state.end_probe(probe);
+
+        let trips = match state.circuit {
+            CircuitState::HalfOpen => true,
+            _ => state.consecutive_failures >= self.failure_threshold,
+        };
+
+        if trips {
+            if state.circuit != CircuitState::Open {
+                warn!("circuit opened after {} consecutive failures", state.consecutive_failures);
+            }
+
+            state.circuit = CircuitState::Open;
+            state.opened_at = Some(Instant::now());
+            // Whatever probe is still out belongs to the half-open period
+            // that just ended, the next one gets its own
+            state.probe_in_flight = false;
+        }
+    }
+
+    fn release(&self, probe: Option<u64>) {
+        self.state.lock().unwrap().end_probe(probe);
+    }
+
+    pub fn status(&self, name: &str) -> doh_common::UpstreamHealth {
+        let state = self.state.lock().unwrap();
+
+        doh_common::UpstreamHealth::new(
+            name.to_string(),
+            state.circuit.to_string(),
+            state.consecutive_failures,
+            state.latency_ewma.unwrap_or(0.0),
+            state.successes,
+            state.failures,
+        )
+    }
+}
+
+impl HealthState {
+    /// Lets another probe through once `probe`, if it is the one in flight, ends.
+    fn end_probe(&mut self, probe: Option<u64>) {
+        if probe.is_some() && probe == Some(self.probes) {
+            self.probe_in_flight = false;
+        }
+    }
+}
+
+/// One query sent to an upstream. Its outcome feeds the health of the upstream;
+/// an attempt dropped without an outcome, because the query was cancelled,
+/// leaves the health untouched.
+pub struct Attempt<'a> {
+    tracker: &'a HealthTracker,
+    started: Instant,
+    finished: bool,
+    // Set when this is the single query let through by a half-open circuit,
+    // numbered among the probes.
+    probe: Option<u64>,
+}
+
+impl Attempt<'_> {
+    pub fn success(mut self) {
+        self.finished = true;
+        self.tracker.record_success(self.started.elapsed(), self.probe);
+    }
+
+    pub fn failure(mut self) {
+        self.finished = true;
+        self.tracker.record_failure(self.probe);
+    }
+}
+
+impl Drop for Attempt<'_> {
+    fn drop(&mut self) {
+        if !self.finished && self.probe.is_some() {
+            self.tracker.release(self.probe);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker(failure_threshold: u32, open_duration: Duration) -> HealthTracker {
+        HealthTracker::new(&HealthSettings::new(failure_threshold, open_duration))
+    }
+
+    fn circuit(tracker: &HealthTracker) -> CircuitState {
+        tracker.state.lock().unwrap().circuit
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let tracker = tracker(3, Duration::from_secs(3600));
+
+        tracker.try_acquire().unwrap().failure();
+        tracker.try_acquire().unwrap().failure();
+        tracker.try_acquire().unwrap().success();
+        tracker.try_acquire().unwrap().failure();
+        tracker.try_acquire().unwrap().failure();
+        assert_eq!(circuit(&tracker), CircuitState::Closed);
+
+        tracker.try_acquire().unwrap().failure();
+        assert_eq!(circuit(&tracker), CircuitState::Open);
+
+        // Skipped until the cool down ends
+        assert!(tracker.try_acquire().is_none());
+    }
+
+    #[test]
+    fn lets_a_single_probe_through_once_cooled_down() {
+        let tracker = tracker(1, Duration::ZERO);
+
+        tracker.try_acquire().unwrap().failure();
+        assert_eq!(circuit(&tracker), CircuitState::Open);
+
+        let probe = tracker.try_acquire().unwrap();
+        assert_eq!(circuit(&tracker), CircuitState::HalfOpen);
+        assert!(tracker.try_acquire().is_none());
+
+        probe.success();
+        assert_eq!(circuit(&tracker), CircuitState::Closed);
+        assert!(tracker.try_acquire().is_some());
+    }
+
+    #[test]
+    fn reopens_when_the_probe_fails() {
+        let tracker = tracker(3, Duration::ZERO);
+
+        for _ in 0..3 {
+            tracker.try_acquire().unwrap().failure();
+        }
+
+        tracker.try_acquire().unwrap().failure();
+        assert_eq!(circuit(&tracker), CircuitState::Open);
+
+        // A single failure is enough to trip a half-open circuit
+        assert!(tracker.try_acquire().is_some());
+        assert_eq!(circuit(&tracker), CircuitState::HalfOpen);
+    }
+
+    #[test]
+    fn a_cancelled_probe_lets_another_through() {
+        let tracker = tracker(1, Duration::ZERO);
+
+        tracker.try_acquire().unwrap().failure();
+
+        drop(tracker.try_acquire().unwrap());
+
+        assert_eq!(circuit(&tracker), CircuitState::HalfOpen);
+        assert!(tracker.try_acquire().is_some());
+    }
+
+    #[test]
+    fn forced_attempts_are_not_the_probe() {
+        let tracker = tracker(1, Duration::ZERO);
+
+        tracker.try_acquire().unwrap().failure();
+
+        let probe = tracker.try_acquire().unwrap();
+
+        // Cancelled or successful, a forced attempt leaves the probe in flight
+        drop(tracker.force_acquire());
+        assert!(tracker.try_acquire().is_none());
+
+        probe.success();
+        assert_eq!(circuit(&tracker), CircuitState::Closed);
+        assert!(!tracker.state.lock().unwrap().probe_in_flight);
+    }
+
+    #[test]
+    fn reopening_clears_the_probe_in_flight() {
+        let tracker = tracker(1, Duration::ZERO);
+
+        tracker.try_acquire().unwrap().failure();
+
+        let stale = tracker.try_acquire().unwrap();
+
+        // A forced failure reopens the circuit under the probe
+        tracker.force_acquire().failure();
+        assert_eq!(circuit(&tracker), CircuitState::Open);
+        assert!(!tracker.state.lock().unwrap().probe_in_flight);
+
+        // The next half-open period has a single probe of its own
+        let probe = tracker.try_acquire().unwrap();
+        assert_eq!(circuit(&tracker), CircuitState::HalfOpen);
+        assert!(tracker.try_acquire().is_none());
+
+        // The end of the older probe does not let a second one through
+        drop(stale);
+        assert!(tracker.try_acquire().is_none());
+
+        probe.success();
+        assert_eq!(circuit(&tracker), CircuitState::Closed);
+    }
+}
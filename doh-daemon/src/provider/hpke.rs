@@ -0,0 +1,343 @@
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_128_GCM, NONCE_LEN};
+use ring::agreement::{agree_ephemeral, EphemeralPrivateKey, UnparsedPublicKey, X25519};
+use ring::hmac;
+use ring::rand::SystemRandom;
+use tracing::error;
+
+use doh_common::error::Error;
+
+// The one suite implemented, the one ODoH servers deploy:
+// https://www.rfc-editor.org/rfc/rfc9180#section-7
+pub const KEM_X25519_HKDF_SHA256: u16 = 0x0020;
+pub const KDF_HKDF_SHA256: u16 = 0x0001;
+pub const AEAD_AES_128_GCM: u16 = 0x0001;
+
+/// Length of the AEAD key.
+pub const NK: usize = 16;
+/// Length of the AEAD nonce.
+pub const NN: usize = NONCE_LEN;
+/// Output length of the KDF hash.
+const NH: usize = 32;
+
+const MODE_BASE: u8 = 0x00;
+const VERSION_LABEL: &[u8] = b"HPKE-v1";
+
+/// Sender side of an HPKE context in base mode (RFC 9180), used to encrypt to
+/// the public key of a server and to derive secrets shared with it.
+pub struct SenderContext {
+    key: LessSafeKey,
+    base_nonce: [u8; NN],
+    exporter_secret: Vec<u8>,
+    sequence: u64,
+}
+
+impl SenderContext {
+    /// Encapsulates a fresh shared secret to `public_key`, returning the
+    /// encapsulated key to send along with the ciphertext, and the context.
+    pub fn setup_base(public_key: &[u8], info: &[u8]) -> Result<(Vec<u8>, Self), Error> {
+        let random = SystemRandom::new();
+
+        let private_key = EphemeralPrivateKey::generate(&X25519, &random).map_err(crypto_error)?;
+        let enc = private_key.compute_public_key().map_err(crypto_error)?.as_ref().to_vec();
+
+        // Fails on low order points, for which the shared secret would be all zeros
+        let dh = agree_ephemeral(private_key, &UnparsedPublicKey::new(&X25519, public_key), |dh| dh.to_vec())
+            .map_err(crypto_error)?;
+
+        let shared_secret = extract_and_expand(&dh, &enc, public_key);
+
+        Ok((enc, Self::key_schedule(&shared_secret, info)?))
+    }
+
+    fn key_schedule(shared_secret: &[u8], info: &[u8]) -> Result<Self, Error> {
+        let suite = hpke_suite();
+
+        let psk_id_hash = labeled_extract(&suite, &[], b"psk_id_hash", &[]);
+        let info_hash = labeled_extract(&suite, &[], b"info_hash", info);
+        let context = [[MODE_BASE].as_slice(), &psk_id_hash, &info_hash].concat();
+
+        let secret = labeled_extract(&suite, shared_secret, b"secret", &[]);
+
+        let key = labeled_expand(&suite, &secret, b"key", &context, NK);
+        let base_nonce = labeled_expand(&suite, &secret, b"base_nonce", &context, NN);
+        let exporter_secret = labeled_expand(&suite, &secret, b"exp", &context, NH);
+
+        Ok(Self {
+            key: aead_key(&key)?,
+            base_nonce: base_nonce.try_into().map_err(|_| Error::UpstreamError)?,
+            exporter_secret,
+            sequence: 0,
+        })
+    }
+
+    /// The context a recipient holding the private key `secret` derives from
+    /// the encapsulated key `enc`, the same as the one of the sender.
+    #[cfg(test)]
+    pub fn setup_base_recipient(secret: &x25519_dalek::StaticSecret, enc: &[u8], info: &[u8]) -> Result<Self, Error> {
+        let public_key = x25519_dalek::PublicKey::from(secret);
+        let enc_key = x25519_dalek::PublicKey::from(<[u8; 32]>::try_from(enc).map_err(|_| Error::MalformedReply)?);
+
+        let shared_secret = extract_and_expand(secret.diffie_hellman(&enc_key).as_bytes(), enc, public_key.as_bytes());
+
+        Self::key_schedule(&shared_secret, info)
+    }
+
+    pub fn seal(&mut self, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce = self.next_nonce();
+
+        let mut ciphertext = plaintext.to_vec();
+
+        self.key
+            .seal_in_place_append_tag(nonce, Aad::from(aad), &mut ciphertext)
+            .map_err(crypto_error)?;
+
+        Ok(ciphertext)
+    }
+
+    /// Decrypts the next message, on the side of the recipient.
+    #[cfg(test)]
+    pub fn open(&mut self, aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce = self.next_nonce();
+
+        let mut plaintext = ciphertext.to_vec();
+
+        let length = self.key
+            .open_in_place(nonce, Aad::from(aad), &mut plaintext)
+            .map_err(crypto_error)?
+            .len();
+
+        plaintext.truncate(length);
+
+        Ok(plaintext)
+    }
+
+    fn next_nonce(&mut self) -> Nonce {
+        let mut nonce = self.base_nonce;
+
+        for (byte, sequence) in nonce[NN - 8..].iter_mut().zip(self.sequence.to_be_bytes()) {
+            *byte ^= sequence;
+        }
+
+        self.sequence += 1;
+
+        Nonce::assume_unique_for_key(nonce)
+    }
+
+    /// Derives a secret of `length` bytes bound to this context.
+    pub fn export(&self, exporter_context: &[u8], length: usize) -> Vec<u8> {
+        labeled_expand(&hpke_suite(), &self.exporter_secret, b"sec", exporter_context, length)
+    }
+}
+
+/// The shared secret of the KEM, from the Diffie-Hellman output `dh`, the
+/// encapsulated key and the public key of the recipient.
+fn extract_and_expand(dh: &[u8], enc: &[u8], public_key: &[u8]) -> Vec<u8> {
+    let kem_suite = [b"KEM".as_slice(), &KEM_X25519_HKDF_SHA256.to_be_bytes()].concat();
+
+    let kem_context = [enc, public_key].concat();
+    let eae_prk = labeled_extract(&kem_suite, &[], b"eae_prk", dh);
+
+    labeled_expand(&kem_suite, &eae_prk, b"shared_secret", &kem_context, NH)
+}
+
+/// HKDF-Extract with SHA-256 (RFC 5869).
+pub fn extract(salt: &[u8], ikm: &[u8]) -> Vec<u8> {
+    hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, salt), ikm).as_ref().to_vec()
+}
+
+/// HKDF-Expand with SHA-256 (RFC 5869).
+pub fn expand(prk: &[u8], info: &[u8], length: usize) -> Vec<u8> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, prk);
+
+    let mut okm = Vec::with_capacity(length + NH);
+    let mut block: Vec<u8> = Vec::with_capacity(NH);
+    let mut counter = 1u8;
+
+    while okm.len() < length {
+        let mut context = hmac::Context::with_key(&key);
+        context.update(&block);
+        context.update(info);
+        context.update(&[counter]);
+
+        block = context.sign().as_ref().to_vec();
+        okm.extend_from_slice(&block);
+        counter += 1;
+    }
+
+    okm.truncate(length);
+
+    okm
+}
+
+/// Decrypts and authenticates a message sealed with AES-128-GCM.
+pub fn open(key: &[u8], nonce: &[u8], aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+    let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(crypto_error)?;
+
+    let mut plaintext = ciphertext.to_vec();
+
+    let length = aead_key(key)?
+        .open_in_place(nonce, Aad::from(aad), &mut plaintext)
+        .map_err(crypto_error)?
+        .len();
+
+    plaintext.truncate(length);
+
+    Ok(plaintext)
+}
+
+fn aead_key(key: &[u8]) -> Result<LessSafeKey, Error> {
+    Ok(LessSafeKey::new(UnboundKey::new(&AES_128_GCM, key).map_err(crypto_error)?))
+}
+
+fn hpke_suite() -> Vec<u8> {
+    [
+        b"HPKE".as_slice(),
+        &KEM_X25519_HKDF_SHA256.to_be_bytes(),
+        &KDF_HKDF_SHA256.to_be_bytes(),
+        &AEAD_AES_128_GCM.to_be_bytes(),
+    ].concat()
+}
+
+fn labeled_extract(suite: &[u8], salt: &[u8], label: &[u8], ikm: &[u8]) -> Vec<u8> {
+    extract(salt, &[VERSION_LABEL, suite, label, ikm].concat())
+}
+
+fn labeled_expand(suite: &[u8], prk: &[u8], label: &[u8], info: &[u8], length: usize) -> Vec<u8> {
+    let length_prefix = (length as u16).to_be_bytes();
+
+    expand(prk, &[length_prefix.as_slice(), VERSION_LABEL, suite, label, info].concat(), length)
+}
+
+fn crypto_error(e: ring::error::Unspecified) -> Error {
+    error!("cryptographic operation failed: {}", e);
+    Error::UpstreamError
+}
+
+#[cfg(test)]
+mod tests {
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    use super::*;
+
+    // DHKEM(X25519, HKDF-SHA256), HKDF-SHA256, AES-128-GCM in base mode:
+    // https://www.rfc-editor.org/rfc/rfc9180#appendix-A.1.1
+    const INFO: &str = "4f6465206f6e2061204772656369616e2055726e";
+    const SK_RM: &str = "4612c550263fc8ad58375df3f557aac531d26850903e55a9f23f21d8534e8ac8";
+    const ENC: &str = "37fda3567bdbd628e88668c3c8d7e97d1d1253b6d4ea6d44c150f741f1bf4431";
+    const SHARED_SECRET: &str = "fe0e18c9f024ce43799ae393c7e8fe8fce9d218875e8227b0187c04e7d2ea1fc";
+    const KEY: &str = "4531685d41d65f03dc48f6b8302c05b0";
+    const BASE_NONCE: &str = "56d890e5accaaf011cff4b7d";
+    const EXPORTER_SECRET: &str = "45ff1c2e220db587171952c0592d5f5ebe103f1561a2614e38f2ffd47e99e3f8";
+    const PLAINTEXT: &str = "4265617574792069732074727574682c20747275746820626561757479";
+
+    /// Sequence number, nonce, aad and ciphertext of the first encryptions.
+    const ENCRYPTIONS: [(u64, &str, &str, &str); 2] = [
+        (0, "56d890e5accaaf011cff4b7d", "436f756e742d30",
+         "f938558b5d72f1a23810b4be2ab4f84331acc02fc97babc53a52ae8218a355a96d8770ac83d07bea87e13c512a"),
+        (1, "56d890e5accaaf011cff4b7c", "436f756e742d31",
+         "af2d7e9ac9ae7e270f46ba1f975be53c09f8d875bdc8535458c2494e8a6eab251c03d0c22a56b8ca42c2063b84"),
+    ];
+
+    /// Exporter context and the 32 byte secret exported for it.
+    const EXPORTS: [(&str, &str); 3] = [
+        ("", "3853fe2b4035195a573ffc53856e77058e15d9ea064de3e59f4961d0095250ee"),
+        ("00", "2e8f0b54673c7029649d4eb9d5e33bf1872cf76d623ff164ac185da9e88c21a5"),
+        ("54657374436f6e74657874", "e9e43065102c3836401bed8c3c3c75ae46be1639869391d62c61f1ec7af54931"),
+    ];
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn recipient() -> (StaticSecret, Vec<u8>) {
+        let secret = StaticSecret::from(<[u8; 32]>::try_from(hex(SK_RM)).unwrap());
+        let public_key = PublicKey::from(&secret).as_bytes().to_vec();
+
+        (secret, public_key)
+    }
+
+    #[test]
+    fn derives_the_shared_secret_of_the_vector() {
+        let (secret, public_key) = recipient();
+        let dh = secret.diffie_hellman(&PublicKey::from(<[u8; 32]>::try_from(hex(ENC)).unwrap()));
+
+        assert_eq!(extract_and_expand(dh.as_bytes(), &hex(ENC), &public_key), hex(SHARED_SECRET));
+    }
+
+    #[test]
+    fn the_recipient_opens_the_ciphertexts_of_the_vector() {
+        let (secret, _) = recipient();
+
+        let mut context = SenderContext::setup_base_recipient(&secret, &hex(ENC), &hex(INFO)).unwrap();
+
+        for (_, _, aad, ciphertext) in ENCRYPTIONS {
+            assert_eq!(context.open(&hex(aad), &hex(ciphertext)).unwrap(), hex(PLAINTEXT));
+        }
+    }
+
+    #[test]
+    fn key_schedule_matches_the_vector() {
+        let context = SenderContext::key_schedule(&hex(SHARED_SECRET), &hex(INFO)).unwrap();
+
+        assert_eq!(context.base_nonce.to_vec(), hex(BASE_NONCE));
+        assert_eq!(context.exporter_secret, hex(EXPORTER_SECRET));
+        assert_eq!(context.sequence, 0);
+    }
+
+    #[test]
+    fn seals_the_ciphertexts_of_the_vector() {
+        let mut context = SenderContext::key_schedule(&hex(SHARED_SECRET), &hex(INFO)).unwrap();
+
+        for (sequence, _, aad, ciphertext) in ENCRYPTIONS {
+            assert_eq!(context.sequence, sequence);
+            assert_eq!(context.seal(&hex(aad), &hex(PLAINTEXT)).unwrap(), hex(ciphertext));
+        }
+    }
+
+    #[test]
+    fn opens_the_ciphertexts_of_the_vector() {
+        for (_, nonce, aad, ciphertext) in ENCRYPTIONS {
+            assert_eq!(open(&hex(KEY), &hex(nonce), &hex(aad), &hex(ciphertext)).unwrap(), hex(PLAINTEXT));
+        }
+
+        let (_, nonce, aad, ciphertext) = ENCRYPTIONS[0];
+
+        let mut tampered = hex(ciphertext);
+        tampered[0] ^= 1;
+
+        assert!(open(&hex(KEY), &hex(nonce), &hex(aad), &tampered).is_err());
+        assert!(open(&hex(KEY), &hex(nonce), b"other aad", &hex(ciphertext)).is_err());
+        assert!(open(&hex(KEY), &hex(ENCRYPTIONS[1].1), &hex(aad), &hex(ciphertext)).is_err());
+    }
+
+    #[test]
+    fn exports_the_secrets_of_the_vector() {
+        let context = SenderContext::key_schedule(&hex(SHARED_SECRET), &hex(INFO)).unwrap();
+
+        for (exporter_context, secret) in EXPORTS {
+            assert_eq!(context.export(&hex(exporter_context), 32), hex(secret));
+        }
+    }
+
+    #[test]
+    fn the_recipient_opens_what_a_fresh_context_seals() {
+        let (secret, public_key) = recipient();
+
+        let (enc, mut sender) = SenderContext::setup_base(&public_key, b"info").unwrap();
+        let mut recipient = SenderContext::setup_base_recipient(&secret, &enc, b"info").unwrap();
+
+        let ciphertext = sender.seal(b"aad", b"query").unwrap();
+
+        assert_eq!(recipient.open(b"aad", &ciphertext).unwrap(), b"query");
+        assert!(recipient.open(b"aad", &ciphertext).is_err(), "the sequence number moved on");
+        assert_eq!(sender.export(b"odoh response", NK), recipient.export(b"odoh response", NK));
+    }
+
+    #[test]
+    fn refuses_a_low_order_public_key() {
+        assert!(SenderContext::setup_base(&[0; 32], b"info").is_err());
+    }
+}
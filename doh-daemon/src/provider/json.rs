@@ -0,0 +1,37 @@
+use reqwest::Url;
+use tracing::instrument;
+use doh_common::error::Error;
+use crate::client::{expand_url_template, HttpClient};
+use crate::provider::ecs::Subnet;
+use crate::provider::{query_template, DnsRecordType, DnsReply};
+use crate::settings::HttpsSettings;
+
+/// The vendor JSON flavour of DoH (`application/dns-json`), as offered by
+/// Google and Cloudflare, where the question travels as `name` and `type`
+/// query parameters.
+pub struct JsonApi;
+
+impl JsonApi {
+    #[instrument(skip_all)]
+    pub async fn resolve(client: &HttpClient, settings: &HttpsSettings, domain: &str, record_type: DnsRecordType, subnet: Option<&Subnet>) -> Result<DnsReply, Error> {
+
+        let tpe = format!("{}", record_type);
+
+        let url = query_template(settings.url(), "name,type");
+
+        let mut url = expand_url_template(&url, &[
+            ("name", domain),
+            ("type", &tpe),
+        ]);
+
+        // Google's extension, the template of the preset does not mention it
+        if let Some(subnet) = subnet {
+            let mut with_subnet = Url::parse(&url)?;
+            with_subnet.query_pairs_mut().append_pair("edns_client_subnet", &subnet.to_string());
+
+            url = with_subnet.to_string();
+        }
+
+        client.request(&url, settings.headers()).await
+    }
+}
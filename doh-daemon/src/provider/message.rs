@@ -0,0 +1,1128 @@
+use std::convert::TryFrom;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use base64::Engine;
+use tracing::error;
+
+use doh_common::error::Error;
+
+use crate::provider::ecs::Subnet;
+use crate::provider::{DnsEntryReply, DnsRecordType, DnsReply, DnsRequest};
+
+// https://www.rfc-editor.org/rfc/rfc1035#section-4.1.1
+const FLAG_QR: u16 = 0x8000;
+const FLAG_TC: u16 = 0x0200;
+const FLAG_RD: u16 = 0x0100;
+const FLAG_RA: u16 = 0x0080;
+const FLAG_AD: u16 = 0x0020;
+const FLAG_CD: u16 = 0x0010;
+
+const CLASS_IN: u16 = 1;
+
+// https://www.rfc-editor.org/rfc/rfc6891#section-6.1.2
+const TYPE_OPT: u16 = 41;
+// Avoids IP fragmentation, https://www.dnsflagday.net/2020/
+const EDNS_UDP_PAYLOAD: u16 = 1232;
+const OPTION_CLIENT_SUBNET: u16 = 8;
+// https://www.rfc-editor.org/rfc/rfc7830#section-4
+const OPTION_PADDING: u16 = 12;
+const OPTION_HEADER_LEN: usize = 4;
+// https://www.rfc-editor.org/rfc/rfc3225#section-3
+const EDNS_FLAG_DO: u32 = 0x8000;
+
+// https://www.rfc-editor.org/rfc/rfc9460#section-14.3.2
+const SVC_PARAM_KEYS: [&str; 7] = ["mandatory", "alpn", "no-default-alpn", "port", "ipv4hint", "ech", "ipv6hint"];
+
+const HEADER_LEN: usize = 12;
+const MAX_POINTERS: usize = 64;
+// https://www.rfc-editor.org/rfc/rfc1035#section-2.3.4
+const MAX_LABEL_LEN: usize = 63;
+const MAX_NAME_LEN: usize = 255;
+
+/// A DNS message in the binary format of RFC 1035, as carried by
+/// `application/dns-message` (RFC 8484) bodies.
+#[derive(Debug)]
+pub struct Message {
+    pub id: u16,
+    pub flags: u16,
+    pub questions: Vec<Question>,
+    pub answers: Vec<Record>,
+    pub authority: Vec<Record>,
+    pub additional: Vec<Record>,
+}
+
+#[derive(Debug)]
+pub struct Question {
+    pub name: String,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+/// A resource record. Domain names embedded in the record data are stored
+/// uncompressed, so the data stays meaningful outside of its message.
+#[derive(Clone, Debug)]
+pub struct Record {
+    pub name: String,
+    pub rtype: u16,
+    pub class: u16,
+    pub ttl: u32,
+    pub rdata: Vec<u8>,
+}
+
+impl Message {
+    /// Builds a recursive query for a single question. The ID is left at zero,
+    /// as RFC 8484 recommends, so that HTTP caches can reuse the answer. The AD
+    /// bit asks the upstream to report whether it validated the answer (RFC 6840).
+    pub fn query(name: &str, record_type: &DnsRecordType) -> Self {
+        Self::question(name, record_type.as_uint() as u16)
+    }
+
+    /// Same as `query`, for any record type.
+    pub fn question(name: &str, qtype: u16) -> Self {
+        Self {
+            id: 0,
+            flags: FLAG_RD | FLAG_AD,
+            questions: vec![Question {
+                name: name.to_string(),
+                qtype,
+                qclass: CLASS_IN,
+            }],
+            answers: vec![],
+            authority: vec![],
+            additional: vec![],
+        }
+    }
+
+    /// Adds an EDNS(0) option, along with the OPT pseudo-record carrying it
+    /// when the message has none yet.
+    pub fn add_option(&mut self, code: u16, data: &[u8]) {
+        let rdata = &mut self.opt_record().rdata;
+
+        rdata.extend_from_slice(&code.to_be_bytes());
+        rdata.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        rdata.extend_from_slice(data);
+    }
+
+    fn opt_record(&mut self) -> &mut Record {
+        let position = match self.additional.iter().position(|record| record.rtype == TYPE_OPT) {
+            Some(position) => position,
+            None => {
+                self.additional.push(Record {
+                    name: String::from("."),
+                    rtype: TYPE_OPT,
+                    class: EDNS_UDP_PAYLOAD,
+                    ttl: 0,
+                    rdata: vec![],
+                });
+
+                self.additional.len() - 1
+            }
+        };
+
+        &mut self.additional[position]
+    }
+
+    /// Sets the DNSSEC OK bit, asking the upstream to send the signatures and
+    /// denial of existence records along with the answer.
+    pub fn with_dnssec_ok(mut self, enabled: bool) -> Self {
+        if enabled {
+            self.opt_record().ttl |= EDNS_FLAG_DO;
+        }
+
+        self
+    }
+
+    /// Adds the EDNS Client Subnet option (RFC 7871) when there is a subnet to send.
+    pub fn with_client_subnet(mut self, subnet: Option<&Subnet>) -> Self {
+        if let Some(subnet) = subnet {
+            self.add_option(OPTION_CLIENT_SUBNET, &subnet.option_data());
+        }
+
+        self
+    }
+
+    /// Pads the message to a multiple of `block_size` bytes with the Padding
+    /// option (RFC 7830), the block-length strategy of RFC 8467, so that its
+    /// length says little about the name asked. Must come after every other
+    /// option.
+    pub fn with_padding(mut self, block_size: usize) -> Self {
+        if block_size > 0 {
+            self.opt_record();
+
+            // A name too long to encode fails the query anyway
+            let Ok(message) = self.encode() else {
+                return self;
+            };
+
+            let length = message.len() + OPTION_HEADER_LEN;
+
+            self.add_option(OPTION_PADDING, &vec![0; (block_size - length % block_size) % block_size]);
+        }
+
+        self
+    }
+
+    /// Whether the message carries the Padding option.
+    pub fn is_padded(&self) -> bool {
+        self.additional
+            .iter()
+            .filter(|record| record.rtype == TYPE_OPT)
+            .any(|record| {
+                let mut options = record.rdata.as_slice();
+
+                while options.len() >= OPTION_HEADER_LEN {
+                    let code = u16::from_be_bytes([options[0], options[1]]);
+                    let length = u16::from_be_bytes([options[2], options[3]]) as usize;
+
+                    if code == OPTION_PADDING {
+                        return true;
+                    }
+
+                    options = options.get(OPTION_HEADER_LEN + length..).unwrap_or_default();
+                }
+
+                false
+            })
+    }
+
+    pub fn rcode(&self) -> u8 {
+        (self.flags & 0x000F) as u8
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        let mut buffer = Vec::with_capacity(512);
+
+        buffer.extend_from_slice(&self.id.to_be_bytes());
+        buffer.extend_from_slice(&self.flags.to_be_bytes());
+        buffer.extend_from_slice(&(self.questions.len() as u16).to_be_bytes());
+        buffer.extend_from_slice(&(self.answers.len() as u16).to_be_bytes());
+        buffer.extend_from_slice(&(self.authority.len() as u16).to_be_bytes());
+        buffer.extend_from_slice(&(self.additional.len() as u16).to_be_bytes());
+
+        for question in &self.questions {
+            write_name(&mut buffer, &question.name)?;
+            buffer.extend_from_slice(&question.qtype.to_be_bytes());
+            buffer.extend_from_slice(&question.qclass.to_be_bytes());
+        }
+
+        for record in self.answers.iter()
+            .chain(self.authority.iter())
+            .chain(self.additional.iter()) {
+            write_name(&mut buffer, &record.name)?;
+            buffer.extend_from_slice(&record.rtype.to_be_bytes());
+            buffer.extend_from_slice(&record.class.to_be_bytes());
+            buffer.extend_from_slice(&record.ttl.to_be_bytes());
+            buffer.extend_from_slice(&(record.rdata.len() as u16).to_be_bytes());
+            buffer.extend_from_slice(&record.rdata);
+        }
+
+        Ok(buffer)
+    }
+
+    pub fn decode(packet: &[u8]) -> Result<Self, Error> {
+        if packet.len() < HEADER_LEN {
+            error!("DNS message too short: {} bytes", packet.len());
+            return Err(Error::MalformedReply);
+        }
+
+        let mut reader = Reader { packet, position: HEADER_LEN };
+
+        let id = u16::from_be_bytes([packet[0], packet[1]]);
+        let flags = u16::from_be_bytes([packet[2], packet[3]]);
+        let qdcount = u16::from_be_bytes([packet[4], packet[5]]);
+        let ancount = u16::from_be_bytes([packet[6], packet[7]]);
+        let nscount = u16::from_be_bytes([packet[8], packet[9]]);
+        let arcount = u16::from_be_bytes([packet[10], packet[11]]);
+
+        let mut questions = Vec::with_capacity(qdcount as usize);
+
+        for _ in 0..qdcount {
+            let name = reader.name()?;
+            let qtype = reader.u16()?;
+            let qclass = reader.u16()?;
+
+            questions.push(Question { name, qtype, qclass });
+        }
+
+        let answers = reader.records(ancount)?;
+        let authority = reader.records(nscount)?;
+        let additional = reader.records(arcount)?;
+
+        Ok(Self { id, flags, questions, answers, authority, additional })
+    }
+
+    /// Maps the message onto the model used by the JSON APIs. Records of types
+    /// the resolver does not handle are left out.
+    pub fn into_reply(self) -> Result<DnsReply, Error> {
+        if self.flags & FLAG_QR == 0 {
+            error!("DNS message is not a response");
+            return Err(Error::MalformedReply);
+        }
+
+        let mut questions = Vec::with_capacity(self.questions.len());
+
+        for question in &self.questions {
+            match DnsRecordType::try_from(question.qtype as i32) {
+                Ok(r#type) => questions.push(DnsRequest { name: question.name.clone(), r#type }),
+                Err(_) => {
+                    error!("DNS response question has unsupported type {}", question.qtype);
+                    return Err(Error::MalformedReply);
+                }
+            }
+        }
+
+        Ok(DnsReply {
+            status: self.rcode(),
+            tc: self.flags & FLAG_TC != 0,
+            rd: self.flags & FLAG_RD != 0,
+            ra: self.flags & FLAG_RA != 0,
+            ad: self.flags & FLAG_AD != 0,
+            cd: self.flags & FLAG_CD != 0,
+            questions,
+            answers: to_entries(&self.answers),
+            authority: to_entries(&self.authority),
+            message: Some(self),
+        })
+    }
+}
+
+fn to_entries(records: &[Record]) -> Vec<DnsEntryReply> {
+    records.iter()
+        .filter(|record| record.class == CLASS_IN)
+        .filter_map(|record| {
+            let r#type = DnsRecordType::try_from(record.rtype as i32).ok()?;
+            let data = record.data_text()?;
+
+            Some(DnsEntryReply {
+                name: record.name.clone(),
+                r#type,
+                ttl: record.ttl,
+                data,
+            })
+        })
+        .collect()
+}
+
+impl Record {
+    /// Renders the record data the way the JSON APIs do, in zone file
+    /// presentation format.
+    pub fn data_text(&self) -> Option<String> {
+        let rdata = self.rdata.as_slice();
+
+        match self.rtype {
+            1 => {
+                let octets: [u8; 4] = rdata.try_into().ok()?;
+                Some(Ipv4Addr::from(octets).to_string())
+            }
+            28 => {
+                let octets: [u8; 16] = rdata.try_into().ok()?;
+                Some(Ipv6Addr::from(octets).to_string())
+            }
+            2 | 5 | 12 => {
+                let (name, _) = read_uncompressed_name(rdata, 0)?;
+                Some(name)
+            }
+            6 => {
+                let (mname, position) = read_uncompressed_name(rdata, 0)?;
+                let (rname, position) = read_uncompressed_name(rdata, position)?;
+                let numbers = rdata.get(position..position + 20)?;
+
+                let values: Vec<String> = numbers
+                    .chunks(4)
+                    .map(|chunk| u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]).to_string())
+                    .collect();
+
+                Some(format!("{} {} {}", mname, rname, values.join(" ")))
+            }
+            15 => {
+                let preference = u16::from_be_bytes([*rdata.first()?, *rdata.get(1)?]);
+                let (exchange, _) = read_uncompressed_name(rdata, 2)?;
+
+                Some(format!("{} {}", preference, exchange))
+            }
+            16 => {
+                let mut strings = vec![];
+                let mut position = 0;
+
+                while position < rdata.len() {
+                    let length = rdata[position] as usize;
+                    strings.push(quoted(rdata.get(position + 1..position + 1 + length)?));
+                    position += 1 + length;
+                }
+
+                Some(strings.join(" "))
+            }
+            33 => {
+                let numbers = rdata.get(0..6)?;
+                let (target, _) = read_uncompressed_name(rdata, 6)?;
+
+                let values: Vec<String> = numbers
+                    .chunks(2)
+                    .map(|chunk| u16::from_be_bytes([chunk[0], chunk[1]]).to_string())
+                    .collect();
+
+                Some(format!("{} {}", values.join(" "), target))
+            }
+            257 => {
+                let flags = *rdata.first()?;
+                let length = *rdata.get(1)? as usize;
+                let tag = rdata.get(2..2 + length)?;
+                let value = rdata.get(2 + length..)?;
+
+                Some(format!("{} {} {}", flags, String::from_utf8_lossy(tag), quoted(value)))
+            }
+            64 | 65 => svcb_text(rdata),
+            _ => None,
+        }
+    }
+}
+
+struct Reader<'a> {
+    packet: &'a [u8],
+    position: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, length: usize) -> Result<&[u8], Error> {
+        let end = self.position + length;
+
+        if end > self.packet.len() {
+            error!("DNS message truncated at offset {}", self.position);
+            return Err(Error::MalformedReply);
+        }
+
+        let slice = &self.packet[self.position..end];
+        self.position = end;
+
+        Ok(slice)
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn name(&mut self) -> Result<String, Error> {
+        let (name, position) = read_name(self.packet, self.position)?;
+        self.position = position;
+        Ok(name)
+    }
+
+    fn records(&mut self, count: u16) -> Result<Vec<Record>, Error> {
+        let mut records = Vec::with_capacity(count as usize);
+
+        for _ in 0..count {
+            let name = self.name()?;
+            let rtype = self.u16()?;
+            let class = self.u16()?;
+            let ttl = self.u32()?;
+            let length = self.u16()? as usize;
+
+            let start = self.position;
+            self.take(length)?;
+
+            let rdata = expand_rdata(self.packet, start, length, rtype)?;
+
+            records.push(Record { name, rtype, class, ttl, rdata });
+        }
+
+        Ok(records)
+    }
+}
+
+/// Rewrites compressed names inside the record data of the well-known types
+/// (RFC 3597 section 4) into their uncompressed form.
+fn expand_rdata(packet: &[u8], start: usize, length: usize, rtype: u16) -> Result<Vec<u8>, Error> {
+    let end = start + length;
+
+    let mut rdata = Vec::with_capacity(length);
+
+    match rtype {
+        // CNAME, NS, PTR
+        5 | 2 | 12 => {
+            let (name, _) = read_name(packet, start)?;
+            write_name(&mut rdata, &name)?;
+        }
+        // SOA
+        6 => {
+            let (mname, position) = read_name(packet, start)?;
+            let (rname, position) = read_name(packet, position)?;
+            write_name(&mut rdata, &mname)?;
+            write_name(&mut rdata, &rname)?;
+
+            match packet.get(position..end) {
+                Some(rest) => rdata.extend_from_slice(rest),
+                None => return Err(Error::MalformedReply),
+            }
+        }
+        // MX
+        15 => {
+            match packet.get(start..start + 2) {
+                Some(preference) => rdata.extend_from_slice(preference),
+                None => return Err(Error::MalformedReply),
+            }
+
+            let (exchange, _) = read_name(packet, start + 2)?;
+            write_name(&mut rdata, &exchange)?;
+        }
+        // SRV
+        33 => {
+            match packet.get(start..start + 6) {
+                Some(numbers) => rdata.extend_from_slice(numbers),
+                None => return Err(Error::MalformedReply),
+            }
+
+            let (target, _) = read_name(packet, start + 6)?;
+            write_name(&mut rdata, &target)?;
+        }
+        // SVCB, HTTPS
+        64 | 65 => {
+            match packet.get(start..start + 2) {
+                Some(priority) => rdata.extend_from_slice(priority),
+                None => return Err(Error::MalformedReply),
+            }
+
+            let (target, position) = read_name(packet, start + 2)?;
+            write_name(&mut rdata, &target)?;
+
+            match packet.get(position..end) {
+                Some(params) => rdata.extend_from_slice(params),
+                None => return Err(Error::MalformedReply),
+            }
+        }
+        _ => rdata.extend_from_slice(&packet[start..end]),
+    }
+
+    Ok(rdata)
+}
+
+/// Formats the data of a SVCB or HTTPS record in the presentation format of
+/// RFC 9460, section 2.1, e.g. `1 . alpn="h2,h3" ipv4hint=192.0.2.1`. None
+/// when the record is malformed, which clients are to ignore it for.
+pub fn svcb_text(rdata: &[u8]) -> Option<String> {
+    let priority = u16::from_be_bytes([*rdata.first()?, *rdata.get(1)?]);
+    let (target, mut position) = read_uncompressed_name(rdata, 2)?;
+
+    let mut text = format!("{} {}", priority, target);
+    let mut keys: Vec<u16> = vec![];
+    let mut mandatory: Vec<u16> = vec![];
+
+    while position < rdata.len() {
+        let key = u16::from_be_bytes([*rdata.get(position)?, *rdata.get(position + 1)?]);
+        let length = u16::from_be_bytes([*rdata.get(position + 2)?, *rdata.get(position + 3)?]) as usize;
+        let value = rdata.get(position + 4..position + 4 + length)?;
+        position += 4 + length;
+
+        // In strictly increasing order, so each key at most once (section 2.2)
+        if keys.last().is_some_and(|last| key <= *last) {
+            return None;
+        }
+
+        keys.push(key);
+
+        // Only no-default-alpn goes without a value among the keys known
+        if value.is_empty() && matches!(key, 0 | 1 | 3 | 4 | 6) {
+            return None;
+        }
+
+        text.push(' ');
+        text.push_str(&svc_param_key(key));
+
+        let value = match key {
+            0 => {
+                if value.len() % 2 != 0 {
+                    return None;
+                }
+
+                mandatory = value.chunks(2).map(|chunk| u16::from_be_bytes([chunk[0], chunk[1]])).collect();
+
+                // Sorted as well, and never mandatory itself (section 8)
+                if mandatory[0] == 0 || mandatory.windows(2).any(|pair| pair[0] >= pair[1]) {
+                    return None;
+                }
+
+                mandatory
+                    .iter()
+                    .map(|key| svc_param_key(*key))
+                    .collect::<Vec<String>>()
+                    .join(",")
+            }
+            1 => {
+                let mut ids = vec![];
+                let mut offset = 0;
+
+                while offset < value.len() {
+                    let length = value[offset] as usize;
+                    let id = String::from_utf8_lossy(value.get(offset + 1..offset + 1 + length)?)
+                        .replace('\\', "\\\\")
+                        .replace(',', "\\,");
+                    ids.push(id);
+                    offset += 1 + length;
+                }
+
+                quoted(ids.join(",").as_bytes())
+            }
+            2 if value.is_empty() => continue,
+            2 => return None,
+            3 => {
+                let port: [u8; 2] = value.try_into().ok()?;
+                u16::from_be_bytes(port).to_string()
+            }
+            4 => {
+                if value.len() % 4 != 0 {
+                    return None;
+                }
+
+                value
+                    .chunks(4)
+                    .map(|chunk| Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]).to_string())
+                    .collect::<Vec<String>>()
+                    .join(",")
+            }
+            5 => base64::engine::general_purpose::STANDARD.encode(value),
+            6 => {
+                if value.len() % 16 != 0 {
+                    return None;
+                }
+
+                value
+                    .chunks(16)
+                    .map(|chunk| Ipv6Addr::from(<[u8; 16]>::try_from(chunk).unwrap()).to_string())
+                    .collect::<Vec<String>>()
+                    .join(",")
+            }
+            _ => quoted(value),
+        };
+
+        text.push('=');
+        text.push_str(&value);
+    }
+
+    // The keys said to be mandatory have to be there
+    if mandatory.iter().any(|key| !keys.contains(key)) {
+        return None;
+    }
+
+    Some(text)
+}
+
+fn svc_param_key(key: u16) -> String {
+    match SVC_PARAM_KEYS.get(key as usize) {
+        Some(name) => name.to_string(),
+        None => format!("key{}", key),
+    }
+}
+
+/// Reads a possibly compressed domain name and returns it in presentation
+/// format together with the offset right after it.
+fn read_name(packet: &[u8], start: usize) -> Result<(String, usize), Error> {
+    let mut labels: Vec<String> = vec![];
+    let mut position = start;
+    let mut resume_at = None;
+    let mut jumps = 0;
+    let mut wire_length = 1;
+
+    loop {
+        let length = *packet.get(position).ok_or(Error::MalformedReply)? as usize;
+
+        match length & 0xC0 {
+            0x00 if length == 0 => {
+                position += 1;
+                break;
+            }
+            0x00 => {
+                let label = packet
+                    .get(position + 1..position + 1 + length)
+                    .ok_or(Error::MalformedReply)?;
+
+                wire_length += 1 + length;
+                if wire_length > MAX_NAME_LEN {
+                    error!("DNS message has a name longer than {} octets", MAX_NAME_LEN);
+                    return Err(Error::MalformedReply);
+                }
+
+                labels.push(escape_label(label));
+                position += 1 + length;
+            }
+            0xC0 => {
+                let low = *packet.get(position + 1).ok_or(Error::MalformedReply)? as usize;
+
+                jumps += 1;
+                if jumps > MAX_POINTERS {
+                    error!("DNS message has a compression loop");
+                    return Err(Error::MalformedReply);
+                }
+
+                if resume_at.is_none() {
+                    resume_at = Some(position + 2);
+                }
+
+                position = ((length & 0x3F) << 8) | low;
+            }
+            _ => {
+                error!("DNS message has an unsupported label type");
+                return Err(Error::MalformedReply);
+            }
+        }
+    }
+
+    let name = if labels.is_empty() {
+        String::from(".")
+    } else {
+        format!("{}.", labels.join("."))
+    };
+
+    Ok((name, resume_at.unwrap_or(position)))
+}
+
+pub fn read_uncompressed_name(rdata: &[u8], start: usize) -> Option<(String, usize)> {
+    let mut labels = vec![];
+    let mut position = start;
+
+    loop {
+        let length = *rdata.get(position)? as usize;
+        position += 1;
+
+        if length == 0 {
+            break;
+        }
+
+        if length & 0xC0 != 0 {
+            return None;
+        }
+
+        labels.push(escape_label(rdata.get(position..position + length)?));
+        position += length;
+    }
+
+    if labels.is_empty() {
+        Some((String::from("."), position))
+    } else {
+        Some((format!("{}.", labels.join(".")), position))
+    }
+}
+
+fn escape_label(label: &[u8]) -> String {
+    let mut text = String::with_capacity(label.len());
+
+    for &byte in label {
+        match byte {
+            b'.' | b'\\' => {
+                text.push('\\');
+                text.push(byte as char);
+            }
+            0x21..=0x7E => text.push(byte as char),
+            _ => text.push_str(&format!("\\{:03}", byte)),
+        }
+    }
+
+    text
+}
+
+/// Formats a character-string (RFC 1035, section 5.1) between quotes.
+fn quoted(string: &[u8]) -> String {
+    let mut text = String::with_capacity(string.len() + 2);
+    text.push('"');
+
+    for &byte in string {
+        match byte {
+            b'"' | b'\\' => {
+                text.push('\\');
+                text.push(byte as char);
+            }
+            0x20..=0x7E => text.push(byte as char),
+            _ => text.push_str(&format!("\\{:03}", byte)),
+        }
+    }
+
+    text.push('"');
+    text
+}
+
+/// Writes a domain name, given in presentation format, without compression.
+/// Refuses labels and names longer than DNS allows, which would otherwise
+/// come out as a different name.
+pub fn write_name(buffer: &mut Vec<u8>, name: &str) -> Result<(), Error> {
+    let start = buffer.len();
+    let mut label: Vec<u8> = Vec::with_capacity(MAX_LABEL_LEN);
+    let mut chars = name.bytes().peekable();
+
+    while let Some(byte) = chars.next() {
+        match byte {
+            b'.' => {
+                if !label.is_empty() {
+                    write_label(buffer, &mut label)?;
+                }
+            }
+            b'\\' => {
+                let digits: Vec<u8> = (0..3)
+                    .map_while(|_| chars.next_if(|c| c.is_ascii_digit()))
+                    .collect();
+
+                if digits.len() == 3 {
+                    let value = digits.iter().fold(0u32, |acc, d| acc * 10 + (d - b'0') as u32);
+                    label.push(value.min(255) as u8);
+                } else if !digits.is_empty() {
+                    label.extend(digits);
+                } else if let Some(escaped) = chars.next() {
+                    label.push(escaped);
+                }
+            }
+            _ => label.push(byte),
+        }
+    }
+
+    if !label.is_empty() {
+        write_label(buffer, &mut label)?;
+    }
+
+    buffer.push(0);
+
+    if buffer.len() - start > MAX_NAME_LEN {
+        buffer.truncate(start);
+        return Err(Error::InvalidName);
+    }
+
+    Ok(())
+}
+
+fn write_label(buffer: &mut Vec<u8>, label: &mut Vec<u8>) -> Result<(), Error> {
+    if label.len() > MAX_LABEL_LEN {
+        return Err(Error::InvalidName);
+    }
+
+    buffer.push(label.len() as u8);
+    buffer.append(label);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(answers: u16) -> Vec<u8> {
+        let mut packet = vec![0x12, 0x34, 0x81, 0x80, 0, 1];
+        packet.extend_from_slice(&answers.to_be_bytes());
+        packet.extend_from_slice(&[0, 0, 0, 0]);
+        packet
+    }
+
+    #[test]
+    fn query_round_trips() {
+        let query = Message::query("www.example.com", &DnsRecordType::AAAA).with_dnssec_ok(true);
+
+        let decoded = Message::decode(&query.encode().unwrap()).unwrap();
+
+        assert_eq!(decoded.id, 0);
+        assert_eq!(decoded.flags, FLAG_RD | FLAG_AD);
+        assert_eq!(decoded.questions.len(), 1);
+        assert_eq!(decoded.questions[0].name, "www.example.com.");
+        assert_eq!(decoded.questions[0].qtype, 28);
+        assert_eq!(decoded.questions[0].qclass, CLASS_IN);
+        assert_eq!(decoded.additional.len(), 1);
+        assert_eq!(decoded.additional[0].rtype, TYPE_OPT);
+        assert_eq!(decoded.additional[0].ttl & EDNS_FLAG_DO, EDNS_FLAG_DO);
+    }
+
+    #[test]
+    fn escaped_labels_round_trip() {
+        let mut wire = vec![];
+        write_name(&mut wire, "a\\.b.c\\032d.example.").unwrap();
+
+        assert_eq!(wire, b"\x03a.b\x03c d\x07example\x00");
+        assert_eq!(read_uncompressed_name(&wire, 0), Some((String::from("a\\.b.c\\032d.example."), wire.len())));
+    }
+
+    #[test]
+    fn root_is_a_single_octet() {
+        let mut wire = vec![];
+        write_name(&mut wire, ".").unwrap();
+
+        assert_eq!(wire, vec![0]);
+        assert_eq!(read_name(&wire, 0).unwrap(), (String::from("."), 1));
+    }
+
+    #[test]
+    fn refuses_a_label_over_63_octets() {
+        let mut wire = vec![];
+
+        assert!(matches!(write_name(&mut wire, &format!("{}.com", "a".repeat(63))), Ok(())));
+        assert!(matches!(write_name(&mut wire, &format!("{}.com", "a".repeat(64))), Err(Error::InvalidName)));
+        assert!(matches!(Message::query(&"b".repeat(64), &DnsRecordType::A).encode(), Err(Error::InvalidName)));
+    }
+
+    #[test]
+    fn refuses_a_name_over_255_octets() {
+        // 4 labels of 63 octets take 256 octets with their lengths, 257 with the root
+        let longest = ["a".repeat(63), "b".repeat(63), "c".repeat(63), "d".repeat(61)].join(".");
+        let too_long = ["a".repeat(63), "b".repeat(63), "c".repeat(63), "d".repeat(62)].join(".");
+
+        let mut wire = vec![7];
+        write_name(&mut wire, &longest).unwrap();
+        assert_eq!(wire.len(), 1 + 255);
+
+        let mut wire = vec![7];
+        assert!(matches!(write_name(&mut wire, &too_long), Err(Error::InvalidName)));
+        assert_eq!(wire, vec![7]);
+    }
+
+    #[test]
+    fn follows_compression_pointers() {
+        let mut packet = header(2);
+        // Question at offset 12
+        packet.extend_from_slice(b"\x03www\x07example\x03com\x00\x00\x05\x00\x01");
+        // www.example.com CNAME cdn.example.com, pointing into the question
+        packet.extend_from_slice(&[0xC0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 6]);
+        packet.extend_from_slice(&[3, b'c', b'd', b'n', 0xC0, 16]);
+        let cname_target = packet.len() - 6;
+        // cdn.example.com A 192.0.2.1, named by a pointer to a pointer
+        packet.extend_from_slice(&[0xC0, cname_target as u8, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 1]);
+
+        let message = Message::decode(&packet).unwrap();
+
+        assert_eq!(message.answers[0].name, "www.example.com.");
+        assert_eq!(message.answers[0].rdata, b"\x03cdn\x07example\x03com\x00");
+        assert_eq!(message.answers[0].data_text().unwrap(), "cdn.example.com.");
+        assert_eq!(message.answers[1].name, "cdn.example.com.");
+        assert_eq!(message.answers[1].data_text().unwrap(), "192.0.2.1");
+
+        let reply = message.into_reply().unwrap();
+        assert_eq!(reply.answers.len(), 2);
+    }
+
+    #[test]
+    fn expands_compressed_names_in_record_data() {
+        let mut packet = header(1);
+        packet.extend_from_slice(b"\x07example\x03com\x00\x00\x0f\x00\x01");
+        // example.com MX 10 mail.example.com
+        packet.extend_from_slice(&[0xC0, 12, 0, 15, 0, 1, 0, 0, 0, 60, 0, 9, 0, 10]);
+        packet.extend_from_slice(&[4, b'm', b'a', b'i', b'l', 0xC0, 12]);
+
+        let message = Message::decode(&packet).unwrap();
+
+        assert_eq!(message.answers[0].data_text().unwrap(), "10 mail.example.com.");
+    }
+
+    #[test]
+    fn rejects_pointer_loops() {
+        let mut packet = header(0);
+        // A question whose name points to itself
+        packet.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1]);
+
+        assert!(matches!(Message::decode(&packet), Err(Error::MalformedReply)));
+
+        // Two pointers pointing to each other
+        let mut packet = header(0);
+        packet.extend_from_slice(&[0xC0, 14, 0xC0, 12, 0, 1, 0, 1]);
+
+        assert!(matches!(Message::decode(&packet), Err(Error::MalformedReply)));
+    }
+
+    #[test]
+    fn rejects_truncated_messages() {
+        let mut packet = header(1);
+        packet.extend_from_slice(b"\x07example\x03com\x00\x00\x01\x00\x01");
+        packet.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0]);
+
+        assert!(matches!(Message::decode(&packet), Err(Error::MalformedReply)));
+        assert!(matches!(Message::decode(&packet[..5]), Err(Error::MalformedReply)));
+    }
+
+    #[test]
+    fn rejects_decoded_names_over_255_octets() {
+        let mut packet = header(0);
+
+        for _ in 0..5 {
+            packet.push(63);
+            packet.extend_from_slice(&[b'a'; 63]);
+        }
+        packet.extend_from_slice(&[0, 0, 1, 0, 1]);
+
+        assert!(matches!(Message::decode(&packet), Err(Error::MalformedReply)));
+    }
+
+    const FOO_EXAMPLE_COM: &[u8] = b"\x03foo\x07example\x03com\x00";
+    const FOO_EXAMPLE_ORG: &[u8] = b"\x03foo\x07example\x03org\x00";
+
+    fn svcb(priority: u16, target: &[u8], params: &[u8]) -> Vec<u8> {
+        [&priority.to_be_bytes()[..], target, params].concat()
+    }
+
+    // The wire format examples of RFC 9460, appendix D
+
+    // https://www.rfc-editor.org/rfc/rfc8467#section-4.1: queries padded to a
+    // multiple of 128 octets
+    #[test]
+    fn pads_queries_to_a_multiple_of_the_block_size() {
+        let subnet = Subnet::new("192.0.2.1".parse().unwrap(), 24);
+
+        for name in ["a.test", "www.example.com", "a-rather-long-label-for-a-host.in.a.deep.zone.example.org"] {
+            for block_size in [16, 128, 468] {
+                let queries = [
+                    Message::query(name, &DnsRecordType::A).with_padding(block_size),
+                    Message::query(name, &DnsRecordType::AAAA).with_dnssec_ok(true).with_padding(block_size),
+                    Message::query(name, &DnsRecordType::A).with_client_subnet(Some(&subnet)).with_padding(block_size),
+                ];
+
+                for query in queries {
+                    let wire = query.encode().unwrap();
+
+                    assert_eq!(wire.len() % block_size, 0, "{} in blocks of {}", name, block_size);
+                    assert!(Message::decode(&wire).unwrap().is_padded());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn an_aligned_query_gets_an_empty_padding_option() {
+        let mut unpadded = Message::query("www.example.com", &DnsRecordType::A);
+        unpadded.add_option(OPTION_PADDING, &[]);
+
+        // Exactly one block once the option header is counted
+        let block_size = unpadded.encode().unwrap().len();
+
+        let query = Message::query("www.example.com", &DnsRecordType::A).with_padding(block_size);
+
+        assert_eq!(query.encode().unwrap().len(), block_size);
+        assert!(query.is_padded());
+    }
+
+    #[test]
+    fn tells_a_query_without_padding() {
+        assert!(!Message::query("www.example.com", &DnsRecordType::A).is_padded());
+        assert!(!Message::query("www.example.com", &DnsRecordType::A).with_padding(0).is_padded());
+        assert!(!Message::query("www.example.com", &DnsRecordType::A).with_dnssec_ok(true).is_padded());
+
+        // An OPT record with other options only
+        let subnet = Subnet::new("192.0.2.1".parse().unwrap(), 24);
+        assert!(!Message::query("www.example.com", &DnsRecordType::A).with_client_subnet(Some(&subnet)).is_padded());
+
+        // Padding after another option
+        assert!(Message::query("www.example.com", &DnsRecordType::A).with_client_subnet(Some(&subnet)).with_padding(128).is_padded());
+    }
+
+    #[test]
+    fn formats_an_alias() {
+        assert_eq!(svcb_text(&svcb(0, FOO_EXAMPLE_COM, &[])).unwrap(), "0 foo.example.com.");
+    }
+
+    #[test]
+    fn formats_a_service_at_the_owner_name() {
+        assert_eq!(svcb_text(&svcb(1, b"\x00", &[])).unwrap(), "1 .");
+    }
+
+    #[test]
+    fn formats_a_port() {
+        let rdata = svcb(16, FOO_EXAMPLE_COM, &[0x00, 0x03, 0x00, 0x02, 0x00, 0x35]);
+
+        assert_eq!(svcb_text(&rdata).unwrap(), "16 foo.example.com. port=53");
+    }
+
+    #[test]
+    fn formats_unknown_keys_as_quoted_strings() {
+        let rdata = svcb(1, FOO_EXAMPLE_COM, b"\x02\x9b\x00\x05hello");
+        let escaped = svcb(1, FOO_EXAMPLE_COM, b"\x02\x9b\x00\x09hello\xd2qoo");
+
+        assert_eq!(svcb_text(&rdata).unwrap(), r#"1 foo.example.com. key667="hello""#);
+        assert_eq!(svcb_text(&escaped).unwrap(), r#"1 foo.example.com. key667="hello\210qoo""#);
+    }
+
+    #[test]
+    fn formats_ipv6_hints() {
+        let rdata = svcb(1, FOO_EXAMPLE_COM, &[
+            0x00, 0x06, 0x00, 0x20,
+            0x20, 0x01, 0x0d, 0xb8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+            0x20, 0x01, 0x0d, 0xb8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x53, 0x00, 0x01,
+        ]);
+
+        // 2001:db8:122:344::192.0.2.33, written without the embedded IPv4 address
+        let embedded = svcb(1, b"\x07example\x03com\x00", &[
+            0x00, 0x06, 0x00, 0x10,
+            0x20, 0x01, 0x0d, 0xb8, 0x01, 0x22, 0x03, 0x44, 0x00, 0x00, 0x00, 0x00, 0xc0, 0x00, 0x02, 0x21,
+        ]);
+
+        assert_eq!(svcb_text(&rdata).unwrap(), "1 foo.example.com. ipv6hint=2001:db8::1,2001:db8::53:1");
+        assert_eq!(svcb_text(&embedded).unwrap(), "1 example.com. ipv6hint=2001:db8:122:344::c000:221");
+    }
+
+    #[test]
+    fn formats_mandatory_keys_alpn_and_ipv4_hints() {
+        let rdata = svcb(16, FOO_EXAMPLE_ORG, &[
+            0x00, 0x00, 0x00, 0x04, 0x00, 0x01, 0x00, 0x04,
+            0x00, 0x01, 0x00, 0x09, 0x02, b'h', b'2', 0x05, b'h', b'3', b'-', b'1', b'9',
+            0x00, 0x04, 0x00, 0x04, 0xc0, 0x00, 0x02, 0x01,
+        ]);
+
+        assert_eq!(svcb_text(&rdata).unwrap(), r#"16 foo.example.org. mandatory=alpn,ipv4hint alpn="h2,h3-19" ipv4hint=192.0.2.1"#);
+    }
+
+    #[test]
+    fn escapes_commas_and_backslashes_of_alpn_ids() {
+        let rdata = svcb(16, FOO_EXAMPLE_ORG, b"\x00\x01\x00\x0c\x08f\\oo,bar\x02h2");
+
+        assert_eq!(svcb_text(&rdata).unwrap(), r#"16 foo.example.org. alpn="f\\\\oo\\,bar,h2""#);
+    }
+
+    #[test]
+    fn formats_no_default_alpn_without_a_value() {
+        let rdata = svcb(1, b"\x00", b"\x00\x01\x00\x03\x02h2\x00\x02\x00\x00");
+
+        assert_eq!(svcb_text(&rdata).unwrap(), r#"1 . alpn="h2" no-default-alpn"#);
+    }
+
+    #[test]
+    fn refuses_keys_out_of_order() {
+        let swapped = svcb(16, FOO_EXAMPLE_ORG, &[
+            0x00, 0x04, 0x00, 0x04, 0xc0, 0x00, 0x02, 0x01,
+            0x00, 0x01, 0x00, 0x03, 0x02, b'h', b'2',
+        ]);
+
+        let repeated = svcb(16, FOO_EXAMPLE_ORG, &[0x00, 0x03, 0x00, 0x02, 0x00, 0x35, 0x00, 0x03, 0x00, 0x02, 0x01, 0xbb]);
+
+        assert_eq!(svcb_text(&swapped), None);
+        assert_eq!(svcb_text(&repeated), None);
+    }
+
+    #[test]
+    fn refuses_malformed_params() {
+        let malformed: [&[u8]; 10] = [
+            // Longer than the data left
+            &[0x00, 0x03, 0x00, 0x05, 0x00, 0x35],
+            // Cut in the middle of a key
+            &[0x00],
+            // Values that can't be empty
+            &[0x00, 0x01, 0x00, 0x00],
+            &[0x00, 0x04, 0x00, 0x00],
+            // A value where there must be none
+            b"\x00\x02\x00\x03abc",
+            // Not a port, an IPv4 address or IPv6 addresses
+            &[0x00, 0x03, 0x00, 0x03, 0x00, 0x00, 0x35],
+            &[0x00, 0x04, 0x00, 0x05, 0xc0, 0x00, 0x02, 0x01, 0x02],
+            &[0x00, 0x06, 0x00, 0x0f, 0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            // mandatory listing itself, then a key that is not there
+            &[0x00, 0x00, 0x00, 0x02, 0x00, 0x00],
+            &[0x00, 0x00, 0x00, 0x02, 0x00, 0x7b],
+        ];
+
+        for params in malformed {
+            assert_eq!(svcb_text(&svcb(1, FOO_EXAMPLE_COM, params)), None, "{:02x?}", params);
+        }
+
+        // Its keys out of order
+        let unsorted = svcb(16, FOO_EXAMPLE_ORG, &[
+            0x00, 0x00, 0x00, 0x04, 0x00, 0x04, 0x00, 0x01,
+            0x00, 0x01, 0x00, 0x03, 0x02, b'h', b'2',
+            0x00, 0x04, 0x00, 0x04, 0xc0, 0x00, 0x02, 0x01,
+        ]);
+
+        assert_eq!(svcb_text(&unsorted), None);
+    }
+}
+
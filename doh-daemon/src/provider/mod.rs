@@ -1,34 +1,144 @@
 use std::cmp::PartialEq;
 use std::convert::TryFrom;
 use std::fmt::{Display, Formatter};
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
 
-use tracing::{error, debug, instrument};
+use futures::stream::{FuturesUnordered, StreamExt};
+use ring::rand::SystemRandom;
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{error, debug, instrument, warn};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde::de::Error;
 
 use libnss::host::{Addresses, AddressFamily, Host};
 
-use crate::database::DatabaseService;
-use crate::provider::cloudflare::CloudFlare;
-use crate::provider::google::Google;
-use crate::settings::{ApplicationSettings, Provider};
+use crate::database::{AnswerEntry, AuditEntry, DatabaseService};
+use crate::provider::classic::ClassicDns;
+use crate::provider::dnssec::{Validation, Validator};
+use crate::provider::health::Attempt;
+use crate::provider::message::Message;
+use crate::provider::singleflight::SingleFlight;
+use crate::provider::upstream::Upstream;
+use crate::settings::{ApplicationSettings, DnssecSettings, ForwardRule, Strategy, TTlConfig};
 use crate::sysinfo::get_process_name;
 
-mod cloudflare;
-mod google;
+mod classic;
+mod dnssec;
+mod dot;
+mod ecs;
+mod health;
+mod hpke;
+mod json;
+mod message;
+mod odoh;
+mod records;
+mod rfc8484;
+mod service;
+mod singleflight;
+mod upstream;
 
 
 #[derive(Debug)]
 pub struct Resolver {
     database: DatabaseService,
-    settings: ApplicationSettings,
+    upstreams: Vec<Upstream>,
+    // Upstreams only reached through forwarding rules.
+    forwarders: Vec<Upstream>,
+    forward_rules: RwLock<Vec<ForwardRule>>,
+    strategy: Strategy,
+    dnssec: DnssecSettings,
+    // Local DNSSEC validation, only when enabled.
+    validator: Option<Validator>,
+    // Classic DNS, only when the fallback is enabled.
+    fallback: Option<ClassicDns>,
+    events: UnboundedSender<ResolverEvent>,
+    // Lookups in progress, shared by the callers asking for the same name and family.
+    flights: SingleFlight<(String, u32), Result<Arc<Resolution>, doh_common::error::Error>>,
+    queries: AtomicU64,
+    max_cname_chain: usize,
+    // How long fresh answers are kept, and reported to be.
+    ttl: TTlConfig,
+    // Weighted selection among the targets of a service.
+    random: SystemRandom,
+}
+
+/// Something users should be told about, published as a D-Bus signal.
+#[derive(Debug)]
+pub enum ResolverEvent {
+    /// No upstream answered and the name was resolved over classic DNS.
+    PrivacyDowngraded {
+        process_id: u32,
+        name: String,
+        nameserver: String,
+    },
+    /// The certificate of an upstream matched none of its pinned keys.
+    PinMismatch {
+        upstream: String,
+    },
+}
+
+/// An answer together with how it was obtained.
+#[derive(Debug)]
+struct Resolution {
+    reply: DnsReply,
+    // Upstream that answered, none when the answer came from the cache.
+    upstream: Option<String>,
+    // Whether the answer, or one along its CNAME chain, came over classic DNS.
+    downgraded: bool,
+    // Whether every answer along the CNAME chain was validated with DNSSEC.
+    authenticated: bool,
+    // Seconds until the answer, or one along its CNAME chain, expires.
+    ttl: u32,
+    // Aliases followed from the name asked about to the owner of the answer.
+    chain: Vec<String>,
+    // Whether the whole answer came from the cache, which then already holds it.
+    cached: bool,
 }
 
 impl Resolver {
-    pub fn new(database: DatabaseService, settings: ApplicationSettings) -> Self {
-        Self { database, settings }
+    pub fn new(database: DatabaseService,
+               settings: ApplicationSettings,
+               events: UnboundedSender<ResolverEvent>) -> Result<Self, doh_common::error::Error> {
+        let validate = settings.dnssec().validate();
+
+        let upstreams = settings
+            .upstreams()
+            .iter()
+            .map(|upstream| Upstream::new(upstream.clone(), settings.health(), settings.retry(), validate, events.clone()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let forwarders = settings
+            .forwarders()
+            .iter()
+            .map(|upstream| Upstream::new(upstream.clone(), settings.health(), settings.retry(), validate, events.clone()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let validator = validate.then(|| Validator::new(database.clone(), settings.dnssec()));
+
+        let fallback = settings
+            .fallback()
+            .enabled()
+            .then(|| ClassicDns::fallback(settings.fallback()));
+
+        Ok(Self {
+            database,
+            upstreams,
+            forwarders,
+            forward_rules: RwLock::new(settings.forward_rules().to_vec()),
+            strategy: settings.strategy().clone(),
+            dnssec: settings.dnssec().clone(),
+            validator,
+            fallback,
+            events,
+            flights: SingleFlight::new(),
+            queries: AtomicU64::new(0),
+            max_cname_chain: settings.max_cname_chain(),
+            ttl: settings.ttl().clone(),
+            random: SystemRandom::new(),
+        })
     }
 
 
@@ -37,50 +147,180 @@ impl Resolver {
                          process_id: u32,
                          domain: &str,
                          family: u32) -> Result<Host, doh_common::error::Error> {
-        let db = self.database.clone();
-        let domain1 = domain.to_string().clone();
-        let family1 = family.clone();
+        let resolution = self.lookup(process_id, domain, family).await?;
 
-        tokio::spawn(async move {
+        resolution.reply
+            .resolved_host(domain, resolution.ttl, resolution.chain.clone())
+            .ok_or(doh_common::error::Error::EmptyDNSReply)
+    }
 
-            let _ = tokio::spawn(async move {
+    /// Resolves the name an address points back to, from its PTR records.
+    #[instrument(name = "resolve_address", skip_all)]
+    pub async fn resolve_address(&self, process_id: u32, address: IpAddr) -> Result<Host, doh_common::error::Error> {
+        let resolution = self.lookup(process_id, &reverse_name(address), DnsRecordType::PTR.as_uint()).await?;
 
-                    let process_name = get_process_name(process_id).ok().unwrap_or(String::from("unknown"));
+        resolution.reply
+            .pointer_host(address, resolution.ttl)
+            .ok_or(doh_common::error::Error::EmptyDNSReply)
+    }
 
-                    if let Err(e) = db.create_dns_audit(&process_name, domain1.as_ref(), family1).await {
-                        error!("Error saving DNS audit: {:?}", e);
-                    }
+    /// Looks up the records of a type other than addresses, such as MX or TXT,
+    /// following CNAMEs like any other lookup.
+    #[instrument(name = "query_records", skip_all)]
+    pub async fn query_records(&self,
+                               process_id: u32,
+                               domain: &str,
+                               record_type: DnsRecordType) -> Result<doh_common::RecordSet, doh_common::error::Error> {
+        let resolution = self.lookup(process_id, domain, record_type.as_uint()).await?;
 
-                });
-        });
+        Ok(records::record_set(&resolution.reply, record_type, resolution.ttl))
+    }
+
+    /// Finds where to connect to a service, named as `_service._proto.name`,
+    /// from its SRV records. The addresses of every target are resolved like
+    /// any other name and come in the order the targets should be tried in;
+    /// targets without addresses are left out.
+    #[instrument(name = "resolve_service", skip_all)]
+    pub async fn resolve_service(&self, process_id: u32, service: &str) -> Result<Vec<doh_common::ServiceAddress>, doh_common::error::Error> {
+        let records = self.query_records(process_id, service, DnsRecordType::SRV).await?.srv;
+        let targets = service::order_targets(records, &self.random);
+
+        let hosts = futures::future::join_all(targets
+            .iter()
+            .map(|target| self.resolve_all(process_id, &target.target)))
+            .await;
+
+        let mut addresses = vec![];
+        let mut error = doh_common::error::Error::EmptyDNSReply;
 
-        let response = self.do_resolve(domain, family).await?;
+        for (target, hosts) in targets.iter().zip(hosts) {
+            let hosts = match hosts {
+                Ok(hosts) => hosts,
+                Err(e) => {
+                    warn!("unable to resolve {}, target of {}: {}", target.target, service, e);
+                    error = e;
+                    continue;
+                }
+            };
 
-        if let Some(host) = response.resolved_host() {
-            let db = self.database.clone();
-            let domain1 = domain.to_string().clone();
-            let family1 = family.clone();
+            for host in hosts {
+                let ips: Vec<IpAddr> = match &host.addresses {
+                    Addresses::V4(ips) => ips.iter().map(|ip| IpAddr::V4(*ip)).collect(),
+                    Addresses::V6(ips) => ips.iter().map(|ip| IpAddr::V6(*ip)).collect(),
+                };
 
-            let _ = tokio::spawn(async move {
-                    if let Err(e) = db.create_dns_answer(domain1.as_ref(), family1, &response).await {
+                addresses.extend(ips.into_iter().map(|ip| doh_common::ServiceAddress::new(
+                    target.target.clone(),
+                    target.priority,
+                    target.weight,
+                    SocketAddr::new(ip, target.port),
+                    target.ttl.min(host.ttl))));
+            }
+        }
+
+        if addresses.is_empty() {
+            return Err(error);
+        }
+
+        Ok(addresses)
+    }
+
+    /// Answers a question, from the cache or the upstreams, auditing it and
+    /// caching the answer.
+    async fn lookup(&self,
+                    process_id: u32,
+                    domain: &str,
+                    family: u32) -> Result<Arc<Resolution>, doh_common::error::Error> {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+
+        let (result, _) = self.flights
+            .run((domain.to_lowercase(), family), async {
+                let resolution = Arc::new(self.do_resolve(domain, family, &[]).await?);
+
+                // Saved before the flight lands, so that the callers coming
+                // later find the answer in the cache instead of asking again
+                if !resolution.cached && resolution.reply.has_answer() && resolution.ttl > 0 {
+                    let answer = AnswerEntry {
+                        reply: &resolution.reply,
+                        authenticated: resolution.authenticated,
+                        downgraded: resolution.downgraded,
+                        // The shortest lived record along the CNAME chain
+                        ttl: resolution.ttl,
+                        cname_chain: &resolution.chain,
+                    };
+
+                    if let Err(e) = self.database.create_dns_answer(domain, family, answer).await {
                         error!("Error saving DNS answer: {:?}", e);
                     }
-                });
+                }
 
-            Ok(host)
-        } else {
-            Err(doh_common::error::Error::EmptyDNSReply)
+                Ok(resolution)
+            })
+            .await;
+
+        let db = self.database.clone();
+        let mut entry = AuditEntry {
+            host: domain.to_string(),
+            family,
+            forward_rule: self.forward_rule(domain).map(|rule| rule.suffix().to_string()),
+            ..AuditEntry::default()
+        };
+
+        if let Ok(resolution) = &result {
+            entry.upstream = resolution.upstream.clone();
+            entry.downgraded = resolution.downgraded;
+            entry.authenticated = resolution.authenticated;
         }
+
+        tokio::spawn(async move {
+
+            entry.process_name = get_process_name(process_id).ok().unwrap_or(String::from("unknown"));
+
+            if let Err(e) = db.create_dns_audit(entry).await {
+                error!("Error saving DNS audit: {:?}", e);
+            }
+        });
+
+        let resolution = result?;
+
+        if resolution.downgraded {
+            let _ = self.events.send(ResolverEvent::PrivacyDowngraded {
+                process_id,
+                name: domain.to_string(),
+                nameserver: resolution.upstream.clone().unwrap_or_default(),
+            });
+        }
+
+        Ok(resolution)
     }
 
+    /// Resolves the IPv4 and the IPv6 addresses of a name at the same time,
+    /// returning a host for each family that has any. Fails only when both
+    /// do, with the error that says the most about why.
+    pub async fn resolve_all(&self, process_id: u32, domain: &str) -> Result<Vec<Host>, doh_common::error::Error> {
+        let (v4, v6) = tokio::join!(
+            self.resolve(process_id, domain, DnsRecordType::A.as_uint()),
+            self.resolve(process_id, domain, DnsRecordType::AAAA.as_uint()));
+
+        let v4 = v4.and_then(with_addresses);
+        let v6 = v6.and_then(with_addresses);
+
+        match (v4, v6) {
+            (Ok(v4), Ok(v6)) => Ok(vec![v4, v6]),
+            (Ok(host), Err(_)) | (Err(_), Ok(host)) => Ok(vec![host]),
+            // A missing family says nothing about the other one
+            (Err(e), Err(doh_common::error::Error::EmptyDNSReply | doh_common::error::Error::NonExistentDomain)) => Err(e),
+            (Err(_), Err(e)) => Err(e),
+        }
+    }
+
+    /// Resolves `domain`, reached through the aliases `followed`.
     #[instrument(name = "do_resolve", skip_all)]
-    async fn do_resolve(&self, domain: &str, family: u32) -> Result<DnsReply, doh_common::error::Error> {
+    async fn do_resolve(&self, domain: &str, family: u32, followed: &[String]) -> Result<Resolution, doh_common::error::Error> {
         let name = if domain.is_ascii() {
             domain.to_string()
         } else {
-            let encoded = punycode::encode(domain).unwrap_or(domain.to_string());
-
-            encoded
+            punycode::encode(domain).unwrap_or(domain.to_string())
         };
 
         if let Ok(true) = self.database.is_host_blocked(domain).await {
@@ -89,18 +329,75 @@ impl Resolver {
             return Err(doh_common::error::Error::EmptyDNSReply);
         }
 
-        if let Ok(Some(answer)) = self.database
+        let dnssec_required = self.dnssec.is_required(&name);
+
+        if let Ok(Some(cached)) = self.database
             .get_dns_answer(domain, family)
             .await {
-            return Ok(answer);
+            if dnssec_required && !cached.authenticated {
+                return Err(doh_common::error::Error::InsecureDNSReply);
+            }
+
+            self.check_chain(domain, followed, &cached.cname_chain)?;
+
+            return Ok(Resolution {
+                ttl: cached.ttl,
+                reply: cached.reply,
+                upstream: None,
+                // Still reported, so that repeated lookups keep telling the
+                // clients their privacy was given up
+                downgraded: cached.downgraded,
+                authenticated: cached.authenticated,
+                chain: cached.cname_chain,
+                cached: true,
+            });
         }
 
-        let response = if self.settings.provider() == &Provider::Google {
-            Google::resolve(&name, DnsRecordType::try_from(family as i32).unwrap()).await?
-        } else {
-            CloudFlare::resolve(&name, DnsRecordType::try_from(family as i32).unwrap()).await?
+        let record_type = DnsRecordType::try_from(family as i32)
+            .map_err(|_| doh_common::error::Error::UnsupportedFamily(family))?;
+
+        let rule = self.forward_rule(&name);
+
+        let result = match (&rule, &self.strategy) {
+            (Some(rule), _) => self.query_forwarder(rule, &name, record_type).await,
+            (None, Strategy::Failover) => self.query_upstreams(&name, record_type).await,
+            (None, Strategy::Race(width)) => self.race_upstreams(&name, record_type, *width).await,
+        };
+
+        let (response, upstream, downgraded) = match (result, &self.fallback) {
+            (Ok((response, upstream)), _) => (response, upstream, false),
+            // Names behind a rule never leave for another server
+            (Err(e), Some(fallback)) if rule.is_none() => {
+                warn!("every upstream failed ({}), falling back to classic DNS", e);
+
+                let (response, nameserver) = fallback.resolve(&name, record_type, None).await?;
+
+                (response, nameserver, true)
+            }
+            (Err(e), _) => return Err(e),
+        };
+
+        let authenticated = match (&self.validator, self.upstream(&upstream)) {
+            // The AD bit of a classic DNS answer may have been set by anyone on the path
+            _ if downgraded => false,
+            (Some(validator), Some(upstream)) => match validator.validate(upstream, &response).await {
+                Validation::Secure => true,
+                Validation::Insecure => false,
+                Validation::Bogus => return Err(doh_common::error::Error::BogusDNSReply),
+            },
+            _ => response.authenticated(),
         };
 
+        if dnssec_required && !authenticated {
+            warn!("answer for {} from {} is not validated with DNSSEC", name, upstream);
+
+            return Err(doh_common::error::Error::InsecureDNSReply);
+        }
+
+        if response.is_name_error() {
+            return Err(doh_common::error::Error::NonExistentDomain);
+        }
+
         if !response.ok() {
             return Err(doh_common::error::Error::DNSErrorReply);
         }
@@ -109,13 +406,274 @@ impl Resolver {
             return Err(doh_common::error::Error::EmptyDNSReply);
         }
 
+        let (mut chain, target) = response.follow_cnames()?;
+
+        self.check_chain(domain, followed, &chain)?;
+
         if response.is_cname_answer() {
-            if let Some(cname) = response.get_cname() {
-                return Box::pin(self.do_resolve(cname.as_str(), family)).await;
+            if chain.is_empty() {
+                return Err(doh_common::error::Error::EmptyDNSReply);
+            }
+
+            let followed: Vec<String> = followed.iter().chain(&chain).cloned().collect();
+
+            if followed.iter().any(|alias| alias.eq_ignore_ascii_case(&target)) {
+                warn!("CNAME records of {} loop back to {}", domain, target);
+
+                return Err(doh_common::error::Error::CnameLoop);
+            }
+
+            let resolution = Box::pin(self.do_resolve(&target, family, &followed)).await?;
+
+            // The target may lie outside of the suffixes requiring DNSSEC
+            if dnssec_required && !resolution.authenticated {
+                return Err(doh_common::error::Error::InsecureDNSReply);
+            }
+
+            chain.extend(resolution.chain.iter().cloned());
+
+            return Ok(Resolution {
+                downgraded: resolution.downgraded || downgraded,
+                authenticated: resolution.authenticated && authenticated,
+                ttl: resolution.ttl.min(self.ttl.keep_for(response.ttl())),
+                chain,
+                cached: false,
+                ..resolution
+            });
+        }
+
+        // As the cache will count it down
+        let ttl = self.ttl.keep_for(response.ttl());
+
+        Ok(Resolution { reply: response, upstream: Some(upstream), downgraded, authenticated, ttl, chain, cached: false })
+    }
+
+    /// Refuses the aliases of `domain` when, after those `followed` to reach
+    /// it, there are too many of them or they lead back to one another.
+    fn check_chain(&self, domain: &str, followed: &[String], chain: &[String]) -> Result<(), doh_common::error::Error> {
+        if followed.len() + chain.len() > self.max_cname_chain {
+            warn!("CNAME chain of {} is longer than {} aliases", domain, self.max_cname_chain);
+
+            return Err(doh_common::error::Error::CnameChainTooLong);
+        }
+
+        if chain.iter().any(|alias| followed.iter().any(|name| name.eq_ignore_ascii_case(alias))) {
+            warn!("CNAME records of {} loop back to a name before it", domain);
+
+            return Err(doh_common::error::Error::CnameLoop);
+        }
+
+        Ok(())
+    }
+
+    /// The forwarding rule with the longest suffix matching `name`.
+    fn forward_rule(&self, name: &str) -> Option<ForwardRule> {
+        self.forward_rules
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|rule| rule.matches(name))
+            .max_by_key(|rule| rule.suffix().len())
+            .cloned()
+    }
+
+    /// Asks the upstream of a forwarding rule, even when its circuit is open,
+    /// since no other upstream may answer for the names under the rule.
+    async fn query_forwarder(&self, rule: &ForwardRule, name: &str, record_type: DnsRecordType) -> Result<(DnsReply, String), doh_common::error::Error> {
+        let upstream = self.upstream(rule.upstream()).ok_or_else(|| {
+            error!("forwarding rule {} names an unknown upstream {}", rule.suffix(), rule.upstream());
+            doh_common::error::Error::UpstreamError
+        })?;
+
+        debug!("forwarding {} to {}", name, upstream.name());
+
+        let attempt = upstream.health()
+            .try_acquire()
+            .unwrap_or_else(|| upstream.health().force_acquire());
+
+        let reply = query_upstream(upstream, attempt, name, record_type).await?;
+
+        Ok((reply, upstream.name().to_string()))
+    }
+
+    fn upstream(&self, name: &str) -> Option<&Upstream> {
+        self.upstreams
+            .iter()
+            .chain(self.forwarders.iter())
+            .find(|upstream| upstream.name() == name)
+    }
+
+    /// Asks the upstreams in order of preference, skipping those whose circuit
+    /// is open, until one of them answers. If every circuit is open the first
+    /// upstream is tried anyway rather than failing without a single attempt.
+    async fn query_upstreams(&self, name: &str, record_type: DnsRecordType) -> Result<(DnsReply, String), doh_common::error::Error> {
+        let mut last_error = doh_common::error::Error::UpstreamError;
+        let mut attempted = false;
+
+        for upstream in &self.upstreams {
+            let attempt = match upstream.health().try_acquire() {
+                Some(attempt) => attempt,
+                None => {
+                    debug!("skipping upstream {}, circuit is open", upstream.name());
+                    continue;
+                }
+            };
+
+            attempted = true;
+
+            match query_upstream(upstream, attempt, name, record_type).await {
+                Ok(reply) => return Ok((reply, upstream.name().to_string())),
+                Err(e) => last_error = e,
             }
         }
 
-        Ok(response)
+        match self.upstreams.first() {
+            Some(upstream) if !attempted => {
+                let reply = query_upstream(upstream, upstream.health().force_acquire(), name, record_type).await?;
+
+                Ok((reply, upstream.name().to_string()))
+            }
+            _ => Err(last_error),
+        }
+    }
+
+    /// Sends the question to the first `width` healthy upstreams at once and
+    /// keeps the first answer that has records; the slower queries are
+    /// cancelled. When no upstream has records to offer, the first valid
+    /// answer is returned, and when all of them fail the remaining upstreams
+    /// are tried one after the other.
+    async fn race_upstreams(&self, name: &str, record_type: DnsRecordType, width: usize) -> Result<(DnsReply, String), doh_common::error::Error> {
+        let mut racers = FuturesUnordered::new();
+        let mut raced = Vec::with_capacity(width);
+
+        for upstream in &self.upstreams {
+            if racers.len() >= width {
+                break;
+            }
+
+            if let Some(attempt) = upstream.health().try_acquire() {
+                raced.push(upstream.name());
+
+                racers.push(async move {
+                    (upstream.name(), query_upstream(upstream, attempt, name, record_type).await)
+                });
+            }
+        }
+
+        if racers.is_empty() {
+            return self.query_upstreams(name, record_type).await;
+        }
+
+        let mut fallback = None;
+        let mut last_error = doh_common::error::Error::UpstreamError;
+
+        while let Some((upstream, result)) = racers.next().await {
+            match result {
+                Ok(reply) if reply.ok() && !reply.no_answers() => {
+                    debug!("upstream {} won the race", upstream);
+                    return Ok((reply, upstream.to_string()));
+                }
+                Ok(reply) => {
+                    if fallback.is_none() {
+                        fallback = Some((reply, upstream.to_string()));
+                    }
+                }
+                Err(e) => last_error = e,
+            }
+        }
+
+        if let Some(fallback) = fallback {
+            return Ok(fallback);
+        }
+
+        for upstream in self.upstreams.iter().filter(|upstream| !raced.contains(&upstream.name())) {
+            if let Some(attempt) = upstream.health().try_acquire() {
+                match query_upstream(upstream, attempt, name, record_type).await {
+                    Ok(reply) => return Ok((reply, upstream.name().to_string())),
+                    Err(e) => last_error = e,
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+
+    /// Connects to every upstream ahead of the first query.
+    pub async fn warm_up(&self) {
+        futures::future::join_all(self.upstreams.iter().map(|upstream| upstream.warm_up())).await;
+    }
+
+    pub fn get_stats(&self) -> doh_common::ResolverStats {
+        doh_common::ResolverStats::new(self.queries.load(Ordering::Relaxed), self.flights.coalesced())
+    }
+
+    pub fn get_upstream_health(&self) -> Vec<doh_common::UpstreamHealth> {
+        self.upstreams
+            .iter()
+            .chain(self.forwarders.iter())
+            .map(|upstream| upstream.health().status(upstream.name()))
+            .collect()
+    }
+
+    /// Adds the forwarding rules saved over D-Bus to those of the
+    /// configuration file, replacing the ones with the same suffix.
+    pub async fn restore_forward_rules(&self) -> Result<(), doh_common::error::Error> {
+        let saved = self.database.get_forward_rules().await?;
+
+        let mut rules = self.forward_rules.write().unwrap();
+
+        for rule in saved {
+            if self.upstream(rule.upstream()).is_none() {
+                warn!("ignoring forwarding rule {}, upstream {} is not configured", rule.suffix(), rule.upstream());
+                continue;
+            }
+
+            rules.retain(|current| current.suffix() != rule.suffix());
+            rules.push(rule);
+        }
+
+        Ok(())
+    }
+
+    /// Returns false when the upstream is not configured.
+    pub async fn add_forward_rule(&self, suffix: &str, upstream: &str) -> Result<bool, doh_common::error::Error> {
+        let rule = ForwardRule::new(suffix, upstream);
+
+        if rule.suffix().is_empty() || self.upstream(rule.upstream()).is_none() {
+            return Ok(false);
+        }
+
+        self.database.create_forward_rule(&rule).await?;
+
+        let mut rules = self.forward_rules.write().unwrap();
+
+        rules.retain(|current| current.suffix() != rule.suffix());
+        rules.push(rule);
+
+        Ok(true)
+    }
+
+    /// Rules from the configuration file come back when the daemon restarts.
+    pub async fn remove_forward_rule(&self, suffix: &str) -> Result<bool, doh_common::error::Error> {
+        let suffix = ForwardRule::new(suffix, "").suffix().to_string();
+
+        self.database.delete_forward_rule(&suffix).await?;
+
+        let mut rules = self.forward_rules.write().unwrap();
+        let count = rules.len();
+
+        rules.retain(|current| current.suffix() != suffix);
+
+        Ok(rules.len() < count)
+    }
+
+    pub fn get_forward_rules(&self) -> Vec<doh_common::ForwardRule> {
+        self.forward_rules
+            .read()
+            .unwrap()
+            .iter()
+            .map(|rule| doh_common::ForwardRule::new(rule.suffix().to_string(), rule.upstream().to_string()))
+            .collect()
     }
 
     pub async fn add_to_blacklist(&self, host: &str) -> Result<bool, doh_common::error::Error> {
@@ -131,6 +689,44 @@ impl Resolver {
     }
 }
 
+async fn query_upstream(upstream: &Upstream, attempt: Attempt<'_>, name: &str, record_type: DnsRecordType) -> Result<DnsReply, doh_common::error::Error> {
+    match upstream.resolve(name, record_type).await {
+        Ok(reply) if reply.is_server_failure() => {
+            warn!("upstream {} failed to answer {}", upstream.name(), name);
+            attempt.failure();
+            Err(doh_common::error::Error::DNSErrorReply)
+        }
+        Ok(reply) => {
+            attempt.success();
+            Ok(reply)
+        }
+        Err(e) => {
+            warn!("upstream {} error: {}", upstream.name(), e);
+            attempt.failure();
+            Err(e)
+        }
+    }
+}
+
+fn with_addresses(host: Host) -> Result<Host, doh_common::error::Error> {
+    if host.addresses.is_empty() {
+        Err(doh_common::error::Error::EmptyDNSReply)
+    } else {
+        Ok(host)
+    }
+}
+
+/// Returns the URL template of an upstream, falling back to a form-style query
+/// expansion of `variables` when the configured URL has no template expression.
+fn query_template(url: &str, variables: &str) -> String {
+    if url.contains('{') {
+        url.to_string()
+    } else if url.contains('?') {
+        format!("{}{{&{}}}", url, variables)
+    } else {
+        format!("{}{{?{}}}", url, variables)
+    }
+}
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct DnsReply {
@@ -154,7 +750,6 @@ pub struct DnsReply {
     #[serde(rename(deserialize = "RA", serialize = "RA"))]
     ra: bool,
     // If true, it means that every record in the answer was verified with DNSSEC.
-    #[allow(dead_code)]
     #[serde(rename(deserialize = "AD", serialize = "AD"))]
     ad: bool,
     // If true, the client asked to disable DNSSEC validation. In this case,
@@ -171,16 +766,34 @@ pub struct DnsReply {
     answers: Vec<DnsEntryReply>,
 
     #[serde(rename(deserialize = "Authority", serialize = "Authority"), default)]
-    authority: Vec<DnsEntryReply>
+    authority: Vec<DnsEntryReply>,
+    // The binary message the reply was read from, with the DNSSEC records the
+    // entries leave out. None for the JSON APIs and the cache.
+    #[serde(skip)]
+    message: Option<Message>,
 }
 
 impl DnsReply {
     fn ok(&self) -> bool {
-        return self.status == 0;
+        self.status == 0
+    }
+
+    // NXDOMAIN
+    fn is_name_error(&self) -> bool {
+        self.status == 3
+    }
+
+    // SERVFAIL and REFUSED say more about the upstream than about the name
+    fn is_server_failure(&self) -> bool {
+        self.status == 2 || self.status == 5
+    }
+
+    fn authenticated(&self) -> bool {
+        self.ad
     }
 
     fn no_answers(&self) -> bool {
-        return self.answers.is_empty();
+        self.answers.is_empty()
     }
 
     fn is_cname_answer(&self) -> bool {
@@ -189,16 +802,38 @@ impl DnsReply {
             .sum::<i32>() == self.answers.len() as i32
     }
 
-    fn get_cname(&self) -> Option<String> {
-        self.answers
+    /// Follows the CNAME records of the answer from the name asked about,
+    /// returning the aliases met on the way and the name owning the answer.
+    fn follow_cnames(&self) -> Result<(Vec<String>, String), doh_common::error::Error> {
+        let mut name = self.questions
+            .first()
+            .map(|question| question.name.trim_end_matches('.').to_string())
+            .unwrap_or_default();
+
+        let mut chain: Vec<String> = vec![];
+
+        while let Some(target) = self.answers
             .iter()
             .filter(|a| a.r#type == DnsRecordType::CNAME)
-            .map(|a| a.data.clone())
-            .next()
+            .find(|a| a.name.trim_end_matches('.').eq_ignore_ascii_case(&name))
+            .map(|a| a.data.trim_end_matches('.').to_string()) {
+
+            chain.push(name);
+
+            if chain.iter().any(|alias| alias.eq_ignore_ascii_case(&target)) {
+                warn!("CNAME records of {} loop back to {}", chain[0], target);
+
+                return Err(doh_common::error::Error::CnameLoop);
+            }
+
+            name = target;
+        }
+
+        Ok((chain, name))
     }
 
     fn no_question(&self) -> bool {
-        return self.questions.is_empty();
+        self.questions.is_empty()
     }
 
     pub fn get_expiration(&self) -> Option<u32> {
@@ -207,8 +842,22 @@ impl DnsReply {
             .max()
     }
 
-    fn resolved_host(&self) -> Option<Host> {
-        if self.no_answers() || self.no_question() {
+    /// Seconds the answer may be kept, those of its shortest lived record.
+    pub(crate) fn ttl(&self) -> u32 {
+        self.answers.iter()
+            .map(|a| a.ttl)
+            .min()
+            .unwrap_or(0)
+    }
+
+    fn has_answer(&self) -> bool {
+        !self.no_answers() && !self.no_question()
+    }
+
+    /// The host `name` resolved to, named after the owner of the addresses
+    /// once its CNAME records are followed.
+    fn resolved_host(&self, name: &str, ttl: u32, aliases: Vec<String>) -> Option<Host> {
+        if !self.has_answer() {
             return None;
         }
 
@@ -230,14 +879,62 @@ impl DnsReply {
             .r#type
             .to_addresses(&raw_addresses);
 
+        let canonical_name = match self.follow_cnames() {
+            Ok((_, owner)) => owner,
+            Err(_) => question.name.trim_end_matches('.').to_string(),
+        };
+
         Some(
             Host {
-                name: question.name.to_string(),
-                aliases: vec![],
+                name: name.to_string(),
+                aliases,
                 addresses,
+                ttl,
+                canonical_name,
             }
         )
     }
+
+    /// The host an address belongs to: the first name its PTR records point
+    /// to, with the others as aliases.
+    fn pointer_host(&self, address: IpAddr, ttl: u32) -> Option<Host> {
+        let mut names = self.answers
+            .iter()
+            .filter(|a| a.r#type == DnsRecordType::PTR)
+            .map(|a| a.data.trim_end_matches('.').to_string());
+
+        let name = names.next()?;
+
+        let addresses = match address {
+            IpAddr::V4(address) => Addresses::V4(vec![address]),
+            IpAddr::V6(address) => Addresses::V6(vec![address]),
+        };
+
+        Some(Host { canonical_name: name.clone(), name, aliases: names.collect(), addresses, ttl })
+    }
+}
+
+/// The name under `in-addr.arpa` or `ip6.arpa` holding the PTR records of an
+/// address (RFC 1035 section 3.5, RFC 3596 section 2.5). IPv4-mapped addresses
+/// are IPv4 addresses, whose records live under `in-addr.arpa`.
+fn reverse_name(address: IpAddr) -> String {
+    match address.to_canonical() {
+        IpAddr::V4(address) => {
+            let octets = address.octets();
+
+            format!("{}.{}.{}.{}.in-addr.arpa", octets[3], octets[2], octets[1], octets[0])
+        }
+        IpAddr::V6(address) => {
+            let mut name = String::with_capacity(72);
+
+            for octet in address.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", octet & 0x0f, octet >> 4));
+            }
+
+            name.push_str("ip6.arpa");
+            name
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -252,7 +949,6 @@ struct DnsRequest {
 #[derive(Deserialize, Serialize, Debug)]
 struct DnsEntryReply {
     // The record owner.
-    #[allow(dead_code)]
     name: String,
     // The type of DNS record. These are defined here:
     // https://www.iana.org/assignments/dns-parameters/dns-parameters.xhtml#dns-parameters-4
@@ -267,39 +963,41 @@ struct DnsEntryReply {
     data: String,
 }
 
-#[derive(Debug, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum DnsRecordType {
     A,
     AAAA,
     CNAME,
-    SOA
+    SOA,
+    PTR,
+    NS,
+    MX,
+    TXT,
+    SRV,
+    CAA,
+    SVCB,
+    HTTPS,
 }
 
 impl DnsRecordType {
-    fn to_addresses(&self, ips: &Vec<&String>) -> Addresses {
+    fn to_addresses(self, ips: &Vec<&String>) -> Addresses {
         match self {
             DnsRecordType::A => {
                 let ipv4: Vec<Ipv4Addr> = ips.iter()
-                    .map(|ip| Ipv4Addr::from_str(ip))
-                    .filter(|ip| ip.is_ok())
-                    .map(|ip| ip.unwrap())
+                    .filter_map(|ip| Ipv4Addr::from_str(ip).ok())
                     .collect();
 
                 Addresses::V4(ipv4)
             }
             DnsRecordType::AAAA => {
                 let ipv6: Vec<Ipv6Addr> = ips.iter()
-                    .map(|ip| Ipv6Addr::from_str(ip))
-                    .filter(|ip| ip.is_ok())
-                    .map(|ip| ip.unwrap())
+                    .filter_map(|ip| Ipv6Addr::from_str(ip).ok())
                     .collect();
 
                 Addresses::V6(ipv6)
             }
-            DnsRecordType::CNAME => {
-                Addresses::V4(Vec::with_capacity(0))
-            },
-            DnsRecordType::SOA => {
+            _ => {
                 Addresses::V4(Vec::with_capacity(0))
             }
         }
@@ -310,7 +1008,15 @@ impl DnsRecordType {
             DnsRecordType::A => 1,
             DnsRecordType::CNAME => 5,
             DnsRecordType::SOA => 6,
+            DnsRecordType::PTR => 12,
+            DnsRecordType::NS => 2,
+            DnsRecordType::MX => 15,
+            DnsRecordType::TXT => 16,
             DnsRecordType::AAAA => 28,
+            DnsRecordType::SRV => 33,
+            DnsRecordType::SVCB => 64,
+            DnsRecordType::HTTPS => 65,
+            DnsRecordType::CAA => 257,
         }
     }
 }
@@ -321,7 +1027,15 @@ impl Display for DnsRecordType {
             DnsRecordType::A => write!(f, "A"),
             DnsRecordType::CNAME => write!(f, "CNAME"),
             DnsRecordType::AAAA => write!(f, "AAAA"),
-            DnsRecordType::SOA => write!(f, "SOA")
+            DnsRecordType::SOA => write!(f, "SOA"),
+            DnsRecordType::PTR => write!(f, "PTR"),
+            DnsRecordType::NS => write!(f, "NS"),
+            DnsRecordType::MX => write!(f, "MX"),
+            DnsRecordType::TXT => write!(f, "TXT"),
+            DnsRecordType::SRV => write!(f, "SRV"),
+            DnsRecordType::CAA => write!(f, "CAA"),
+            DnsRecordType::SVCB => write!(f, "SVCB"),
+            DnsRecordType::HTTPS => write!(f, "HTTPS")
         }
     }
 }
@@ -334,6 +1048,14 @@ impl TryFrom<i32> for DnsRecordType {
             1 => Ok(DnsRecordType::A),
             5 => Ok(DnsRecordType::CNAME),
             6 => Ok(DnsRecordType::SOA),
+            12 => Ok(DnsRecordType::PTR),
+            2 => Ok(DnsRecordType::NS),
+            15 => Ok(DnsRecordType::MX),
+            16 => Ok(DnsRecordType::TXT),
+            33 => Ok(DnsRecordType::SRV),
+            257 => Ok(DnsRecordType::CAA),
+            64 => Ok(DnsRecordType::SVCB),
+            65 => Ok(DnsRecordType::HTTPS),
             28 => Ok(DnsRecordType::AAAA),
             _ => Err(String::from("DNS record out of scope"))
         }
@@ -370,4 +1092,334 @@ impl From<AddressFamily> for DnsRecordType {
             _ => DnsRecordType::A
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::provider::message::{write_name, Record};
+    use crate::testing::{database, https_server, Pki};
+
+    const TYPE_A: u16 = 1;
+    const TYPE_CNAME: u16 = 5;
+
+    /// A DoH server answering every question after `delay` with an A record
+    /// per TTL of `ttls`, 192.0.2.1 onwards, or with a server failure when
+    /// there are none, and counting the queries.
+    async fn upstream(pki: &Pki, delay: Duration, ttls: &'static [u32]) -> (SocketAddr, Arc<AtomicUsize>) {
+        let queries = Arc::new(AtomicUsize::new(0));
+
+        let address = https_server(pki.server_config(&[b"http/1.1"]), {
+            let queries = queries.clone();
+            move |request| {
+                let queries = queries.clone();
+                async move {
+                    queries.fetch_add(1, Ordering::SeqCst);
+
+                    tokio::time::sleep(delay).await;
+
+                    let mut message = Message::decode(&request.body).unwrap();
+                    let name = message.questions[0].name.clone();
+
+                    message.additional.clear();
+
+                    if ttls.is_empty() {
+                        message.flags = 0x8182;
+                    } else {
+                        message.flags = 0x8180;
+                        message.answers = ttls
+                            .iter()
+                            .zip(1..)
+                            .map(|(ttl, host)| Record { name: name.clone(), rtype: TYPE_A, class: 1, ttl: *ttl, rdata: vec![192, 0, 2, host] })
+                            .collect();
+                    }
+
+                    (200, message.encode().unwrap())
+                }
+            }
+        }).await;
+
+        (address, queries)
+    }
+
+    /// A DoH server answering each question with the records `zone` holds
+    /// for its name: (owner, TTL, CNAME target or none for 192.0.2.1).
+    async fn zone(pki: &Pki, zone: &'static [(&'static str, u32, Option<&'static str>)]) -> SocketAddr {
+        https_server(pki.server_config(&[b"http/1.1"]), move |request| async move {
+            let mut message = Message::decode(&request.body).unwrap();
+            let name = message.questions[0].name.clone();
+
+            message.flags = 0x8180;
+            message.additional.clear();
+            message.answers = zone
+                .iter()
+                .filter(|(owner, _, _)| name.trim_end_matches('.').eq_ignore_ascii_case(owner))
+                .map(|(owner, ttl, target)| match target {
+                    Some(target) => {
+                        let mut rdata = vec![];
+                        write_name(&mut rdata, target).unwrap();
+
+                        Record { name: owner.to_string(), rtype: TYPE_CNAME, class: 1, ttl: *ttl, rdata }
+                    }
+                    None => Record { name: owner.to_string(), rtype: TYPE_A, class: 1, ttl: *ttl, rdata: vec![192, 0, 2, 1] },
+                })
+                .collect();
+
+            (200, message.encode().unwrap())
+        }).await
+    }
+
+    async fn new_resolver(settings: ApplicationSettings) -> Resolver {
+        let (events, _) = tokio::sync::mpsc::unbounded_channel();
+
+        Resolver::new(database().await, settings, events).unwrap()
+    }
+
+    /// A resolver whose only upstream answers every question with A records
+    /// of the TTLs `ttls`, slowly enough for identical lookups to overlap, and
+    /// counts the queries. The sections `extra` are added to the settings.
+    async fn resolver(pki: &Pki, ttls: &'static [u32], extra: &str) -> (Resolver, Arc<AtomicUsize>) {
+        let (address, queries) = upstream(pki, Duration::from_millis(50), ttls).await;
+
+        (new_resolver(pki.application_settings(address, extra)).await, queries)
+    }
+
+    /// A resolver racing the upstreams `upstreams`, in that order.
+    async fn racing_resolver(pki: &Pki, upstreams: &[(&str, SocketAddr)]) -> Resolver {
+        let names: Vec<_> = upstreams.iter().map(|(name, _)| *name).collect();
+        let sections: String = upstreams.iter().map(|(name, address)| pki.provider_section(name, *address) + "\n").collect();
+
+        let settings = ApplicationSettings::parse(&format!("[resolver]\nprovider={}\nstrategy=race\n\n{}", names.join(","), sections));
+        assert_eq!(settings.strategy(), &Strategy::Race(upstreams.len()));
+
+        new_resolver(settings).await
+    }
+
+    #[tokio::test]
+    async fn identical_lookups_ask_the_upstream_once() {
+        let pki = Pki::new();
+        let (resolver, queries) = resolver(&pki, &[300], "").await;
+        let resolver = Arc::new(resolver);
+
+        let lookups: Vec<_> = (0..16)
+            .map(|_| {
+                let resolver = resolver.clone();
+                tokio::spawn(async move { resolver.resolve(0, "www.example.test", TYPE_A as u32).await })
+            })
+            .collect();
+
+        for result in futures::future::join_all(lookups).await {
+            assert!(result.unwrap().is_ok());
+        }
+
+        // Right after the flight landed, from the cache
+        resolver.resolve(0, "www.example.test", TYPE_A as u32).await.unwrap();
+
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn answers_live_as_long_as_their_shortest_record() {
+        let pki = Pki::new();
+        let (resolver, queries) = resolver(&pki, &[300, 1], "").await;
+
+        let fresh = resolver.resolve(0, "www.example.test", TYPE_A as u32).await.unwrap();
+        let cached = resolver.resolve(0, "www.example.test", TYPE_A as u32).await.unwrap();
+
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+        assert_eq!(fresh.ttl, 1);
+        assert!(cached.ttl <= 1, "{}", cached.ttl);
+
+        // Past the TTL of the shortest lived record, even if the other one still lives
+        tokio::time::sleep(Duration::from_millis(2100)).await;
+
+        resolver.resolve(0, "www.example.test", TYPE_A as u32).await.unwrap();
+
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn cached_answers_count_their_ttl_down() {
+        let pki = Pki::new();
+        let (resolver, queries) = resolver(&pki, &[300], "").await;
+
+        let fresh = resolver.resolve(0, "www.example.test", TYPE_A as u32).await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+
+        let cached = resolver.resolve(0, "www.example.test", TYPE_A as u32).await.unwrap();
+        let again = resolver.resolve(0, "www.example.test", TYPE_A as u32).await.unwrap();
+
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+        assert_eq!(fresh.ttl, 300);
+        // Saved once, the answer keeps its expiration however often it is read
+        assert!((298..=299).contains(&cached.ttl), "{}", cached.ttl);
+        assert!(again.ttl <= cached.ttl, "{} after {}", again.ttl, cached.ttl);
+    }
+
+    #[tokio::test]
+    async fn a_custom_ttl_replaces_the_one_of_the_records() {
+        let pki = Pki::new();
+        let (resolver, queries) = resolver(&pki, &[60, 300], "[resolver]\nttl=3600\n").await;
+
+        let fresh = resolver.resolve(0, "www.example.test", TYPE_A as u32).await.unwrap();
+        let cached = resolver.resolve(0, "www.example.test", TYPE_A as u32).await.unwrap();
+
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+        // Fresh or cached alike, give or take a second
+        assert_eq!(fresh.ttl, 3600);
+        assert!((3599..=3600).contains(&cached.ttl), "{}", cached.ttl);
+    }
+
+    #[tokio::test]
+    async fn the_fastest_answer_wins_the_race() {
+        let pki = Pki::new();
+        let (slow, _) = upstream(&pki, Duration::from_millis(500), &[300]).await;
+        let (fast, _) = upstream(&pki, Duration::from_millis(10), &[300]).await;
+
+        // Listed first, the slow upstream still loses
+        let resolver = racing_resolver(&pki, &[("slow", slow), ("fast", fast)]).await;
+
+        let (reply, winner) = resolver.race_upstreams("www.example.test", DnsRecordType::A, 2).await.unwrap();
+
+        assert_eq!(winner, "fast");
+        assert!(reply.ok() && !reply.no_answers());
+    }
+
+    #[tokio::test]
+    async fn a_fast_failure_does_not_beat_a_slower_answer() {
+        let pki = Pki::new();
+        let (failing, _) = upstream(&pki, Duration::ZERO, &[]).await;
+        let (slow, _) = upstream(&pki, Duration::from_millis(100), &[300]).await;
+
+        let resolver = racing_resolver(&pki, &[("failing", failing), ("slow", slow)]).await;
+
+        let (reply, winner) = resolver.race_upstreams("www.example.test", DnsRecordType::A, 2).await.unwrap();
+
+        assert_eq!(winner, "slow");
+        assert!(reply.ok() && !reply.no_answers());
+    }
+
+    #[tokio::test]
+    async fn the_longest_matching_suffix_picks_the_forwarder() {
+        let pki = Pki::new();
+        let (resolver, _) = resolver(&pki, &[300], "[forward]\nexample.test=cloudflare\ncorp.example.test=stub\n").await;
+
+        let suffix = |name| resolver.forward_rule(name).map(|rule| rule.suffix().to_string());
+
+        assert_eq!(suffix("host.corp.example.test").as_deref(), Some("corp.example.test"));
+        assert_eq!(suffix("Host.Corp.Example.Test.").as_deref(), Some("corp.example.test"));
+        assert_eq!(suffix("corp.example.test").as_deref(), Some("corp.example.test"));
+        assert_eq!(suffix("www.example.test").as_deref(), Some("example.test"));
+        assert_eq!(suffix("othercorp.example.test").as_deref(), Some("example.test"));
+        assert_eq!(suffix("badexample.test"), None);
+    }
+
+    #[test]
+    fn names_the_reverse_zone_of_an_address() {
+        let ones = "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.ip6.arpa";
+
+        let cases = [
+            ("192.0.2.1", "1.2.0.192.in-addr.arpa"),
+            ("10.0.0.255", "255.0.0.10.in-addr.arpa"),
+            ("0.0.0.0", "0.0.0.0.in-addr.arpa"),
+            // The example of RFC 3596 section 2.5
+            ("4321:0:1:2:3:4:567:89ab", "b.a.9.8.7.6.5.0.4.0.0.0.3.0.0.0.2.0.0.0.1.0.0.0.0.0.0.0.1.2.3.4.ip6.arpa"),
+            ("2001:db8::ff00:42:8329", "9.2.3.8.2.4.0.0.0.0.f.f.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa"),
+            ("::1", ones),
+            ("::ffff:192.0.2.1", "1.2.0.192.in-addr.arpa"),
+            // IPv4-compatible addresses are long deprecated, and not IPv4 ones
+            ("::192.0.2.1", "1.0.2.0.0.0.0.c.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.ip6.arpa"),
+        ];
+
+        for (address, name) in cases {
+            assert_eq!(reverse_name(address.parse().unwrap()), name, "{}", address);
+        }
+    }
+
+    #[tokio::test]
+    async fn an_unknown_family_is_an_error() {
+        let pki = Pki::new();
+        let (resolver, queries) = resolver(&pki, &[300], "").await;
+
+        let result = resolver.resolve(0, "example.test", 9999).await;
+
+        assert!(matches!(result, Err(doh_common::error::Error::UnsupportedFamily(9999))), "{:?}", result.err());
+        assert_eq!(queries.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn a_cname_to_itself_is_a_loop() {
+        let pki = Pki::new();
+        let address = zone(&pki, &[("loop.example.test", 300, Some("loop.example.test"))]).await;
+        let resolver = new_resolver(pki.application_settings(address, "")).await;
+
+        let result = resolver.resolve(0, "loop.example.test", TYPE_A as u32).await;
+
+        assert!(matches!(result, Err(doh_common::error::Error::CnameLoop)), "{:?}", result.err());
+    }
+
+    #[tokio::test]
+    async fn cnames_leading_back_to_the_name_asked_are_a_loop() {
+        let pki = Pki::new();
+        let address = zone(&pki, &[
+            ("a.example.test", 300, Some("b.example.test")),
+            ("b.example.test", 300, Some("A.Example.Test")),
+        ]).await;
+        let resolver = new_resolver(pki.application_settings(address, "")).await;
+
+        for name in ["a.example.test", "b.example.test"] {
+            let result = resolver.resolve(0, name, TYPE_A as u32).await;
+
+            assert!(matches!(result, Err(doh_common::error::Error::CnameLoop)), "{}: {:?}", name, result.err());
+        }
+    }
+
+    #[tokio::test]
+    async fn cname_chains_stop_at_the_configured_length() {
+        const ZONE: &[(&str, u32, Option<&str>)] = &[
+            ("c1.example.test", 300, Some("c2.example.test")),
+            ("c2.example.test", 300, Some("c3.example.test")),
+            ("c3.example.test", 300, Some("host.example.test")),
+            ("host.example.test", 300, None),
+        ];
+
+        let pki = Pki::new();
+        let address = zone(&pki, ZONE).await;
+
+        let short = new_resolver(pki.application_settings(address, "[resolver]\nmax_cname_chain=2\n")).await;
+        let result = short.resolve(0, "c1.example.test", TYPE_A as u32).await;
+
+        assert!(matches!(result, Err(doh_common::error::Error::CnameChainTooLong)), "{:?}", result.err());
+
+        // Two aliases are within the limit
+        short.resolve(0, "c2.example.test", TYPE_A as u32).await.unwrap();
+
+        let long = new_resolver(pki.application_settings(address, "[resolver]\nmax_cname_chain=3\n")).await;
+        let host = long.resolve(0, "c1.example.test", TYPE_A as u32).await.unwrap();
+
+        assert_eq!(host.aliases, ["c1.example.test", "c2.example.test", "c3.example.test"]);
+        assert_eq!(host.canonical_name, "host.example.test");
+    }
+
+    #[tokio::test]
+    async fn cached_aliases_expire_with_their_cname() {
+        let pki = Pki::new();
+        let address = zone(&pki, &[
+            ("www.example.test", 30, Some("cdn.example.test")),
+            ("cdn.example.test", 300, None),
+        ]).await;
+        let resolver = new_resolver(pki.application_settings(address, "")).await;
+
+        let fresh = resolver.resolve(0, "www.example.test", TYPE_A as u32).await.unwrap();
+        let cached = resolver.resolve(0, "www.example.test", TYPE_A as u32).await.unwrap();
+
+        assert_eq!(fresh.ttl, 30);
+        // Rather than the 300 seconds of the address
+        assert!((29..=30).contains(&cached.ttl), "{}", cached.ttl);
+        assert_eq!(cached.aliases, ["www.example.test"]);
+    }
+}
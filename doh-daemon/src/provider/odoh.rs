@@ -0,0 +1,505 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use reqwest::Url;
+use tracing::{debug, error, instrument, warn};
+
+use doh_common::error::Error;
+
+use crate::client::certificate::CertificateVerifier;
+use crate::client::{expand_url_template, HttpClient};
+use crate::provider::hpke::{self, SenderContext, AEAD_AES_128_GCM, KDF_HKDF_SHA256, KEM_X25519_HKDF_SHA256, NK, NN};
+use crate::provider::message::Message;
+use crate::provider::query_template;
+use crate::settings::{ObliviousSettings, Proxy};
+
+// https://www.rfc-editor.org/rfc/rfc9230#section-6
+const CONFIGS_PATH: &str = "/.well-known/odohconfigs";
+const CONFIG_VERSION: u16 = 0x0001;
+const MESSAGE_QUERY: u8 = 0x01;
+const MESSAGE_RESPONSE: u8 = 0x02;
+
+/// Targets rotate their keys; a config older than this is fetched again.
+const CONFIG_MAX_AGE: Duration = Duration::from_secs(3600);
+
+/// Oblivious DoH as specified by RFC 9230. Queries are encrypted to the public
+/// key of the target and sent through a relay, so the relay sees who asks but
+/// not what, and the target sees what is asked but not by whom.
+pub struct ObliviousDns {
+    relay: HttpClient,
+    target: HttpClient,
+    relay_url: String,
+    configs_url: String,
+    headers: Vec<(String, String)>,
+    config: tokio::sync::Mutex<Option<TargetConfig>>,
+}
+
+/// Public key of the target, from its ObliviousDoHConfigs.
+#[derive(Clone)]
+struct TargetConfig {
+    public_key: Vec<u8>,
+    key_id: Vec<u8>,
+    fetched: Instant,
+}
+
+impl std::fmt::Debug for ObliviousDns {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ObliviousDns({})", self.relay_url)
+    }
+}
+
+impl ObliviousDns {
+    pub fn new(
+        settings: &ObliviousSettings,
+        target_addresses: &[std::net::IpAddr],
+        proxy: &Proxy,
+        verifier: Arc<CertificateVerifier>,
+        timeout: Duration) -> Result<Self, Error> {
+        let target_url = Url::parse(settings.target())?;
+        let relay_url = Url::parse(&expand_url_template(settings.relay(), &[]))?;
+
+        let target_host = match target_url.port() {
+            Some(port) => format!("{}:{}", target_url.host_str().unwrap_or_default(), port),
+            None => target_url.host_str().unwrap_or_default().to_string(),
+        };
+
+        let relay = expand_url_template(
+            &query_template(settings.relay(), "targethost,targetpath"),
+            &[("targethost", &target_host), ("targetpath", target_url.path())]);
+
+        let configs_url = format!("{}{}", target_url.origin().ascii_serialization(), CONFIGS_PATH);
+
+        Ok(Self {
+            relay: HttpClient::new(settings.relay_bootstrap(), &relay_url, proxy, verifier.clone(), timeout)?,
+            target: HttpClient::new(target_addresses, &target_url, proxy, verifier, timeout)?,
+            relay_url: relay,
+            configs_url,
+            headers: settings.headers().to_vec(),
+            config: tokio::sync::Mutex::new(None),
+        })
+    }
+
+    #[instrument(skip_all)]
+    pub async fn exchange(&self, query: &Message) -> Result<Message, Error> {
+        let config = self.target_config().await?;
+
+        let result = self.seal_and_send(&config, &query.encode()?).await;
+
+        if result.is_err() {
+            // The target may have rotated its key, fetch it again next time
+            self.config.lock().await.take();
+        }
+
+        Message::decode(&result?)
+    }
+
+    /// Fetches the key of the target ahead of the first query.
+    pub async fn warm_up(&self) -> Result<(), Error> {
+        self.target_config().await?;
+        self.relay.warm_up(&self.relay_url).await
+    }
+
+    async fn seal_and_send(&self, config: &TargetConfig, query: &[u8]) -> Result<Vec<u8>, Error> {
+        let plaintext = encode_plaintext(query);
+
+        let (encapsulated_key, mut context) = SenderContext::setup_base(&config.public_key, b"odoh query")?;
+
+        let aad = message_aad(MESSAGE_QUERY, &config.key_id);
+
+        let encrypted = [encapsulated_key, context.seal(&aad, &plaintext)?].concat();
+
+        let body = self.relay
+            .request_oblivious_message(&self.relay_url, &self.headers, &encode_message(MESSAGE_QUERY, &config.key_id, &encrypted))
+            .await?;
+
+        let (message_type, response_nonce, encrypted) = decode_message(&body)?;
+
+        if message_type != MESSAGE_RESPONSE {
+            error!("unexpected ODoH message type {}", message_type);
+            return Err(Error::MalformedReply);
+        }
+
+        // https://www.rfc-editor.org/rfc/rfc9230#section-6.4
+        let secret = context.export(b"odoh response", NK);
+
+        let salt = [plaintext.as_slice(), &(response_nonce.len() as u16).to_be_bytes(), response_nonce].concat();
+        let prk = hpke::extract(&salt, &secret);
+
+        let key = hpke::expand(&prk, b"odoh key", NK);
+        let nonce = hpke::expand(&prk, b"odoh nonce", NN);
+
+        let plaintext = hpke::open(&key, &nonce, &message_aad(MESSAGE_RESPONSE, response_nonce), encrypted)?;
+
+        decode_plaintext(&plaintext)
+    }
+
+    async fn target_config(&self) -> Result<TargetConfig, Error> {
+        let mut current = self.config.lock().await;
+
+        if let Some(config) = current.as_ref() {
+            if config.fetched.elapsed() < CONFIG_MAX_AGE {
+                return Ok(config.clone());
+            }
+        }
+
+        debug!("fetching target configs from {}", self.configs_url);
+
+        let config = parse_configs(&self.target.request_bytes(&self.configs_url).await?)?;
+
+        *current = Some(config.clone());
+
+        Ok(config)
+    }
+}
+
+/// Picks the first config of a supported version and HPKE suite out of an
+/// ObliviousDoHConfigs structure.
+fn parse_configs(bytes: &[u8]) -> Result<TargetConfig, Error> {
+    let mut reader = Reader::new(bytes);
+    let mut configs = Reader::new(reader.vector()?);
+
+    while !configs.is_empty() {
+        let version = configs.u16()?;
+        let contents = configs.vector()?;
+
+        if version != CONFIG_VERSION {
+            debug!("skipping ODoH config version {}", version);
+            continue;
+        }
+
+        let mut reader = Reader::new(contents);
+        let suite = (reader.u16()?, reader.u16()?, reader.u16()?);
+        let public_key = reader.vector()?;
+
+        if suite != (KEM_X25519_HKDF_SHA256, KDF_HKDF_SHA256, AEAD_AES_128_GCM) {
+            debug!("skipping ODoH config with suite {:?}", suite);
+            continue;
+        }
+
+        let key_id = hpke::expand(&hpke::extract(&[], contents), b"odoh key id", 32);
+
+        return Ok(TargetConfig {
+            public_key: public_key.to_vec(),
+            key_id,
+            fetched: Instant::now(),
+        });
+    }
+
+    warn!("the target offers no supported ODoH config");
+
+    Err(Error::UpstreamError)
+}
+
+/// ObliviousDoHMessagePlaintext, without padding.
+fn encode_plaintext(query: &[u8]) -> Vec<u8> {
+    [&(query.len() as u16).to_be_bytes(), query, &0u16.to_be_bytes()].concat()
+}
+
+fn decode_plaintext(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    Ok(Reader::new(bytes).vector()?.to_vec())
+}
+
+fn encode_message(message_type: u8, key_id: &[u8], encrypted: &[u8]) -> Vec<u8> {
+    [
+        &[message_type],
+        (key_id.len() as u16).to_be_bytes().as_slice(),
+        key_id,
+        &(encrypted.len() as u16).to_be_bytes(),
+        encrypted,
+    ].concat()
+}
+
+fn decode_message(bytes: &[u8]) -> Result<(u8, &[u8], &[u8]), Error> {
+    let mut reader = Reader::new(bytes);
+
+    let message_type = reader.u8()?;
+    let key_id = reader.vector()?;
+    let encrypted = reader.vector()?;
+
+    Ok((message_type, key_id, encrypted))
+}
+
+fn message_aad(message_type: u8, key_id: &[u8]) -> Vec<u8> {
+    [&[message_type], (key_id.len() as u16).to_be_bytes().as_slice(), key_id].concat()
+}
+
+/// Reads the TLS presentation language structures used by ODoH.
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], Error> {
+        if self.bytes.len() < length {
+            error!("truncated ODoH structure");
+            return Err(Error::MalformedReply);
+        }
+
+        let (taken, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        let bytes = self.take(2)?;
+
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// A vector with a two byte length prefix.
+    fn vector(&mut self) -> Result<&'a [u8], Error> {
+        let length = self.u16()? as usize;
+
+        self.take(length)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_128_GCM};
+    use url::Url;
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    use super::*;
+    use crate::provider::message::Record;
+    use crate::testing::{https_server, Pki, Request, SERVER_NAME};
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    const TIMEOUT: Duration = Duration::from_secs(5);
+    const NAME: &str = "secret.example";
+
+    /// An ODoH target answering every A question with 192.0.2.1.
+    struct Target {
+        secret: Mutex<StaticSecret>,
+        configs: AtomicUsize,
+        queries: AtomicUsize,
+    }
+
+    impl Target {
+        fn new() -> Self {
+            Self {
+                secret: Mutex::new(StaticSecret::from([1; 32])),
+                configs: AtomicUsize::new(0),
+                queries: AtomicUsize::new(0),
+            }
+        }
+
+        /// Replaces the key pair, as targets do from time to time.
+        fn rotate(&self) {
+            *self.secret.lock().unwrap() = StaticSecret::from([2; 32]);
+        }
+
+        /// Contents of the one ObliviousDoHConfig of the target.
+        fn contents(&self) -> Vec<u8> {
+            let public_key = PublicKey::from(&*self.secret.lock().unwrap());
+
+            [
+                KEM_X25519_HKDF_SHA256.to_be_bytes().as_slice(),
+                &KDF_HKDF_SHA256.to_be_bytes(),
+                &AEAD_AES_128_GCM.to_be_bytes(),
+                &32u16.to_be_bytes(),
+                public_key.as_bytes(),
+            ].concat()
+        }
+
+        fn handle(&self, request: Request) -> (u16, Vec<u8>) {
+            if request.target == CONFIGS_PATH {
+                self.configs.fetch_add(1, Ordering::SeqCst);
+
+                let contents = self.contents();
+                let config = [CONFIG_VERSION.to_be_bytes().as_slice(), &(contents.len() as u16).to_be_bytes(), &contents].concat();
+
+                return (200, [(config.len() as u16).to_be_bytes().as_slice(), &config].concat());
+            }
+
+            match self.answer(&request.body) {
+                Some(response) => {
+                    self.queries.fetch_add(1, Ordering::SeqCst);
+                    (200, response)
+                }
+                None => (401, vec![]),
+            }
+        }
+
+        /// Decrypts the query, and encrypts the reply to it as in
+        /// https://www.rfc-editor.org/rfc/rfc9230#section-6.4
+        fn answer(&self, body: &[u8]) -> Option<Vec<u8>> {
+            let (message_type, key_id, encrypted) = decode_message(body).ok()?;
+
+            let contents = self.contents();
+            let expected_key_id = hpke::expand(&hpke::extract(&[], &contents), b"odoh key id", 32);
+
+            if message_type != MESSAGE_QUERY || key_id != expected_key_id || encrypted.len() < 32 {
+                return None;
+            }
+
+            let (enc, ciphertext) = encrypted.split_at(32);
+
+            let mut context = SenderContext::setup_base_recipient(&self.secret.lock().unwrap(), enc, b"odoh query").ok()?;
+
+            let plaintext = context.open(&message_aad(MESSAGE_QUERY, key_id), ciphertext).ok()?;
+
+            let mut message = Message::decode(&decode_plaintext(&plaintext).ok()?).ok()?;
+            message.flags |= 0x8080;
+            message.additional.clear();
+            message.answers.push(Record {
+                name: message.questions[0].name.clone(),
+                rtype: 1,
+                class: 1,
+                ttl: 300,
+                rdata: vec![192, 0, 2, 1],
+            });
+
+            let response_nonce = [7u8; NK];
+
+            let secret = context.export(b"odoh response", NK);
+            let salt = [plaintext.as_slice(), &(NK as u16).to_be_bytes(), &response_nonce].concat();
+            let prk = hpke::extract(&salt, &secret);
+
+            let key = LessSafeKey::new(UnboundKey::new(&AES_128_GCM, &hpke::expand(&prk, b"odoh key", NK)).ok()?);
+            let nonce = Nonce::try_assume_unique_for_key(&hpke::expand(&prk, b"odoh nonce", NN)).ok()?;
+
+            let mut reply = encode_plaintext(&message.encode().ok()?);
+
+            key.seal_in_place_append_tag(nonce, Aad::from(message_aad(MESSAGE_RESPONSE, &response_nonce)), &mut reply).ok()?;
+
+            Some(encode_message(MESSAGE_RESPONSE, &response_nonce, &reply))
+        }
+    }
+
+    /// A relay forwarding the queries to the target named in their URL, and
+    /// remembering what it saw of them.
+    struct Relay {
+        client: HttpClient,
+        seen: Mutex<Vec<(String, Vec<u8>)>>,
+    }
+
+    impl Relay {
+        async fn handle(&self, request: Request) -> (u16, Vec<u8>) {
+            let url = Url::parse(&format!("https://relay{}", request.target)).unwrap();
+            let parameter = |name| url.query_pairs().find(|(key, _)| key == name).map(|(_, value)| value.into_owned());
+
+            let (Some(host), Some(path)) = (parameter("targethost"), parameter("targetpath")) else {
+                return (400, vec![]);
+            };
+
+            self.seen.lock().unwrap().push((request.method, request.body.clone()));
+
+            match self.client.request_oblivious_message(&format!("https://{}{}", host, path), &[], &request.body).await {
+                Ok(response) => (200, response),
+                Err(_) => (401, vec![]),
+            }
+        }
+    }
+
+    struct Setup {
+        odoh: ObliviousDns,
+        target: Arc<Target>,
+        relay: Arc<Relay>,
+    }
+
+    async fn setup(pki: &Pki) -> Setup {
+        let target = Arc::new(Target::new());
+
+        let target_address = https_server(pki.server_config(&[b"http/1.1"]), {
+            let target = target.clone();
+            move |request| {
+                let target = target.clone();
+                async move { target.handle(request) }
+            }
+        }).await;
+
+        let target_url = format!("https://{}:{}/dns-query", SERVER_NAME, target_address.port());
+
+        let relay = Arc::new(Relay {
+            client: HttpClient::new(&[LOCALHOST], &Url::parse(&target_url).unwrap(), &Proxy::Direct, pki.verifier(), TIMEOUT).unwrap(),
+            seen: Mutex::new(vec![]),
+        });
+
+        let relay_address = https_server(pki.server_config(&[b"http/1.1"]), {
+            let relay = relay.clone();
+            move |request| {
+                let relay = relay.clone();
+                async move { relay.handle(request).await }
+            }
+        }).await;
+
+        let relay_url = format!("https://{}:{}/proxy{{?targethost,targetpath}}", SERVER_NAME, relay_address.port());
+
+        let settings = ObliviousSettings::new(&target_url, &relay_url, vec![LOCALHOST]);
+
+        let odoh = ObliviousDns::new(&settings, &[LOCALHOST], &Proxy::Direct, pki.verifier(), TIMEOUT).unwrap();
+
+        Setup { odoh, target, relay }
+    }
+
+    fn address(reply: &Message) -> Vec<u8> {
+        reply.answers[0].rdata.clone()
+    }
+
+    #[tokio::test]
+    async fn resolves_through_the_relay_without_showing_it_the_query() {
+        let pki = Pki::new();
+        let Setup { odoh, target, relay } = setup(&pki).await;
+
+        let reply = odoh.exchange(&Message::query(NAME, &crate::provider::DnsRecordType::A)).await.unwrap();
+
+        assert_eq!(reply.questions[0].name, format!("{}.", NAME));
+        assert_eq!(address(&reply), [192, 0, 2, 1]);
+        assert_eq!(target.configs.load(Ordering::SeqCst), 1);
+        assert_eq!(target.queries.load(Ordering::SeqCst), 1);
+
+        let seen = relay.seen.lock().unwrap();
+
+        assert_eq!(seen.len(), 1);
+        assert_eq!(seen[0].0, "POST");
+        assert!(!seen[0].1.windows(6).any(|window| window == b"secret"), "the relay saw the name asked");
+    }
+
+    #[tokio::test]
+    async fn reuses_the_config_of_the_target() {
+        let pki = Pki::new();
+        let Setup { odoh, target, .. } = setup(&pki).await;
+
+        for _ in 0..3 {
+            odoh.exchange(&Message::query(NAME, &crate::provider::DnsRecordType::A)).await.unwrap();
+        }
+
+        assert_eq!(target.configs.load(Ordering::SeqCst), 1);
+        assert_eq!(target.queries.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn fetches_the_config_again_once_the_target_rotates_its_key() {
+        let pki = Pki::new();
+        let Setup { odoh, target, .. } = setup(&pki).await;
+
+        let query = Message::query(NAME, &crate::provider::DnsRecordType::A);
+
+        odoh.exchange(&query).await.unwrap();
+
+        target.rotate();
+
+        assert!(odoh.exchange(&query).await.is_err());
+        assert_eq!(address(&odoh.exchange(&query).await.unwrap()), [192, 0, 2, 1]);
+        assert_eq!(target.configs.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,413 @@
+use base64::Engine;
+
+use doh_common::{CaaRecord, MxRecord, NsRecord, RecordSet, SoaRecord, SrvRecord, SvcbRecord, TxtRecord};
+
+use crate::provider::message::svcb_text;
+use crate::provider::{DnsEntryReply, DnsRecordType, DnsReply};
+
+/// Collects the answers of type `record_type` into typed records, skipping
+/// the ones whose data can't be parsed. `ttl` caps the TTL of every record,
+/// so that answers from the cache report what is left of it.
+pub fn record_set(reply: &DnsReply, record_type: DnsRecordType, ttl: u32) -> RecordSet {
+    let mut set = RecordSet::default();
+
+    let entries = reply.answers
+        .iter()
+        .filter(|entry| entry.r#type == record_type);
+
+    for entry in entries {
+        let name = owner(entry);
+        let ttl = entry.ttl.min(ttl);
+        let data = entry.data.as_str();
+
+        match record_type {
+            DnsRecordType::MX => {
+                if let Some((preference, exchange)) = data.split_once(' ') {
+                    if let Ok(preference) = preference.parse() {
+                        set.mx.push(MxRecord { name, ttl, preference, exchange: domain(exchange) });
+                    }
+                }
+            }
+            DnsRecordType::TXT => {
+                if let Some(strings) = character_strings(data) {
+                    set.txt.push(TxtRecord { name, ttl, strings });
+                }
+            }
+            DnsRecordType::SRV => {
+                if let Some(srv) = srv(name, ttl, data) {
+                    set.srv.push(srv);
+                }
+            }
+            DnsRecordType::CAA => {
+                if let Some((flags, tag, value)) = caa(data) {
+                    set.caa.push(CaaRecord { name, ttl, flags, tag, value });
+                }
+            }
+            DnsRecordType::NS => {
+                set.ns.push(NsRecord { name, ttl, nameserver: domain(data) });
+            }
+            DnsRecordType::SOA => {
+                if let Some(soa) = soa(name, ttl, data) {
+                    set.soa.push(soa);
+                }
+            }
+            DnsRecordType::SVCB => {
+                if let Some(svcb) = svcb(name, ttl, data) {
+                    set.svcb.push(svcb);
+                }
+            }
+            DnsRecordType::HTTPS => {
+                if let Some(https) = svcb(name, ttl, data) {
+                    set.https.push(https);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    set
+}
+
+fn owner(entry: &DnsEntryReply) -> String {
+    domain(&entry.name)
+}
+
+fn domain(name: &str) -> String {
+    let name = name.trim();
+
+    if name == "." {
+        name.to_string()
+    } else {
+        name.trim_end_matches('.').to_string()
+    }
+}
+
+fn srv(name: String, ttl: u32, data: &str) -> Option<SrvRecord> {
+    let fields: Vec<&str> = data.split_whitespace().collect();
+
+    match fields.as_slice() {
+        [priority, weight, port, target] => Some(SrvRecord {
+            name,
+            ttl,
+            priority: priority.parse().ok()?,
+            weight: weight.parse().ok()?,
+            port: port.parse().ok()?,
+            target: domain(target),
+        }),
+        _ => None,
+    }
+}
+
+fn soa(name: String, ttl: u32, data: &str) -> Option<SoaRecord> {
+    let fields: Vec<&str> = data.split_whitespace().collect();
+
+    match fields.as_slice() {
+        [mname, rname, serial, refresh, retry, expire, minimum] => Some(SoaRecord {
+            name,
+            ttl,
+            mname: domain(mname),
+            rname: domain(rname),
+            serial: serial.parse().ok()?,
+            refresh: refresh.parse().ok()?,
+            retry: retry.parse().ok()?,
+            expire: expire.parse().ok()?,
+            minimum: minimum.parse().ok()?,
+        }),
+        _ => None,
+    }
+}
+
+fn caa(data: &str) -> Option<(u8, String, String)> {
+    // Some upstreams send CAA in the generic form of RFC 3597
+    if let Some(rdata) = generic_rdata(data) {
+        let flags = *rdata.first()?;
+        let length = *rdata.get(1)? as usize;
+        let tag = rdata.get(2..2 + length)?;
+        let value = rdata.get(2 + length..)?;
+
+        return Some((flags, String::from_utf8_lossy(tag).into_owned(), String::from_utf8_lossy(value).into_owned()));
+    }
+
+    let mut fields = data.trim().splitn(3, ' ');
+
+    let flags = fields.next()?.parse().ok()?;
+    let tag = fields.next()?.to_string();
+    let value = fields.next()?;
+
+    Some((flags, tag, char_string(value)?))
+}
+
+/// Parses SVCB and HTTPS data in the presentation format of RFC 9460,
+/// section 2.1, or in the generic form some upstreams send them in.
+fn svcb(name: String, ttl: u32, data: &str) -> Option<SvcbRecord> {
+    let data = match generic_rdata(data) {
+        Some(rdata) => svcb_text(&rdata)?,
+        None => data.to_string(),
+    };
+
+    let tokens = tokens(&data)?;
+
+    let mut record = SvcbRecord {
+        name,
+        ttl,
+        priority: tokens.first()?.parse().ok()?,
+        target: domain(tokens.get(1)?),
+        mandatory: vec![],
+        alpn: vec![],
+        no_default_alpn: false,
+        port: 0,
+        ipv4_hints: vec![],
+        ech: vec![],
+        ipv6_hints: vec![],
+        other_params: vec![],
+    };
+
+    for param in &tokens[2..] {
+        let (key, raw) = param.split_once('=').unwrap_or((param, ""));
+        let value = char_string(raw)?;
+
+        match key {
+            "mandatory" => record.mandatory = value.split(',').map(String::from).collect(),
+            "alpn" => record.alpn = value_list(&value),
+            "no-default-alpn" => record.no_default_alpn = true,
+            "port" => record.port = value.parse().ok()?,
+            "ipv4hint" => record.ipv4_hints = value.split(',').map(String::from).collect(),
+            "ech" => record.ech = base64::engine::general_purpose::STANDARD.decode(&value).ok()?,
+            "ipv6hint" => record.ipv6_hints = value.split(',').map(String::from).collect(),
+            // Left escaped, as their values need not be text
+            _ => record.other_params.push((key.to_string(), raw.trim_matches('"').to_string())),
+        }
+    }
+
+    Some(record)
+}
+
+/// Splits TXT data into its character-strings. Quoted strings are unescaped,
+/// while data without quotes, as some upstreams send it, is a single string.
+fn character_strings(data: &str) -> Option<Vec<String>> {
+    if let Some(rdata) = generic_rdata(data) {
+        let mut strings = vec![];
+        let mut position = 0;
+
+        while position < rdata.len() {
+            let length = rdata[position] as usize;
+            let string = rdata.get(position + 1..position + 1 + length)?;
+            strings.push(String::from_utf8_lossy(string).into_owned());
+            position += 1 + length;
+        }
+
+        return Some(strings);
+    }
+
+    let data = data.trim();
+
+    if !data.starts_with('"') {
+        return Some(vec![data.to_string()]);
+    }
+
+    tokens(data)?
+        .into_iter()
+        .map(char_string)
+        .collect()
+}
+
+/// Splits presentation data on the blanks outside of quotes.
+fn tokens(data: &str) -> Option<Vec<&str>> {
+    let mut tokens = vec![];
+    let mut start = None;
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (i, c) in data.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+
+        match c {
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if let Some(start) = start.take() {
+                    tokens.push(&data[start..i]);
+                }
+
+                continue;
+            }
+            _ => {}
+        }
+
+        start.get_or_insert(i);
+    }
+
+    if quoted {
+        return None;
+    }
+
+    if let Some(start) = start {
+        tokens.push(&data[start..]);
+    }
+
+    Some(tokens)
+}
+
+/// The content of a character-string, quoted or not, with its escapes
+/// (RFC 1035, section 5.1) resolved.
+fn char_string(text: &str) -> Option<String> {
+    let mut string = vec![];
+    let mut bytes = text.bytes().peekable();
+
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'"' => {}
+            b'\\' => {
+                let digits: Vec<u8> = (0..3)
+                    .map_while(|_| bytes.next_if(|c| c.is_ascii_digit()))
+                    .collect();
+
+                if digits.len() == 3 {
+                    let value = digits.iter().fold(0u32, |acc, d| acc * 10 + (d - b'0') as u32);
+                    string.push(value.min(255) as u8);
+                } else if !digits.is_empty() {
+                    string.extend(digits);
+                } else {
+                    string.push(bytes.next()?);
+                }
+            }
+            other => string.push(other),
+        }
+    }
+
+    Some(String::from_utf8_lossy(&string).into_owned())
+}
+
+/// Splits a comma separated value-list (RFC 9460, appendix A.1), in which
+/// commas and backslashes within items are escaped.
+fn value_list(value: &str) -> Vec<String> {
+    let mut items = vec![];
+    let mut item = String::new();
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => item.extend(chars.next()),
+            ',' => items.push(std::mem::take(&mut item)),
+            _ => item.push(c),
+        }
+    }
+
+    items.push(item);
+    items
+}
+
+/// Decodes record data in the `\# length hex` form of RFC 3597, section 5.
+fn generic_rdata(data: &str) -> Option<Vec<u8>> {
+    let rest = data.trim().strip_prefix("\\#")?;
+
+    let mut fields = rest.split_whitespace();
+    let length: usize = fields.next()?.parse().ok()?;
+    let hex: String = fields.collect();
+
+    if hex.len() != length * 2 {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `rdata` in the generic form of RFC 3597.
+    fn generic(rdata: &[u8]) -> String {
+        let hex: String = rdata.iter().map(|byte| format!("{:02x}", byte)).collect();
+
+        format!("\\# {} {}", rdata.len(), hex)
+    }
+
+    fn parse(data: &str) -> Option<SvcbRecord> {
+        svcb("example.com".to_string(), 300, data)
+    }
+
+    // The examples of RFC 9460, appendix D, as upstreams send them
+
+    #[test]
+    fn parses_an_alias() {
+        let record = parse(&generic(b"\x00\x00\x03foo\x07example\x03com\x00")).unwrap();
+
+        assert_eq!((record.priority, record.target.as_str()), (0, "foo.example.com"));
+        assert!(record.alpn.is_empty() && record.other_params.is_empty());
+    }
+
+    #[test]
+    fn parses_mandatory_keys_alpn_and_ipv4_hints() {
+        let rdata = [
+            &b"\x00\x10\x03foo\x07example\x03org\x00"[..],
+            &[0x00, 0x00, 0x00, 0x04, 0x00, 0x01, 0x00, 0x04],
+            b"\x00\x01\x00\x09\x02h2\x05h3-19",
+            &[0x00, 0x04, 0x00, 0x04, 0xc0, 0x00, 0x02, 0x01],
+        ].concat();
+
+        for data in [generic(&rdata), String::from("16 foo.example.org. alpn=h2,h3-19 mandatory=ipv4hint,alpn ipv4hint=192.0.2.1")] {
+            let record = parse(&data).unwrap();
+
+            assert_eq!(record.priority, 16);
+            assert_eq!(record.target, "foo.example.org");
+            assert_eq!(record.alpn, ["h2", "h3-19"]);
+            assert_eq!(record.ipv4_hints, ["192.0.2.1"]);
+            assert_eq!(record.mandatory.len(), 2);
+        }
+    }
+
+    #[test]
+    fn parses_a_port_ipv6_hints_and_unknown_keys() {
+        let rdata = [
+            &b"\x00\x01\x03foo\x07example\x03com\x00"[..],
+            &[0x00, 0x03, 0x00, 0x02, 0x00, 0x35],
+            &[0x00, 0x06, 0x00, 0x10, 0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01],
+            b"\x02\x9b\x00\x09hello\xd2qoo",
+        ].concat();
+
+        let record = parse(&generic(&rdata)).unwrap();
+
+        assert_eq!(record.port, 53);
+        assert_eq!(record.ipv6_hints, ["2001:db8::1"]);
+        assert_eq!(record.other_params, [("key667".to_string(), "hello\\210qoo".to_string())]);
+    }
+
+    #[test]
+    fn unescapes_alpn_ids() {
+        let escaped = [
+            r#"16 foo.example.org. alpn="f\\\\oo\\,bar,h2""#,
+            r#"16 foo.example.org. alpn=f\\\092oo\092,bar,h2"#,
+        ];
+
+        for data in escaped {
+            assert_eq!(parse(data).unwrap().alpn, ["f\\oo,bar", "h2"], "{}", data);
+        }
+
+        let rdata = b"\x00\x10\x03foo\x07example\x03org\x00\x00\x01\x00\x0c\x08f\\oo,bar\x02h2";
+
+        assert_eq!(parse(&generic(rdata)).unwrap().alpn, ["f\\oo,bar", "h2"]);
+    }
+
+    #[test]
+    fn skips_malformed_records() {
+        let target = &b"\x00\x01\x03foo\x07example\x03com\x00"[..];
+
+        // Keys out of order, repeated, or with a value cut short
+        let swapped = [target, &[0x00, 0x04, 0x00, 0x04, 0xc0, 0x00, 0x02, 0x01, 0x00, 0x03, 0x00, 0x02, 0x00, 0x35]].concat();
+        let repeated = [target, &[0x00, 0x03, 0x00, 0x02, 0x00, 0x35, 0x00, 0x03, 0x00, 0x02, 0x00, 0x35]].concat();
+        let truncated = [target, &[0x00, 0x03, 0x00, 0x02, 0x00]].concat();
+
+        assert!(parse(&generic(&swapped)).is_none());
+        assert!(parse(&generic(&repeated)).is_none());
+        assert!(parse(&generic(&truncated)).is_none());
+        // A length that does not match the data
+        assert!(parse("\\# 4 0001").is_none());
+    }
+}
@@ -0,0 +1,36 @@
+use base64::Engine;
+use tracing::instrument;
+use doh_common::error::Error;
+use crate::client::{expand_url_template, HttpClient, HttpMethod};
+use crate::provider::message::Message;
+use crate::provider::query_template;
+use crate::settings::HttpsSettings;
+
+/// Standard DNS-over-HTTPS as specified by RFC 8484, exchanging binary DNS
+/// messages with any compliant server (Quad9, self-hosted resolvers, ...).
+pub struct Rfc8484;
+
+impl Rfc8484 {
+    #[instrument(skip_all)]
+    pub async fn exchange(client: &HttpClient, settings: &HttpsSettings, query: &Message) -> Result<Message, Error> {
+
+        let query = query.encode()?;
+
+        let url = match settings.method() {
+            HttpMethod::Get => {
+                let encoded = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(&query);
+
+                expand_url_template(&query_template(settings.url(), "dns"), &[("dns", &encoded)])
+            }
+            HttpMethod::Post => expand_url_template(settings.url(), &[]),
+        };
+
+        let body = client.request_message(
+            &url,
+            settings.method(),
+            settings.headers(),
+            &query).await?;
+
+        Message::decode(&body)
+    }
+}
@@ -0,0 +1,123 @@
+use ring::rand::SecureRandom;
+
+use doh_common::SrvRecord;
+
+/// Orders SRV records the way clients should try their targets (RFC 2782):
+/// by priority, lowest first, and within a priority by a weighted random
+/// selection. A single record with "." as target means the service is
+/// decidedly not available at the name, which leaves nothing to try.
+pub fn order_targets(mut records: Vec<SrvRecord>, random: &dyn SecureRandom) -> Vec<SrvRecord> {
+    if records.len() == 1 && records[0].target == "." {
+        return vec![];
+    }
+
+    records.retain(|record| record.target != ".");
+    records.sort_by_key(|record| record.priority);
+
+    let mut ordered = Vec::with_capacity(records.len());
+
+    while !records.is_empty() {
+        let priority = records[0].priority;
+        let end = records
+            .iter()
+            .position(|record| record.priority != priority)
+            .unwrap_or(records.len());
+
+        let mut group: Vec<SrvRecord> = records.drain(..end).collect();
+
+        // Records of weight 0 go first, so they have a very small chance of
+        // being selected before the others
+        group.sort_by_key(|record| record.weight != 0);
+
+        while !group.is_empty() {
+            let total: u64 = group.iter().map(|record| record.weight as u64).sum();
+            let chosen = uniform(random, total);
+
+            let mut running = 0u64;
+            let position = group
+                .iter()
+                .position(|record| {
+                    running += record.weight as u64;
+                    running >= chosen
+                })
+                .unwrap_or(0);
+
+            ordered.push(group.remove(position));
+        }
+    }
+
+    ordered
+}
+
+/// A number between 0 and `max`, both included.
+fn uniform(random: &dyn SecureRandom, max: u64) -> u64 {
+    let mut bytes = [0u8; 8];
+
+    if random.fill(&mut bytes).is_err() {
+        return 0;
+    }
+
+    u64::from_be_bytes(bytes) % (max + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::UnsafeCell;
+
+    // The only way to give ring a fixed sequence of random numbers
+    #[allow(deprecated)]
+    use ring::test::rand::FixedSliceSequenceRandom;
+
+    use super::*;
+
+    fn srv(target: &str, priority: u16, weight: u16) -> SrvRecord {
+        SrvRecord { name: "_sip._tcp.example.test.".to_string(), ttl: 300, priority, weight, port: 5060, target: target.to_string() }
+    }
+
+    /// The targets in the order given by `draws`, the random numbers taken
+    /// one per selection. Fails unless every draw is taken.
+    #[allow(deprecated)]
+    fn ordered(records: Vec<SrvRecord>, draws: &[u64]) -> Vec<String> {
+        let draws: Vec<[u8; 8]> = draws.iter().map(|draw| draw.to_be_bytes()).collect();
+        let bytes: Vec<&[u8]> = draws.iter().map(|draw| draw.as_slice()).collect();
+
+        let random = FixedSliceSequenceRandom { bytes: &bytes, current: UnsafeCell::new(0) };
+
+        order_targets(records, &random).into_iter().map(|record| record.target).collect()
+    }
+
+    #[test]
+    fn lowest_priority_first_then_by_running_sum_of_weights() {
+        let records = || vec![srv("a.", 10, 60), srv("b.", 10, 30), srv("c.", 10, 10), srv("backup.", 20, 5), srv("first.", 5, 0)];
+
+        // Out of 0..=100, 95 is past the 90 of a and b together; out of
+        // 0..=90, 0 picks the first record left
+        assert_eq!(ordered(records(), &[0, 95, 0, 0, 0]), ["first.", "c.", "a.", "b.", "backup."]);
+        // 60 still falls within a, 61 past it, and then past a out of 0..=70
+        assert_eq!(ordered(records(), &[0, 60, 0, 0, 0]), ["first.", "a.", "b.", "c.", "backup."]);
+        assert_eq!(ordered(records(), &[0, 61, 61, 0, 0]), ["first.", "b.", "c.", "a.", "backup."]);
+    }
+
+    #[test]
+    fn weight_zero_is_only_drawn_by_zero() {
+        let records = || vec![srv("heavy.", 1, 50), srv("x.", 1, 0), srv("y.", 1, 0)];
+
+        assert_eq!(ordered(records(), &[1, 0, 0]), ["heavy.", "x.", "y."]);
+        assert_eq!(ordered(records(), &[0, 7, 0]), ["x.", "heavy.", "y."]);
+        assert_eq!(ordered(records(), &[0, 0, 0]), ["x.", "y.", "heavy."]);
+    }
+
+    #[test]
+    fn equal_weights_of_zero_keep_their_order() {
+        let records = vec![srv("x.", 1, 0), srv("y.", 1, 0), srv("z.", 1, 0)];
+
+        assert_eq!(ordered(records, &[0, 0, 0]), ["x.", "y.", "z."]);
+    }
+
+    #[test]
+    fn a_single_dot_target_leaves_nothing_to_try() {
+        assert!(ordered(vec![srv(".", 0, 0)], &[]).is_empty());
+        // Among other records, it is only left out
+        assert_eq!(ordered(vec![srv(".", 0, 0), srv("a.", 1, 1)], &[0]), ["a."]);
+    }
+}
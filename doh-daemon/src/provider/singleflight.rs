@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use tokio::sync::broadcast;
+use tracing::debug;
+
+/// Runs a single lookup per key at a time. Callers asking for a key that is
+/// already being looked up wait for that lookup and share its result instead
+/// of starting their own.
+#[derive(Debug)]
+pub struct SingleFlight<K, V> {
+    flights: Mutex<HashMap<K, broadcast::Sender<V>>>,
+    coalesced: AtomicU64,
+}
+
+/// The lookup of a key, taken out of the flights once it ends or is dropped
+/// so a lookup that was cancelled does not leave its waiters stuck.
+struct Flight<'a, K: Eq + Hash, V> {
+    flights: &'a Mutex<HashMap<K, broadcast::Sender<V>>>,
+    key: Option<K>,
+}
+
+impl<K: Eq + Hash, V> Flight<'_, K, V> {
+    fn land(mut self) -> Option<broadcast::Sender<V>> {
+        let key = self.key.take()?;
+
+        self.flights.lock().unwrap().remove(&key)
+    }
+}
+
+impl<K: Eq + Hash, V> Drop for Flight<'_, K, V> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.flights.lock().unwrap().remove(&key);
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone + std::fmt::Debug, V: Clone> SingleFlight<K, V> {
+    pub fn new() -> Self {
+        Self { flights: Mutex::new(HashMap::new()), coalesced: AtomicU64::new(0) }
+    }
+
+    /// Runs `lookup` unless the same key is being looked up already. The flag
+    /// tells whether the result came from the lookup of another caller.
+    pub async fn run<F>(&self, key: K, lookup: F) -> (V, bool)
+    where F: Future<Output = V> {
+
+        let waiting = {
+            let mut flights = self.flights.lock().unwrap();
+
+            match flights.get(&key) {
+                Some(sender) => Some(sender.subscribe()),
+                None => {
+                    flights.insert(key.clone(), broadcast::channel(1).0);
+                    None
+                }
+            }
+        };
+
+        if let Some(mut receiver) = waiting {
+            if let Ok(result) = receiver.recv().await {
+                debug!("coalesced lookup of {:?}", key);
+
+                self.coalesced.fetch_add(1, Ordering::Relaxed);
+
+                return (result, true);
+            }
+
+            // The lookup was cancelled before it ended
+            return (lookup.await, false);
+        }
+
+        let flight = Flight { flights: &self.flights, key: Some(key) };
+
+        let result = lookup.await;
+
+        // Taken out before the result is sent, so that the callers coming
+        // later start a lookup of their own rather than wait for nothing
+        if let Some(sender) = flight.land() {
+            let _ = sender.send(result.clone());
+        }
+
+        (result, false)
+    }
+
+    /// How many callers were given the result of another lookup.
+    pub fn coalesced(&self) -> u64 {
+        self.coalesced.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,209 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::sync::mpsc::UnboundedSender;
+
+use reqwest::Url;
+use tracing::{debug, error, info, instrument, warn};
+use doh_common::error::Error;
+use crate::client::certificate::CertificateVerifier;
+use crate::client::tls::TlsClient;
+use crate::client::{expand_url_template, HttpClient};
+use crate::provider::classic::ClassicDns;
+use crate::provider::dot::DnsOverTls;
+use crate::provider::ecs::Subnet;
+use crate::provider::health::HealthTracker;
+use crate::provider::json::JsonApi;
+use crate::provider::message::Message;
+use crate::provider::odoh::ObliviousDns;
+use crate::provider::rfc8484::Rfc8484;
+use crate::provider::{DnsRecordType, DnsReply, ResolverEvent};
+use crate::settings::{HealthSettings, HttpsSettings, ResponseFormat, RetrySettings, Transport, UpstreamSettings};
+
+/// A server the resolver forwards questions to, built from its description
+/// in the settings. It owns the connection to the server for as long as the
+/// daemon runs.
+#[derive(Debug)]
+pub struct Upstream {
+    settings: UpstreamSettings,
+    connector: Connector,
+    health: HealthTracker,
+    retry: RetrySettings,
+    random: SystemRandom,
+    // Whether queries ask for the DNSSEC records, to validate answers locally.
+    dnssec_ok: bool,
+    // Set once the upstream sent a reply without padding, to warn only once.
+    unpadded: AtomicBool,
+    events: UnboundedSender<ResolverEvent>,
+}
+
+#[derive(Debug)]
+enum Connector {
+    Https(HttpClient, HttpsSettings),
+    Tls(TlsClient),
+    Oblivious(ObliviousDns),
+    Plain(ClassicDns),
+}
+
+impl Upstream {
+    pub fn new(settings: UpstreamSettings,
+               health: &HealthSettings,
+               retry: &RetrySettings,
+               dnssec_ok: bool,
+               events: UnboundedSender<ResolverEvent>) -> Result<Self, Error> {
+        let verifier = CertificateVerifier::new(settings.certificates())?;
+
+        let connector = match settings.transport() {
+            Transport::Tls(tls) => {
+                Connector::Tls(TlsClient::new(tls.hostname(), tls.port(), settings.bootstrap(), settings.proxy(), verifier, settings.timeout())?)
+            }
+            Transport::Plain(plain) => Connector::Plain(ClassicDns::servers(plain, settings.bootstrap())),
+            Transport::Oblivious(oblivious) => {
+                Connector::Oblivious(ObliviousDns::new(oblivious, settings.bootstrap(), settings.proxy(), verifier, settings.timeout())?)
+            }
+            Transport::Https(https) => {
+                let url = Url::parse(&expand_url_template(https.url(), &[]))?;
+
+                Connector::Https(HttpClient::new(settings.bootstrap(), &url, settings.proxy(), verifier, settings.timeout())?, https.clone())
+            }
+        };
+
+        Ok(Self {
+            settings,
+            connector,
+            health: HealthTracker::new(health),
+            retry: retry.clone(),
+            random: SystemRandom::new(),
+            dnssec_ok,
+            unpadded: AtomicBool::new(false),
+            events,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        self.settings.name()
+    }
+
+    pub fn health(&self) -> &HealthTracker {
+        &self.health
+    }
+
+    /// Asks the question, again after the failures that may not last, waiting
+    /// longer before each retry or as long as the upstream asked to.
+    #[instrument(skip(self), fields(upstream = self.settings.name()))]
+    pub async fn resolve(&self, domain: &str, record_type: DnsRecordType) -> Result<DnsReply, Error> {
+        let mut backoff = self.retry.backoff();
+        let mut attempt = 1;
+
+        loop {
+            let error = match self.resolve_once(domain, record_type).await {
+                Err(e) if e.is_transient() && attempt < self.retry.attempts() => e,
+                result => return result,
+            };
+
+            let delay = match error.retry_after() {
+                // Better to move on to the next upstream than to keep the caller waiting
+                Some(delay) if delay > self.retry.max_backoff() => {
+                    warn!("upstream {} asks to wait {:?} before the next query", self.name(), delay);
+                    return Err(error);
+                }
+                Some(delay) => delay,
+                None => self.jitter(backoff),
+            };
+
+            debug!("retrying {} in {:?} after {}", domain, delay, error);
+
+            tokio::time::sleep(delay).await;
+
+            backoff = (backoff * 2).min(self.retry.max_backoff());
+            attempt += 1;
+        }
+    }
+
+    async fn resolve_once(&self, domain: &str, record_type: DnsRecordType) -> Result<DnsReply, Error> {
+        let subnet = Subnet::for_policy(self.settings.client_subnet(), self.settings.bootstrap()).await;
+
+        if let Connector::Https(client, https) = &self.connector {
+            if let ResponseFormat::Json = https.format() {
+                return JsonApi::resolve(client, https, domain, record_type, subnet.as_ref())
+                    .await
+                    .map_err(|e| self.alert(e));
+            }
+        }
+
+        let query = Message::query(domain, &record_type)
+            .with_client_subnet(subnet.as_ref())
+            .with_dnssec_ok(self.dnssec_ok);
+
+        self.exchange(query).await?.into_reply()
+    }
+
+    /// Sends a binary DNS message, which the JSON APIs have no way to carry,
+    /// padded as configured.
+    pub async fn exchange(&self, query: Message) -> Result<Message, Error> {
+        let padding = self.settings.padding_block_size();
+        let query = &query.with_padding(padding);
+
+        let reply = match &self.connector {
+            Connector::Https(client, https) => match https.format() {
+                ResponseFormat::Json => {
+                    error!("upstream {} only speaks JSON", self.name());
+                    Err(Error::UpstreamError)
+                }
+                ResponseFormat::Message => Rfc8484::exchange(client, https, query).await,
+            },
+            Connector::Tls(client) => DnsOverTls::exchange(client, query).await,
+            Connector::Oblivious(oblivious) => oblivious.exchange(query).await,
+            Connector::Plain(classic) => classic.exchange(query).await.map(|(message, _)| message),
+        }.map_err(|e| self.alert(e))?;
+
+        // Servers are to pad the replies to padded queries (RFC 8467 section 4.1)
+        if padding > 0 && !reply.is_padded() && !self.unpadded.swap(true, Ordering::Relaxed) {
+            warn!("upstream {} does not pad its replies, their length may give the names away", self.name());
+        }
+
+        Ok(reply)
+    }
+
+    /// Connects to the server ahead of the first query.
+    #[instrument(skip(self), fields(upstream = self.settings.name()))]
+    pub async fn warm_up(&self) {
+        let result = match &self.connector {
+            Connector::Https(client, https) => client.warm_up(&expand_url_template(https.url(), &[])).await,
+            Connector::Tls(client) => client.warm_up().await,
+            Connector::Oblivious(oblivious) => oblivious.warm_up().await,
+            // Nothing to set up ahead of a datagram
+            Connector::Plain(_) => Ok(()),
+        };
+
+        match result {
+            Ok(_) => info!("connection to {} ready", self.name()),
+            Err(e) => warn!("unable to warm up connection to {}: {}", self.name(), self.alert(e)),
+        }
+    }
+
+    /// A delay between half of `backoff` and all of it, so the clients that
+    /// failed together do not all come back at the same time.
+    fn jitter(&self, backoff: Duration) -> Duration {
+        let mut random = [0u8; 4];
+
+        if self.random.fill(&mut random).is_err() {
+            return backoff;
+        }
+
+        let half = backoff / 2;
+
+        half + half.mul_f64(u32::from_be_bytes(random) as f64 / u32::MAX as f64)
+    }
+
+    /// Announces a certificate matching none of the pins, which may be an
+    /// interception attempt the user should hear about.
+    fn alert(&self, error: Error) -> Error {
+        if let Error::PinMismatch = error {
+            let _ = self.events.send(ResolverEvent::PinMismatch { upstream: self.name().to_string() });
+        }
+
+        error
+    }
+}
@@ -0,0 +1,291 @@
+//! Local stand-ins for the servers the daemon talks to, shared by the tests.
+
+use std::future::Future;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+
+use rcgen::{BasicConstraints, CertificateParams, CertifiedKey, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use tempfile::TempDir;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+
+use crate::client::certificate::CertificateVerifier;
+use crate::database::DatabaseService;
+use crate::settings::{ApplicationSettings, CertificateSettings};
+
+/// Host name the test servers answer for.
+pub const SERVER_NAME: &str = "doh.test";
+
+/// A private CA with the server and client certificates it issued, and
+/// another CA trusted by nobody, written to PEM files in a directory removed
+/// along with the value.
+pub struct Pki {
+    directory: TempDir,
+    ca: CertifiedKey,
+    server: CertifiedKey,
+    client: CertifiedKey,
+}
+
+impl Pki {
+    pub fn new() -> Self {
+        let ca = authority("Test CA");
+        let server = issue(&ca, ExtendedKeyUsagePurpose::ServerAuth, &[SERVER_NAME, "127.0.0.1"]);
+        let client = issue(&ca, ExtendedKeyUsagePurpose::ClientAuth, &["client.test"]);
+
+        let directory = tempfile::tempdir().unwrap();
+        let write = |file: &str, content: String| std::fs::write(directory.path().join(file), content).unwrap();
+
+        write("ca.pem", ca.cert.pem());
+        write("other-ca.pem", authority("Other CA").cert.pem());
+        write("client.pem", client.cert.pem());
+        write("client.key", client.key_pair.serialize_pem());
+
+        Self { directory, ca, server, client }
+    }
+
+    pub fn path(&self, file: &str) -> String {
+        self.directory.path().join(file).to_string_lossy().into_owned()
+    }
+
+    pub fn ca_bundle(&self) -> String {
+        self.path("ca.pem")
+    }
+
+    /// A CA bundle without the CA of this PKI.
+    pub fn other_ca_bundle(&self) -> String {
+        self.path("other-ca.pem")
+    }
+
+    /// The certificate and key files of the client.
+    pub fn client_files(&self) -> (String, String) {
+        (self.path("client.pem"), self.path("client.key"))
+    }
+
+    pub fn client_certificate(&self) -> CertificateDer<'static> {
+        self.client.cert.der().clone()
+    }
+
+    /// The certificate settings of an upstream trusting this CA only.
+    pub fn settings(&self) -> CertificateSettings {
+        CertificateSettings::new(Some(&self.ca_bundle()), None, vec![])
+    }
+
+    pub fn verifier(&self) -> Arc<CertificateVerifier> {
+        CertificateVerifier::new(&self.settings()).unwrap()
+    }
+
+    pub fn server_certificate(&self) -> CertificateDer<'static> {
+        self.server.cert.der().clone()
+    }
+
+    /// The settings of a daemon whose only provider is the DoH server at
+    /// `address`, speaking binary messages, followed by the sections `extra`.
+    pub fn application_settings(&self, address: SocketAddr, extra: &str) -> ApplicationSettings {
+        ApplicationSettings::parse(&format!("[resolver]\nprovider=stub\n\n{}\n{}", self.provider_section("stub", address), extra))
+    }
+
+    /// The section of a provider `name`, the DoH server at `address`
+    /// speaking binary messages.
+    pub fn provider_section(&self, name: &str, address: SocketAddr) -> String {
+        format!(
+            "[provider.{}]\nurl=https://{}:{}/dns-query\nbootstrap={}\nformat=message\nca_bundle={}\n",
+            name,
+            SERVER_NAME,
+            address.port(),
+            address.ip(),
+            self.ca_bundle())
+    }
+
+    /// TLS configuration of a server presenting the server certificate.
+    pub fn server_config(&self, alpn: &[&[u8]]) -> Arc<ServerConfig> {
+        self.config(alpn, false)
+    }
+
+    /// Same as `server_config`, for a server requiring a client certificate
+    /// issued by the CA.
+    pub fn mtls_server_config(&self, alpn: &[&[u8]]) -> Arc<ServerConfig> {
+        self.config(alpn, true)
+    }
+
+    /// Same as `server_config`, sending `chain` after the server certificate
+    /// instead of the CA.
+    pub fn server_config_with_chain(&self, alpn: &[&[u8]], chain: &[CertificateDer<'static>]) -> Arc<ServerConfig> {
+        tls_config(&self.server, chain, alpn, None)
+    }
+
+    fn config(&self, alpn: &[&[u8]], client_auth: bool) -> Arc<ServerConfig> {
+        let client_roots = client_auth.then(|| {
+            let mut roots = RootCertStore::empty();
+            roots.add(self.ca.cert.der().clone()).unwrap();
+            roots
+        });
+
+        tls_config(&self.server, &[self.ca.cert.der().clone()], alpn, client_roots)
+    }
+}
+
+/// TLS configuration of a server presenting the certificate of `server`
+/// followed by `chain`, and requiring a client certificate issued by one of
+/// `client_roots` when there are any.
+pub fn tls_config(server: &CertifiedKey, chain: &[CertificateDer<'static>], alpn: &[&[u8]], client_roots: Option<RootCertStore>) -> Arc<ServerConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .unwrap();
+
+    let builder = match client_roots {
+        Some(roots) => builder.with_client_cert_verifier(WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).build().unwrap()),
+        None => builder.with_no_client_auth(),
+    };
+
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(server.key_pair.serialize_der()));
+    let certificates = std::iter::once(server.cert.der().clone()).chain(chain.iter().cloned()).collect();
+
+    let mut config = builder.with_single_cert(certificates, key).unwrap();
+
+    config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+
+    Arc::new(config)
+}
+
+/// A database of its own, in memory.
+pub async fn database() -> DatabaseService {
+    let pool = async_sqlite::PoolBuilder::new()
+        .path(":memory:")
+        .num_conns(1)
+        .open()
+        .await
+        .unwrap();
+
+    let database = DatabaseService::new(pool);
+    database.create_tables().await.unwrap();
+
+    database
+}
+
+/// An HTTP/1.1 request received by [`https_server`].
+pub struct Request {
+    pub method: String,
+    /// Path and query of the request.
+    pub target: String,
+    pub body: Vec<u8>,
+    /// The certificate the client presented during the handshake.
+    pub client_certificate: Option<CertificateDer<'static>>,
+}
+
+/// Serves HTTP/1.1 over TLS on a local port, answering every request with the
+/// status and body `handler` returns for it.
+pub async fn https_server<H, F>(config: Arc<ServerConfig>, handler: H) -> SocketAddr
+where
+    H: Fn(Request) -> F + Clone + Send + Sync + 'static,
+    F: Future<Output = (u16, Vec<u8>)> + Send,
+{
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let acceptor = TlsAcceptor::from(config);
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let acceptor = acceptor.clone();
+            let handler = handler.clone();
+
+            tokio::spawn(async move {
+                if let Ok(stream) = acceptor.accept(stream).await {
+                    let client_certificate = stream.get_ref().1.peer_certificates().and_then(|chain| chain.first()).cloned();
+
+                    let _ = serve_http(BufReader::new(stream), client_certificate, handler).await;
+                }
+            });
+        }
+    });
+
+    address
+}
+
+async fn serve_http<S, H, F>(mut stream: BufReader<S>, client_certificate: Option<CertificateDer<'static>>, handler: H) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    H: Fn(Request) -> F,
+    F: Future<Output = (u16, Vec<u8>)>,
+{
+    loop {
+        let mut line = String::new();
+
+        if stream.read_line(&mut line).await? == 0 {
+            return Ok(());
+        }
+
+        let mut fields = line.split_whitespace();
+        let method = fields.next().unwrap_or_default().to_string();
+        let target = fields.next().unwrap_or_default().to_string();
+
+        let mut length = 0;
+
+        loop {
+            line.clear();
+            stream.read_line(&mut line).await?;
+
+            if line.trim().is_empty() {
+                break;
+            }
+
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    length = value.trim().parse().unwrap_or(0);
+                }
+            }
+        }
+
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).await?;
+
+        let (status, body) = handler(Request { method, target, body, client_certificate: client_certificate.clone() }).await;
+
+        let mut response = format!("HTTP/1.1 {} Stub\r\ncontent-length: {}\r\n\r\n", status, body.len()).into_bytes();
+        response.extend_from_slice(&body);
+
+        stream.get_mut().write_all(&response).await?;
+        stream.get_mut().flush().await?;
+    }
+}
+
+/// A self-signed root CA.
+pub fn authority(name: &str) -> CertifiedKey {
+    let key_pair = KeyPair::generate().unwrap();
+    let cert = ca_params(name).self_signed(&key_pair).unwrap();
+
+    CertifiedKey { cert, key_pair }
+}
+
+/// An intermediate CA issued by `ca`.
+pub fn intermediate(ca: &CertifiedKey, name: &str) -> CertifiedKey {
+    let key_pair = KeyPair::generate().unwrap();
+    let cert = ca_params(name).signed_by(&key_pair, &ca.cert, &ca.key_pair).unwrap();
+
+    CertifiedKey { cert, key_pair }
+}
+
+fn ca_params(name: &str) -> CertificateParams {
+    let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.distinguished_name.push(DnType::CommonName, name);
+
+    params
+}
+
+/// A certificate of `names` issued by `ca` for `usage`.
+pub fn issue(ca: &CertifiedKey, usage: ExtendedKeyUsagePurpose, names: &[&str]) -> CertifiedKey {
+    let key_pair = KeyPair::generate().unwrap();
+
+    let mut params = CertificateParams::new(names.iter().map(|name| name.to_string()).collect::<Vec<_>>()).unwrap();
+    params.extended_key_usages = vec![usage];
+
+    let cert = params.signed_by(&key_pair, &ca.cert, &ca.key_pair).unwrap();
+
+    CertifiedKey { cert, key_pair }
+}
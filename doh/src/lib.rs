@@ -44,14 +44,63 @@ impl HostHooks for DoHHost {
                 message.body().deserialize::<Host>()
             });
 
-        match result {
-            Ok(host) => Response::Success(host),
-            Err(_err) => Response::NotFound
-        }
+        response(result)
     }
 
-    fn get_host_by_addr(_addr: IpAddr) -> Response<Host> {
-        Response::NotFound
+    fn get_hosts_by_name(name: &str) -> Response<Vec<Host>> {
+
+        let result = Connection::system()
+            .and_then(|connection: Connection| {
+
+               connection.call_method(
+                    Some("com.glaciaos.NameResolver"),
+                    "/com/glaciaos/NameResolver",
+                    Some("com.glaciaos.NameResolver"),
+                    "ResolveNameAll",
+                    &(std::process::id(), name),
+                )
+            })
+            .and_then(|message| {
+
+                message.body().deserialize::<Vec<Host>>()
+            });
+
+        response(result)
+    }
+
+    fn get_host_by_addr(addr: IpAddr) -> Response<Host> {
+
+        let result = Connection::system()
+            .and_then(|connection: Connection| {
+
+               connection.call_method(
+                    Some("com.glaciaos.NameResolver"),
+                    "/com/glaciaos/NameResolver",
+                    Some("com.glaciaos.NameResolver"),
+                    "ResolveAddress",
+                    &(std::process::id(), addr.to_string()),
+                )
+            })
+            .and_then(|message| {
+
+                message.body().deserialize::<Host>()
+            });
+
+        response(result)
+    }
+}
+
+/// Maps the reply of the daemon to the NSS status its error is named after.
+fn response<T>(result: zbus::Result<T>) -> Response<T> {
+    match result {
+        Ok(value) => Response::Success(value),
+        Err(zbus::Error::MethodError(name, _, _)) => match name.as_str() {
+            "com.glaciaos.NameResolver.NotFound" => Response::NotFound,
+            "com.glaciaos.NameResolver.TryAgain" => Response::TryAgain,
+            _ => Response::Unavail,
+        },
+        // The daemon is not running, let the next source answer
+        Err(_err) => Response::Unavail
     }
 }
 
@@ -1,5 +1,6 @@
 use std::mem;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ptr;
 
 
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
@@ -12,6 +13,22 @@ pub struct Host {
     pub name: String,
     pub aliases: Vec<String>,
     pub addresses: Addresses,
+    /// Seconds the addresses may be cached.
+    pub ttl: u32,
+    /// The name owning the addresses once aliases are followed, the official
+    /// name of the host.
+    pub canonical_name: String,
+}
+
+impl Host {
+    /// The canonical name, or the name looked up when there is none.
+    pub fn official_name(&self) -> &str {
+        if self.canonical_name.is_empty() {
+            &self.name
+        } else {
+            &self.canonical_name
+        }
+    }
 }
 
 #[derive(PartialEq)]
@@ -63,7 +80,9 @@ impl<'de> Deserialize<'de> for Addresses {
     {
         let str = String::deserialize(deserializer)?;
 
-        return if str.contains(".") {
+        if str.is_empty() {
+            Ok(Addresses::V4(Vec::new()))
+        } else if str.contains(".") {
             let mut result = Vec::new();
             for part in str.split("_") {
                 let addr = part.parse::<Ipv4Addr>().unwrap();
@@ -84,7 +103,7 @@ impl<'de> Deserialize<'de> for Addresses {
 
 impl ToC<CHost> for Host {
     unsafe fn to_c(&self, hostent: *mut CHost, buffer: &mut CBuffer) -> std::io::Result<()> {
-        (*hostent).name = buffer.write_str(&self.name)?;
+        (*hostent).name = buffer.write_str(self.official_name())?;
         (*hostent).h_aliases = buffer.write_strs(&self.aliases[..])?;
 
         let (addr_len, count) = match &self.addresses {
@@ -103,6 +122,7 @@ impl ToC<CHost> for Host {
         };
 
         let ptr_size = mem::size_of::<*mut libc::c_char>() as isize;
+        buffer.align(ptr_size as usize)?;
         let mut array_pos =
             buffer.reserve(ptr_size * (count as isize + 1))? as *mut *mut libc::c_char;
         (*hostent).h_addr_list = array_pos;
@@ -146,11 +166,88 @@ impl ToC<CHost> for Host {
     }
 }
 
+impl ToC<*mut CAddrTuple> for Vec<Host> {
+    unsafe fn to_c(&self, pat: *mut *mut CAddrTuple, buffer: &mut CBuffer) -> std::io::Result<()> {
+        let count: usize = self.iter().map(|host| host.addresses.len()).sum();
+
+        buffer.align(mem::align_of::<CAddrTuple>())?;
+
+        let first = buffer.reserve((mem::size_of::<CAddrTuple>() * count) as isize)? as *mut CAddrTuple;
+        let mut tuple = first;
+
+        for host in self {
+            let name = buffer.write_str(host.official_name())?;
+
+            let addresses: Vec<(libc::c_int, Vec<u8>)> = match &host.addresses {
+                Addresses::V4(addrs) => addrs.iter().map(|a| (libc::AF_INET, a.octets().to_vec())).collect(),
+                Addresses::V6(addrs) => addrs.iter().map(|a| (libc::AF_INET6, a.octets().to_vec())).collect(),
+            };
+
+            for (family, octets) in addresses {
+                let mut addr = [0u32; 4];
+                libc::memcpy(
+                    addr.as_mut_ptr() as *mut libc::c_void,
+                    octets.as_ptr() as *const libc::c_void,
+                    octets.len(),
+                );
+
+                let next = tuple.add(1);
+
+                ptr::write(tuple, CAddrTuple {
+                    next: if next == first.add(count) { ptr::null_mut() } else { next },
+                    name,
+                    family,
+                    addr,
+                    scopeid: 0,
+                });
+
+                tuple = next;
+            }
+        }
+
+        // The caller may hand over the first entry of the list
+        if (*pat).is_null() {
+            *pat = first;
+        } else {
+            ptr::write(*pat, ptr::read(first));
+        }
+
+        Ok(())
+    }
+}
+
+impl Addresses {
+    pub fn len(&self) -> usize {
+        match self {
+            Addresses::V4(addrs) => addrs.len(),
+            Addresses::V6(addrs) => addrs.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
 pub trait HostHooks {
     fn get_all_entries() -> Response<Vec<Host>>;
 
     fn get_host_by_name(name: &str, family: AddressFamily) -> Response<Host>;
 
+    /// The addresses of both families at once, one host per family found, as
+    /// `getaddrinfo` asks for them through `gethostbyname4_r`. Unless
+    /// overridden, the IPv4 addresses are looked up and then the IPv6 ones.
+    fn get_hosts_by_name(name: &str) -> Response<Vec<Host>> {
+        match (Self::get_host_by_name(name, AddressFamily::IPv4), Self::get_host_by_name(name, AddressFamily::IPv6)) {
+            (Response::Success(v4), Response::Success(v6)) => Response::Success(vec![v4, v6]),
+            (Response::Success(host), _) | (_, Response::Success(host)) => Response::Success(vec![host]),
+            (Response::TryAgain, _) | (_, Response::TryAgain) => Response::TryAgain,
+            (Response::Unavail, _) | (_, Response::Unavail) => Response::Unavail,
+            (Response::Return, _) | (_, Response::Return) => Response::Return,
+            _ => Response::NotFound,
+        }
+    }
+
     fn get_host_by_addr(addr: IpAddr) -> Response<Host>;
 }
 
@@ -167,6 +264,19 @@ pub struct CHost {
     pub h_addr_list: *mut *mut libc::c_char,
 }
 
+/// NSS C address list entry, glibc `struct gaih_addrtuple`
+/// https://sourceware.org/git/?p=glibc.git;a=blob;f=include/nss.h
+#[repr(C)]
+#[allow(missing_copy_implementations)]
+#[derive(Debug)]
+pub struct CAddrTuple {
+    pub next: *mut CAddrTuple,
+    pub name: *mut libc::c_char,
+    pub family: libc::c_int,
+    pub addr: [u32; 4],
+    pub scopeid: u32,
+}
+
 #[macro_export]
 macro_rules! libnss_host_hooks {
 ($mod_ident:ident, $hooks_ident:ident) => (
@@ -180,7 +290,7 @@ macro_rules! libnss_host_hooks {
             use std::str;
             use std::sync::{Mutex, MutexGuard};
             use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
-            use $crate::host::{CHost, HostHooks, Host, AddressFamily};
+            use $crate::host::{CAddrTuple, CHost, HostHooks, Host, AddressFamily};
             use $crate::interop::{CBuffer, Response, NssStatus, Iterator};
 
             // https://code.woboq.org/userspace/glibc/resolv/netdb.h.html#62
@@ -192,6 +302,18 @@ macro_rules! libnss_host_hooks {
                 NoData = 4,
             }
 
+            fn herrno(status: NssStatus, errno: libc::c_int) -> Herrno {
+                match status {
+                    NssStatus::Success => Herrno::NetDbSuccess,
+                    // The caller retries with a larger buffer
+                    NssStatus::TryAgain if errno == libc::ERANGE => Herrno::NetDbInternal,
+                    NssStatus::TryAgain => Herrno::TryAgain,
+                    NssStatus::Unavail => Herrno::NoRecovery,
+                    NssStatus::NotFound => Herrno::NoData,
+                    _ => Herrno::NetDbInternal,
+                }
+            }
+
             lazy_static! {
             static ref [<HOST_ $mod_ident _ITERATOR>]: Mutex<Iterator<Host>> = Mutex::new(Iterator::<Host>::new());
             }
@@ -283,17 +405,28 @@ macro_rules! libnss_host_hooks {
                 ttlp: *mut i32,
                 canonp: *mut *const libc::c_char
             ) -> libc::c_int {
-                let name2_res = [<_nss_ $mod_ident _gethostbyname2_r>](name, family, result, buf, buflen, errnop, h_errnop);
+                let response = get_host_by_name(name, family);
 
-                if ! ttlp.is_null() {
-                    *ttlp = 0;
-                }
+                let ttl = match &response {
+                    Response::Success(host) => host.ttl.min(i32::MAX as u32) as i32,
+                    _ => 0,
+                };
+
+                let status = response.to_c(result, buf, buflen, errnop);
+
+                *h_errnop = herrno(status, *errnop) as i32;
+
+                if status == NssStatus::Success {
+                    if ! ttlp.is_null() {
+                        *ttlp = ttl;
+                    }
 
-                if ! canonp.is_null() {
-                    *canonp = name;
+                    if ! canonp.is_null() {
+                        *canonp = (*result).name;
+                    }
                 }
 
-                name2_res
+                status as c_int
             }
 
             #[no_mangle]
@@ -306,13 +439,21 @@ macro_rules! libnss_host_hooks {
                 errnop: *mut libc::c_int,
                 h_errnop: *mut libc::c_int
             ) -> libc::c_int {
+                let status = get_host_by_name(name, family).to_c(result, buf, buflen, errnop);
+
+                *h_errnop = herrno(status, *errnop) as i32;
+
+                status as c_int
+            }
+
+            unsafe fn get_host_by_name(name: *const libc::c_char, family: libc::c_int) -> Response<Host> {
 
                 let cstr = CStr::from_ptr(name);
 
-                let status = match str::from_utf8(cstr.to_bytes()) {
+                match str::from_utf8(cstr.to_bytes()) {
                     Ok(name) => {
                         use super::$hooks_ident as hooks;
-                        let status = match family {
+                        match family {
                             libc::AF_INET => hooks::get_host_by_name(&name.to_string(), AddressFamily::IPv4),
                             libc::AF_INET6 => hooks::get_host_by_name(&name.to_string(), AddressFamily::IPv6),
 
@@ -322,39 +463,238 @@ macro_rules! libnss_host_hooks {
                                 Response::NotFound => hooks::get_host_by_name(&name.to_string(), AddressFamily::IPv6),
                                 val => val,
                             },
-                            _ => {
-                                *h_errnop = Herrno::NoRecovery as i32;
-                                Response::Unavail
-                            },
-                        }.to_c(result, buf, buflen, errnop);
-
-                        match status {
-                            NssStatus::Success => {
-                                *h_errnop = Herrno::NetDbSuccess as i32
-                            }
-                            NssStatus::TryAgain => {
-                                *h_errnop = Herrno::TryAgain as i32
-                            }
-                            NssStatus::Unavail => {
-                                *h_errnop = Herrno::NoRecovery as i32
-                            }
-                            NssStatus::NotFound => {
-                                *h_errnop = Herrno::NoData as i32
-                            }
-                            _ => {
-                                *h_errnop = Herrno::NetDbInternal as i32
-                            }
-                        };
-
-                        status
+                            _ => Response::Unavail,
+                        }
                     }
 
-                    Err(_) => NssStatus::NotFound
+                    Err(_) => Response::NotFound
+                }
+            }
+
+            // Preferred by getaddrinfo, which gets both families in a single call
+            #[no_mangle]
+            unsafe extern "C" fn [<_nss_ $mod_ident _gethostbyname4_r>](
+                name: *const libc::c_char,
+                pat: *mut *mut CAddrTuple,
+                buf: *mut libc::c_char,
+                buflen: libc::size_t,
+                errnop: *mut libc::c_int,
+                h_errnop: *mut libc::c_int,
+                ttlp: *mut i32
+            ) -> libc::c_int {
+
+                let cstr = CStr::from_ptr(name);
+
+                let response = match str::from_utf8(cstr.to_bytes()) {
+                    Ok(name) => match super::$hooks_ident::get_hosts_by_name(name) {
+                        Response::Success(hosts) if hosts.iter().all(|host| host.addresses.is_empty()) => Response::NotFound,
+                        response => response,
+                    },
+
+                    Err(_) => Response::NotFound
                 };
 
+                let ttl = match &response {
+                    Response::Success(hosts) => hosts.iter().map(|host| host.ttl).min().unwrap_or(0).min(i32::MAX as u32) as i32,
+                    _ => 0,
+                };
+
+                let status = response.to_c(pat, buf, buflen, errnop);
+
+                *h_errnop = herrno(status, *errnop) as i32;
+
+                if status == NssStatus::Success && ! ttlp.is_null() {
+                    *ttlp = ttl;
+                }
+
                 status as c_int
             }
 
         }
     }
 )}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::{CStr, CString};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use std::ptr;
+
+    use super::{Addresses, AddressFamily, CAddrTuple, Host, HostHooks};
+    use crate::interop::{NssStatus, Response};
+
+    struct TestHost;
+
+    impl HostHooks for TestHost {
+        fn get_all_entries() -> Response<Vec<Host>> {
+            Response::NotFound
+        }
+
+        fn get_host_by_name(_: &str, _: AddressFamily) -> Response<Host> {
+            Response::NotFound
+        }
+
+        fn get_hosts_by_name(name: &str) -> Response<Vec<Host>> {
+            if name != "mixed.test" {
+                return Response::NotFound;
+            }
+
+            Response::Success(vec![
+                Host {
+                    name: name.to_string(),
+                    aliases: vec![],
+                    addresses: Addresses::V4(vec![Ipv4Addr::new(192, 0, 2, 1), Ipv4Addr::new(192, 0, 2, 2)]),
+                    ttl: 300,
+                    canonical_name: "www.mixed.test".to_string(),
+                },
+                Host {
+                    name: name.to_string(),
+                    aliases: vec![],
+                    addresses: Addresses::V6(vec!["2001:db8::1".parse().unwrap()]),
+                    ttl: 60,
+                    canonical_name: "www.mixed.test".to_string(),
+                },
+            ])
+        }
+
+        fn get_host_by_addr(_: IpAddr) -> Response<Host> {
+            Response::NotFound
+        }
+    }
+
+    // Expanded here, the hooks have imports that not all of them use
+    #[allow(unused_imports, unused_mut)]
+    mod hooks {
+        use super::TestHost;
+
+        libnss_host_hooks!(test, TestHost);
+    }
+
+    extern "C" {
+        fn _nss_test_gethostbyname4_r(
+            name: *const libc::c_char,
+            pat: *mut *mut CAddrTuple,
+            buf: *mut libc::c_char,
+            buflen: libc::size_t,
+            errnop: *mut libc::c_int,
+            h_errnop: *mut libc::c_int,
+            ttlp: *mut i32,
+        ) -> libc::c_int;
+    }
+
+    struct Lookup {
+        status: i32,
+        errno: i32,
+        h_errno: i32,
+        ttl: i32,
+    }
+
+    /// Calls `gethostbyname4_r` as glibc does, with `buffer` for the list.
+    unsafe fn lookup(name: &str, pat: *mut *mut CAddrTuple, buffer: &mut [libc::c_char]) -> Lookup {
+        let name = CString::new(name).unwrap();
+        let mut lookup = Lookup { status: 0, errno: 0, h_errno: 0, ttl: -1 };
+
+        lookup.status = _nss_test_gethostbyname4_r(
+            name.as_ptr(),
+            pat,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut lookup.errno,
+            &mut lookup.h_errno,
+            &mut lookup.ttl);
+
+        lookup
+    }
+
+    /// The name, family and address of every entry of the list.
+    unsafe fn walk(mut tuple: *mut CAddrTuple) -> Vec<(String, i32, IpAddr)> {
+        let mut entries = vec![];
+
+        while !tuple.is_null() {
+            let octets: [u8; 16] = std::mem::transmute((*tuple).addr);
+
+            let address = match (*tuple).family {
+                libc::AF_INET => IpAddr::V4(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3])),
+                _ => IpAddr::V6(Ipv6Addr::from(octets)),
+            };
+
+            entries.push((CStr::from_ptr((*tuple).name).to_string_lossy().into_owned(), (*tuple).family, address));
+
+            tuple = (*tuple).next;
+        }
+
+        entries
+    }
+
+    fn expected() -> Vec<(String, i32, IpAddr)> {
+        vec![
+            ("www.mixed.test".to_string(), libc::AF_INET, "192.0.2.1".parse().unwrap()),
+            ("www.mixed.test".to_string(), libc::AF_INET, "192.0.2.2".parse().unwrap()),
+            ("www.mixed.test".to_string(), libc::AF_INET6, "2001:db8::1".parse().unwrap()),
+        ]
+    }
+
+    #[test]
+    fn lists_the_addresses_of_both_families() {
+        let mut buffer = [0; 1024];
+        let mut pat: *mut CAddrTuple = ptr::null_mut();
+
+        unsafe {
+            let lookup = lookup("mixed.test", &mut pat, &mut buffer);
+
+            assert_eq!(lookup.status, NssStatus::Success as i32);
+            assert_eq!((lookup.errno, lookup.h_errno), (0, 0));
+            // The shortest of the lifetimes
+            assert_eq!(lookup.ttl, 60);
+
+            assert_eq!(walk(pat), expected());
+        }
+    }
+
+    #[test]
+    fn fills_in_the_entry_handed_over() {
+        let mut buffer = [0; 1024];
+        let mut entry = CAddrTuple { next: ptr::null_mut(), name: ptr::null_mut(), family: 0, addr: [0; 4], scopeid: 0 };
+        let mut pat: *mut CAddrTuple = &mut entry;
+
+        unsafe {
+            let lookup = lookup("mixed.test", &mut pat, &mut buffer);
+
+            assert_eq!(lookup.status, NssStatus::Success as i32);
+            assert_eq!(pat, &mut entry as *mut CAddrTuple);
+            assert_eq!(walk(pat), expected());
+        }
+    }
+
+    #[test]
+    fn asks_for_a_larger_buffer() {
+        let mut buffer = [0; 64];
+        let mut pat: *mut CAddrTuple = ptr::null_mut();
+
+        unsafe {
+            let lookup = lookup("mixed.test", &mut pat, &mut buffer);
+
+            assert_eq!(lookup.status, NssStatus::TryAgain as i32);
+            assert_eq!(lookup.errno, libc::ERANGE);
+            // NETDB_INTERNAL, so that the caller retries rather than gives up
+            assert_eq!(lookup.h_errno, -1);
+            assert_eq!(lookup.ttl, -1);
+            assert!(pat.is_null());
+        }
+    }
+
+    #[test]
+    fn reports_unknown_names() {
+        let mut buffer = [0; 1024];
+        let mut pat: *mut CAddrTuple = ptr::null_mut();
+
+        unsafe {
+            let lookup = lookup("unknown.test", &mut pat, &mut buffer);
+
+            assert_eq!(lookup.status, NssStatus::NotFound as i32);
+            // NO_DATA
+            assert_eq!(lookup.h_errno, 4);
+            assert!(pat.is_null());
+        }
+    }
+}
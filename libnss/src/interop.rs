@@ -3,6 +3,9 @@ use std::ffi::CString;
 use std::io;
 
 pub trait ToC<C> {
+    /// # Safety
+    ///
+    /// `result` must point to a valid, writable `C` and `buffer` must wrap memory owned by the caller.
     unsafe fn to_c(&self, result: *mut C, buffer: &mut CBuffer) -> std::io::Result<()>;
 }
 
@@ -37,6 +40,9 @@ impl<R> Response<R> {
         }
     }
 
+    /// # Safety
+    ///
+    /// All pointers are the raw NSS output arguments and must be valid for `buflen` bytes.
     pub unsafe fn to_c<C>(
         &self,
         result: *mut C,
@@ -77,6 +83,12 @@ pub struct Iterator<T> {
     items: Option<VecDeque<T>>,
 }
 
+impl<T> Default for Iterator<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T> Iterator<T> {
     pub fn new() -> Self {
         Iterator { items: None }
@@ -86,6 +98,7 @@ impl<T> Iterator<T> {
         NssStatus::Success
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> Response<T> {
         match self.items {
             Some(ref mut items) => match items.pop_front() {
@@ -119,10 +132,16 @@ impl CBuffer {
         }
     }
 
+    /// # Safety
+    ///
+    /// The wrapped buffer must be valid for writes of its whole length.
     pub unsafe fn clear(&mut self) {
         libc::memset(self.start, 0, self.len);
     }
 
+    /// # Safety
+    ///
+    /// The wrapped buffer must be valid for writes of its whole length.
     pub unsafe fn write_str(&mut self, string: &str) -> io::Result<*mut libc::c_char> {
         // Capture start address
         let str_start = self.pos;
@@ -146,12 +165,17 @@ impl CBuffer {
         Ok(str_start as *mut libc::c_char)
     }
 
+    /// # Safety
+    ///
+    /// The wrapped buffer must be valid for writes of its whole length.
     pub unsafe fn write_strs<S: AsRef<str>>(
         &mut self,
         strings: &[S],
     ) -> io::Result<*mut *mut libc::c_char> {
         let ptr_size = std::mem::size_of::<*mut libc::c_char>() as isize;
 
+        self.align(ptr_size as usize)?;
+
         let vec_start =
             self.reserve(ptr_size * (strings.len() as isize + 1))? as *mut *mut libc::c_char;
         let mut pos = vec_start;
@@ -167,6 +191,20 @@ impl CBuffer {
         Ok(vec_start)
     }
 
+    /// Skips the bytes up to the next multiple of `align`, which must be a power of two.
+    ///
+    /// # Safety
+    ///
+    /// The wrapped buffer must be valid for writes of its whole length.
+    pub unsafe fn align(&mut self, align: usize) -> io::Result<()> {
+        let padding = (self.pos as usize).wrapping_neg() & (align - 1);
+
+        self.reserve(padding as isize).map(|_| ())
+    }
+
+    /// # Safety
+    ///
+    /// The wrapped buffer must be valid for writes of its whole length.
     pub unsafe fn reserve(&mut self, len: isize) -> io::Result<*mut libc::c_char> {
         let start = self.pos;
 
@@ -176,7 +214,7 @@ impl CBuffer {
         }
 
         // Reserve space
-        self.pos = self.pos.offset(len as isize);
+        self.pos = self.pos.offset(len);
         self.free -= len as usize;
 
         Ok(start as *mut libc::c_char)
@@ -1,3 +1,4 @@
+#[cfg_attr(test, macro_use)]
 extern crate lazy_static;
 extern crate libc;
 